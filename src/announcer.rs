@@ -0,0 +1,175 @@
+use crate::tracker::{AnnounceEvent, Peer, Tracker};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often `shutdown` checks whether the final announces are done.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct AnnounceConfig {
+    /// Announce before the interval is up once fewer peers than this are connected.
+    pub min_peers: usize,
+    /// Used in place of `min interval` when the tracker does not send one.
+    pub default_min_interval: Duration,
+    /// Delay after the first failed announce, doubled on every further failure.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long `shutdown` waits for the final announces. A tracker that has not answered by
+    /// then is left to time out on the detached announce thread.
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            min_peers: 10,
+            default_min_interval: Duration::from_secs(120),
+            min_backoff: Duration::from_secs(15),
+            max_backoff: Duration::from_secs(30 * 60),
+            shutdown_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+enum Command {
+    PeerCount(usize),
+    AnnounceNow,
+    Shutdown,
+}
+
+/// Keeps a torrent announced in the background, honoring the tracker's `interval` and
+/// `min interval`, and hands out peers not seen before through the receiver from `spawn`.
+pub struct Announcer {
+    commands: Sender<Command>,
+    handle: Option<JoinHandle<()>>,
+    shutdown_timeout: Duration,
+}
+
+impl Announcer {
+    pub fn spawn(tracker: Tracker, config: AnnounceConfig) -> (Self, Receiver<Vec<Peer>>) {
+        let (commands, command_rx) = mpsc::channel();
+        let (peer_tx, peers) = mpsc::channel();
+        let shutdown_timeout = config.shutdown_timeout;
+        let handle = thread::spawn(move || run(tracker, config, command_rx, peer_tx));
+        let announcer = Self {
+            commands,
+            handle: Some(handle),
+            shutdown_timeout,
+        };
+        (announcer, peers)
+    }
+
+    /// Reports how many peers the downloader is connected to, which may trigger an early announce.
+    pub fn set_peer_count(&self, count: usize) {
+        let _ = self.commands.send(Command::PeerCount(count));
+    }
+
+    /// Announces as soon as `min interval` allows, e.g. to report `completed` promptly.
+    pub fn announce_now(&self) {
+        let _ = self.commands.send(Command::AnnounceNow);
+    }
+
+    /// Stops re-announcing and sends `stopped` (preceded by a pending `completed`) to the
+    /// tracker. Returns once that is done or after `shutdown_timeout`, whichever comes first.
+    pub fn shutdown(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        let _ = self.commands.send(Command::Shutdown);
+        let Some(handle) = self.handle.take() else {
+            return;
+        };
+        // An unresponsive UDP tracker keeps retransmitting for hours, so the thread is
+        // detached rather than joined once the deadline passes.
+        let deadline = Instant::now() + self.shutdown_timeout;
+        while !handle.is_finished() {
+            if Instant::now() >= deadline {
+                return;
+            }
+            thread::sleep(SHUTDOWN_POLL_INTERVAL);
+        }
+        let _ = handle.join();
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run(
+    mut tracker: Tracker,
+    config: AnnounceConfig,
+    commands: Receiver<Command>,
+    peers: Sender<Vec<Peer>>,
+) {
+    let mut seen: HashSet<SocketAddr> = HashSet::new();
+    let mut peer_count: Option<usize> = None;
+    let mut urgent = false;
+    let mut failures = 0;
+    let mut last_announce: Option<Instant> = None;
+    let mut min_interval = config.default_min_interval;
+    let mut next_announce = Instant::now();
+
+    loop {
+        let wants_early = urgent || peer_count.is_some_and(|count| count < config.min_peers);
+        let due = match last_announce {
+            // Never announce early while backing off from failures.
+            Some(last) if wants_early && failures == 0 => next_announce.min(last + min_interval),
+            _ => next_announce,
+        };
+        match commands.recv_timeout(due.saturating_duration_since(Instant::now())) {
+            Ok(Command::PeerCount(count)) => {
+                peer_count = Some(count);
+                continue;
+            }
+            Ok(Command::AnnounceNow) => {
+                urgent = true;
+                continue;
+            }
+            Ok(Command::Shutdown) | Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let now = Instant::now();
+        match tracker.announce() {
+            Ok(response) => {
+                failures = 0;
+                urgent = false;
+                last_announce = Some(now);
+                min_interval = response
+                    .min_interval
+                    .map_or(config.default_min_interval, Duration::from_secs);
+                next_announce = now + Duration::from_secs(response.interval).max(min_interval);
+                let new_peers: Vec<Peer> = response
+                    .peers
+                    .into_iter()
+                    .filter(|peer| seen.insert(peer.socket_addr()))
+                    .collect();
+                if !new_peers.is_empty() {
+                    // The downloader may have gone away; keep announcing until shut down anyway.
+                    let _ = peers.send(new_peers);
+                }
+            }
+            Err(_) => {
+                failures += 1;
+                let backoff = config
+                    .min_backoff
+                    .saturating_mul(1 << (failures - 1).min(16))
+                    .min(config.max_backoff);
+                next_announce = now + backoff;
+            }
+        }
+    }
+
+    // A download that finished right before shutdown still gets reported as completed.
+    if tracker.next_event() == AnnounceEvent::Completed {
+        let _ = tracker.announce();
+    }
+    let _ = tracker.stop();
+}
@@ -0,0 +1,119 @@
+use crate::session::BLOCK_SIZE;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+/// A block of a piece that failed its hash check, kept to compare once the piece passes.
+#[derive(Debug, Clone)]
+struct Suspect {
+    ip: IpAddr,
+    block: usize,
+    hash: [u8; 20],
+}
+
+/// Works out which peers send corrupt data ("smart ban"). A piece that fails its hash check
+/// and came from a single peer is blamed on that peer. When several peers contributed, the
+/// hash of each block is kept and the piece is downloaded again, preferably from others;
+/// once it passes, every peer whose block differs from the good data is blamed. Peers are
+/// banned by address after `max_failures` pieces blamed on them.
+#[derive(Debug, Clone)]
+pub struct SmartBan {
+    max_failures: u32,
+    failures: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+    suspects: HashMap<u32, Vec<Suspect>>,
+    /// Peers that contributed to a failed attempt at a piece.
+    contributors: HashMap<u32, HashSet<IpAddr>>,
+}
+
+impl SmartBan {
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            failures: HashMap::new(),
+            banned: HashSet::new(),
+            suspects: HashMap::new(),
+            contributors: HashMap::new(),
+        }
+    }
+
+    /// Piece `index` failed its hash check; `sources[i]` sent block `i` of `data`. Returns
+    /// the peers banned because of it.
+    pub fn piece_failed(&mut self, index: u32, data: &[u8], sources: &[IpAddr]) -> Vec<IpAddr> {
+        let distinct: HashSet<IpAddr> = sources.iter().copied().collect();
+        self.contributors
+            .entry(index)
+            .or_default()
+            .extend(distinct.iter().copied());
+        if distinct.len() == 1 {
+            return distinct
+                .into_iter()
+                .filter_map(|ip| self.blame(ip))
+                .collect();
+        }
+        let suspects = self.suspects.entry(index).or_default();
+        for (block, (data, ip)) in data.chunks(BLOCK_SIZE as usize).zip(sources).enumerate() {
+            suspects.push(Suspect {
+                ip: *ip,
+                block,
+                hash: Sha1::digest(data).into(),
+            });
+        }
+        Vec::new()
+    }
+
+    /// Piece `index` passed its hash check with `data`. Blames the peers that sent a
+    /// different block in an earlier attempt, and returns those banned because of it.
+    pub fn piece_passed(&mut self, index: u32, data: &[u8]) -> Vec<IpAddr> {
+        self.contributors.remove(&index);
+        let Some(suspects) = self.suspects.remove(&index) else {
+            return Vec::new();
+        };
+        let blocks: Vec<&[u8]> = data.chunks(BLOCK_SIZE as usize).collect();
+        let culprits: HashSet<IpAddr> = suspects
+            .into_iter()
+            .filter(|suspect| {
+                blocks.get(suspect.block).map_or(true, |block| {
+                    let hash: [u8; 20] = Sha1::digest(block).into();
+                    hash != suspect.hash
+                })
+            })
+            .map(|suspect| suspect.ip)
+            .collect();
+        culprits
+            .into_iter()
+            .filter_map(|ip| self.blame(ip))
+            .collect()
+    }
+
+    /// The pieces `ip` sent data for in a failed attempt, which others should download
+    /// again if they can.
+    pub fn contributed_to(&self, ip: IpAddr) -> Vec<u32> {
+        self.contributors
+            .iter()
+            .filter(|(_, contributors)| contributors.contains(&ip))
+            .map(|(index, _)| *index)
+            .collect()
+    }
+
+    /// Number of peers that contributed to failed attempts at piece `index`.
+    pub fn contributor_count(&self, index: u32) -> usize {
+        self.contributors.get(&index).map_or(0, HashSet::len)
+    }
+
+    /// Number of failed pieces blamed on `ip`.
+    pub fn failures(&self, ip: IpAddr) -> u32 {
+        self.failures.get(&ip).copied().unwrap_or(0)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.banned.contains(&ip)
+    }
+
+    /// Counts a failed piece against `ip`, returning it if that gets it banned.
+    fn blame(&mut self, ip: IpAddr) -> Option<IpAddr> {
+        let failures = self.failures.entry(ip).or_default();
+        *failures += 1;
+        (*failures >= self.max_failures && self.banned.insert(ip)).then_some(ip)
+    }
+}
@@ -1,23 +1,65 @@
-use serde::{Deserialize, Serialize};
-use serde_json;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::{forward_to_deserialize_any, Deserialize, Serialize};
 use serde_json::json;
-use std;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::iter::Peekable;
 use std::slice::Iter;
 use std::str::from_utf8;
+use thiserror::Error;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum BencodeValue {
     ByteString(Vec<u8>),
     Integer(i64),
     List(Vec<BencodeValue>),
-    Dictionary(HashMap<String, BencodeValue>),
+    /// Keys are byte strings and kept sorted, as bencode requires when encoding.
+    Dictionary(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+/// Error produced when deserializing a typed structure out of a `BencodeValue`.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("missing field `{0}`")]
+    MissingField(&'static str),
+    #[error("{0}")]
+    Message(String),
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error::Message(msg.to_string())
+    }
+
+    fn missing_field(field: &'static str) -> Self {
+        Error::MissingField(field)
+    }
 }
 
-impl<'a> BencodeValue {
+impl From<serde_bencode::value::Value> for BencodeValue {
+    fn from(value: serde_bencode::value::Value) -> Self {
+        use serde_bencode::value::Value;
+        match value {
+            Value::Bytes(bytes) => BencodeValue::ByteString(bytes),
+            Value::Int(n) => BencodeValue::Integer(n),
+            Value::List(list) => BencodeValue::List(list.into_iter().map(Self::from).collect()),
+            Value::Dict(dict) => BencodeValue::Dictionary(
+                dict.into_iter().map(|(k, v)| (k, Self::from(v))).collect(),
+            ),
+        }
+    }
+}
+
+impl BencodeValue {
+    /// Parses a complete bencoded document, reporting malformed input instead of panicking.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, serde_bencode::Error> {
+        serde_bencode::from_bytes::<serde_bencode::value::Value>(bytes).map(Self::from)
+    }
+
     pub fn from_bencoded_string(chars: &mut Peekable<std::slice::Iter<u8>>) -> Option<Self> {
         let mut index = String::new();
-        while let Some(cur) = chars.next() {
+        for cur in chars.by_ref() {
             if *cur != b':' {
                 index.push(*cur as char);
             } else {
@@ -25,14 +67,14 @@ impl<'a> BencodeValue {
             }
         }
         let index = index.parse().unwrap();
-        let string: Vec<u8> = chars.take(index).map(|&x| x).collect();
+        let string: Vec<u8> = chars.take(index).copied().collect();
         Some(BencodeValue::ByteString(string))
     }
 
     pub fn from_bencoded_integer(chars: &mut Peekable<std::slice::Iter<u8>>) -> Option<Self> {
         chars.next();
         let mut number = String::new();
-        while let Some(cur) = chars.next() {
+        for cur in chars.by_ref() {
             if *cur != b'e' {
                 number.push(*cur as char);
             } else {
@@ -56,28 +98,23 @@ impl<'a> BencodeValue {
     }
 
     /// Parses a dictionary from the given iterator of bytes.
-    /// 
+    ///
     /// This function assumes that the iterator points to the start of a dictionary.
     /// It reads each key-value pair until it encounters the end marker 'e'.
-    /// Keys are converted to strings and values are decoded using the `decode_bencoded_value` function.
-    /// 
+    /// Keys are kept as raw bytes and values are decoded using the `decode_bencoded_value` function.
+    ///
     /// Returns `None` if the iterator does not contain a valid dictionary.
     pub fn from_bencoded_dictionary(chars: &mut Peekable<Iter<u8>>) -> Option<Self> {
         chars.next();
-        let mut dict = HashMap::new();
+        let mut dict = BTreeMap::new();
         while let Some(cur) = chars.peek() {
             if **cur != b'e' {
-                let mut k: String = String::new();
+                let mut k: Vec<u8> = Vec::new();
                 if let Self::ByteString(value) = decode_bencoded_value(chars).unwrap() {
-                    k = from_utf8(&value).unwrap().to_string();
+                    k = value;
                 };
                 let v = decode_bencoded_value(chars).unwrap();
-                // let key = k.strip_prefix('"').unwrap();
-                // let key = key.strip_suffix('"').unwrap();
-
-                // println!("{:?}", dict);
                 dict.insert(k, v);
-                // print out the dict
             } else {
                 break;
             }
@@ -87,18 +124,16 @@ impl<'a> BencodeValue {
     }
 
     pub fn into_json(&self) -> Option<serde_json::Value> {
-        return Some(match self {
+        Some(match self {
             Self::ByteString(bytes) => {
-                json!(from_utf8(&bytes).unwrap())
+                json!(from_utf8(bytes).unwrap())
             }
 
             BencodeValue::Integer(n) => json!(n),
 
             BencodeValue::List(arr) => {
-                let collected: Vec<serde_json::Value> = arr
-                    .into_iter()
-                    .map(|item| item.into_json().unwrap())
-                    .collect();
+                let collected: Vec<serde_json::Value> =
+                    arr.iter().map(|item| item.into_json().unwrap()).collect();
 
                 serde_json::Value::Array(collected)
             }
@@ -107,12 +142,180 @@ impl<'a> BencodeValue {
                 let mut map: serde_json::Map<String, serde_json::Value> = serde_json::Map::new();
 
                 for (key, value) in dict.iter() {
-                    map.insert(key.clone(), value.into_json().unwrap());
+                    map.insert(
+                        String::from_utf8_lossy(key).into_owned(),
+                        value.into_json().unwrap(),
+                    );
                 }
 
                 serde_json::Value::Object(map)
             }
-        });
+        })
+    }
+
+    /// Builds a dictionary from string keys, which is how every dictionary we send is keyed.
+    pub fn dictionary<'k>(entries: impl IntoIterator<Item = (&'k str, BencodeValue)>) -> Self {
+        BencodeValue::Dictionary(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) {
+        match self {
+            BencodeValue::ByteString(bytes) => {
+                buf.extend(bytes.len().to_string().as_bytes());
+                buf.push(b':');
+                buf.extend(bytes);
+            }
+            BencodeValue::Integer(n) => {
+                buf.push(b'i');
+                buf.extend(n.to_string().as_bytes());
+                buf.push(b'e');
+            }
+            BencodeValue::List(list) => {
+                buf.push(b'l');
+                for item in list {
+                    item.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+            BencodeValue::Dictionary(dict) => {
+                buf.push(b'd');
+                for (key, value) in dict {
+                    BencodeValue::ByteString(key.clone()).encode_into(buf);
+                    value.encode_into(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+
+    fn mismatch(&self, expected: &str) -> Error {
+        Error::Message(format!("expected {}", expected))
+    }
+}
+
+impl<'de> de::Deserializer<'de> for BencodeValue {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BencodeValue::ByteString(bytes) => visitor.visit_byte_buf(bytes),
+            BencodeValue::Integer(n) => visitor.visit_i64(n),
+            BencodeValue::List(list) => visitor.visit_seq(SeqDeserializer::new(list.into_iter())),
+            BencodeValue::Dictionary(dict) => visitor
+                .visit_map(MapDeserializer::new(dict.into_iter().map(
+                    |(key, value)| (String::from_utf8_lossy(&key).into_owned(), value),
+                ))),
+        }
+    }
+
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BencodeValue::Integer(n) => visitor.visit_i64(n),
+            other => Err(other.mismatch("integer")),
+        }
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BencodeValue::ByteString(bytes) => match String::from_utf8(bytes) {
+                Ok(s) => visitor.visit_string(s),
+                Err(_) => Err(Error::Message("expected UTF-8 string".to_owned())),
+            },
+            other => Err(other.mismatch("string")),
+        }
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BencodeValue::ByteString(bytes) => visitor.visit_byte_buf(bytes),
+            other => Err(other.mismatch("byte string")),
+        }
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BencodeValue::List(_) => self.deserialize_any(visitor),
+            other => Err(other.mismatch("list")),
+        }
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            BencodeValue::Dictionary(_) => self.deserialize_any(visitor),
+            other => Err(other.mismatch("dictionary")),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool f32 f64 char unit unit_struct tuple tuple_struct enum identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for BencodeValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
     }
 }
 
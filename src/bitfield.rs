@@ -0,0 +1,88 @@
+/// One bit per piece, most significant bit of the first byte for piece 0, as sent in
+/// `bitfield` messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// A bitfield for `len` pieces with none set.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; (len + 7) / 8],
+            len,
+        }
+    }
+
+    /// A bitfield for `len` pieces with all of them set.
+    pub fn full(len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for index in 0..len {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    /// Takes a received bitfield, which must have exactly enough bytes for `len` pieces and
+    /// no spare bits set.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != (len + 7) / 8 {
+            return None;
+        }
+        let spare = bytes.len() * 8 - len;
+        if spare > 0 && bytes[bytes.len() - 1] & ((1 << spare) - 1) != 0 {
+            return None;
+        }
+        Some(Self {
+            bytes: bytes.to_vec(),
+            len,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    /// Sets the bit for `index`, ignoring indexes past the end.
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Indexes of the pieces set, in order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|index| self.has(*index))
+    }
+}
@@ -0,0 +1,421 @@
+use crate::extension::{ExtensionError, ExtensionRegistry};
+use crate::handshake::{
+    Extensions, Handshake, HandshakeError, HandshakeResult, HANDSHAKE_SIZE, HANDSHAKE_TIMEOUT,
+};
+use crate::message::{MessageError, PeerMessage, MAX_MESSAGE_LEN};
+use crate::peer_id::PeerId;
+use crate::proxy::ProxyConfig;
+use bytes::BytesMut;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::sync::CancellationToken;
+
+/// Bytes queued in a writer before `feed` waits for the socket to drain.
+pub const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum ConnectionError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Message(#[from] MessageError),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Extension(#[from] ExtensionError),
+    #[error("peer closed the connection during the handshake")]
+    HandshakeClosed,
+    #[error("connection cancelled")]
+    Cancelled,
+}
+
+/// Frames the fixed 68-byte handshake that opens every connection.
+#[derive(Debug, Default)]
+pub struct HandshakeCodec;
+
+impl Decoder for HandshakeCodec {
+    type Item = Handshake;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Handshake>, io::Error> {
+        if src.len() < HANDSHAKE_SIZE {
+            src.reserve(HANDSHAKE_SIZE - src.len());
+            return Ok(None);
+        }
+        let bytes = src.split_to(HANDSHAKE_SIZE);
+        Ok(Some(Handshake::from_bytes(bytes[..].try_into().unwrap())))
+    }
+}
+
+impl Encoder<Handshake> for HandshakeCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, handshake: Handshake, dst: &mut BytesMut) -> Result<(), io::Error> {
+        dst.extend_from_slice(&handshake.to_bytes());
+        Ok(())
+    }
+}
+
+/// Frames length-prefixed peer messages, rejecting frames over `max_len` bytes.
+#[derive(Debug)]
+pub struct MessageCodec {
+    max_len: usize,
+}
+
+impl MessageCodec {
+    pub fn new(max_len: usize) -> Self {
+        Self { max_len }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new(MAX_MESSAGE_LEN)
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = PeerMessage;
+    type Error = MessageError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<PeerMessage>, MessageError> {
+        PeerMessage::decode_frame(src, self.max_len)
+    }
+}
+
+impl Encoder<PeerMessage> for MessageCodec {
+    type Error = MessageError;
+
+    fn encode(&mut self, message: PeerMessage, dst: &mut BytesMut) -> Result<(), MessageError> {
+        message.encode(dst);
+        Ok(())
+    }
+}
+
+type MessageSink = SplitSink<Framed<TcpStream, MessageCodec>, PeerMessage>;
+
+/// An established peer connection: the handshake is done and messages flow both ways.
+/// Cancelling the token makes every pending and later operation fail with `Cancelled`; the
+/// token is polled first so a ready socket cannot win the race.
+///
+/// Extended messages (BEP 10) never reach the caller; they are routed to the connection's
+/// `ExtensionRegistry` and its replies are sent right away.
+pub struct PeerConnection {
+    framed: Framed<TcpStream, MessageCodec>,
+    cancel: CancellationToken,
+    remote: HandshakeResult,
+    addr: SocketAddr,
+    extensions: Arc<Mutex<ExtensionRegistry>>,
+}
+
+impl PeerConnection {
+    /// Connects to `addr`, through the global proxy configuration if one is set, and exchanges
+    /// handshakes.
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        cancel: CancellationToken,
+    ) -> Result<Self, ConnectionError> {
+        let connect = async {
+            let proxy = ProxyConfig::global();
+            if proxy.proxy.is_none() && !proxy.proxy_only {
+                return TcpStream::connect(addr).await;
+            }
+            let stream = tokio::task::spawn_blocking(move || proxy.connect(&addr.to_string()))
+                .await
+                .expect("proxy connect task panicked")?;
+            stream.set_nonblocking(true)?;
+            TcpStream::from_std(stream)
+        };
+        let stream = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Err(ConnectionError::Cancelled),
+            stream = connect => stream?,
+        };
+        let local = Handshake::new(info_hash, peer_id).with_extensions(Extensions {
+            extension_protocol: true,
+            fast: true,
+            ..Extensions::default()
+        });
+        Self::establish(stream, local, cancel).await
+    }
+
+    /// Sends `local` on an already open stream, incoming or outgoing, and waits up to
+    /// `HANDSHAKE_TIMEOUT` for a remote handshake that matches it.
+    pub async fn establish(
+        stream: TcpStream,
+        local: Handshake,
+        cancel: CancellationToken,
+    ) -> Result<Self, ConnectionError> {
+        let addr = stream.peer_addr()?;
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        let exchange = async {
+            framed.send(local).await?;
+            match framed.next().await {
+                Some(remote) => Ok(remote?),
+                None => Err(ConnectionError::HandshakeClosed),
+            }
+        };
+        let exchange = tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange);
+        let remote: Handshake = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Err(ConnectionError::Cancelled),
+            remote = exchange => remote.map_err(|_| HandshakeError::Timeout)??,
+        };
+        let remote = remote.verify(&local)?;
+        Ok(Self::established(framed, remote, addr, cancel))
+    }
+
+    /// Takes an incoming connection. Its handshake is read first, so that `local` can pick
+    /// our handshake from the info hash the peer asks for, or refuse it with `None`. The
+    /// whole exchange has `HANDSHAKE_TIMEOUT` to complete.
+    pub async fn accept(
+        stream: TcpStream,
+        local: impl FnOnce([u8; 20]) -> Option<Handshake>,
+        cancel: CancellationToken,
+    ) -> Result<Self, ConnectionError> {
+        let addr = stream.peer_addr()?;
+        let mut framed = Framed::new(stream, HandshakeCodec);
+        let exchange = async {
+            let remote: Handshake = match framed.next().await {
+                Some(remote) => remote?,
+                None => return Err(ConnectionError::HandshakeClosed),
+            };
+            let local = local(remote.info_hash).ok_or(HandshakeError::UnknownTorrent)?;
+            framed.send(local).await?;
+            Ok((remote, local))
+        };
+        let exchange = tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange);
+        let (remote, local) = tokio::select! {
+            biased;
+            _ = cancel.cancelled() => return Err(ConnectionError::Cancelled),
+            exchange = exchange => exchange.map_err(|_| HandshakeError::Timeout)??,
+        };
+        let remote = remote.verify(&local)?;
+        Ok(Self::established(framed, remote, addr, cancel))
+    }
+
+    fn established(
+        framed: Framed<TcpStream, HandshakeCodec>,
+        remote: HandshakeResult,
+        addr: SocketAddr,
+        cancel: CancellationToken,
+    ) -> Self {
+        // Switching codecs keeps the buffers, so messages the peer sent right behind its
+        // handshake are not lost.
+        let mut framed = framed.map_codec(|_| MessageCodec::default());
+        framed.set_backpressure_boundary(DEFAULT_WRITE_BUFFER);
+        Self {
+            framed,
+            cancel,
+            remote,
+            addr,
+            extensions: Arc::new(Mutex::new(ExtensionRegistry::new())),
+        }
+    }
+
+    /// Replaces the connection's extensions and, if both sides speak BEP 10, sends our
+    /// extended handshake. Call it before the first `recv` so no extended message is missed.
+    pub async fn start_extensions(
+        &mut self,
+        registry: ExtensionRegistry,
+    ) -> Result<(), ConnectionError> {
+        let handshake = registry.local_handshake(Some(self.addr.ip()));
+        *self.extensions.lock().unwrap() = registry;
+        if self.remote.extensions.extension_protocol {
+            self.send(handshake.to_message()).await?;
+        }
+        Ok(())
+    }
+
+    pub fn extensions(&self) -> &Arc<Mutex<ExtensionRegistry>> {
+        &self.extensions
+    }
+
+    /// Sends `payload` to the peer's side of extension `name`. Returns false, without sending,
+    /// if the peer does not support it.
+    pub async fn send_extension(
+        &mut self,
+        name: &str,
+        payload: Vec<u8>,
+    ) -> Result<bool, ConnectionError> {
+        let message = self.extensions.lock().unwrap().message(name, payload);
+        match message {
+            Some(message) => self.send(message).await.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// The remote peer ID and the extensions both sides agreed on.
+    pub fn handshake(&self) -> &HandshakeResult {
+        &self.remote
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn set_backpressure_boundary(&mut self, bytes: usize) {
+        self.framed.set_backpressure_boundary(bytes);
+    }
+
+    /// The next message, or `None` once the peer closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<PeerMessage>, ConnectionError> {
+        loop {
+            let message = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => return Err(ConnectionError::Cancelled),
+                message = self.framed.next() => message.transpose()?,
+            };
+            let Some(PeerMessage::Extended { id, payload }) = message else {
+                return Ok(message);
+            };
+            let replies = self.extensions.lock().unwrap().handle(id, &payload)?;
+            for reply in replies {
+                self.send(reply).await?;
+            }
+        }
+    }
+
+    /// Queues `message` and flushes everything queued so far.
+    pub async fn send(&mut self, message: PeerMessage) -> Result<(), ConnectionError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(ConnectionError::Cancelled),
+            result = self.framed.send(message) => Ok(result?),
+        }
+    }
+
+    /// Sends whatever the extensions want to send unprompted, like periodic PEX updates.
+    pub async fn tick_extensions(&mut self) -> Result<(), ConnectionError> {
+        let messages = self.extensions.lock().unwrap().tick();
+        for message in messages {
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Separates the halves so reading and writing can happen on different tasks.
+    pub fn split(self) -> (PeerReader, PeerWriter) {
+        let (sink, stream) = self.framed.split();
+        // The reader answers extended messages itself, so both halves share the sink.
+        let sink = Arc::new(tokio::sync::Mutex::new(sink));
+        let reader = PeerReader {
+            stream,
+            sink: sink.clone(),
+            cancel: self.cancel.clone(),
+            extensions: self.extensions.clone(),
+        };
+        let writer = PeerWriter {
+            sink,
+            cancel: self.cancel,
+            extensions: self.extensions,
+        };
+        (reader, writer)
+    }
+}
+
+pub struct PeerReader {
+    stream: SplitStream<Framed<TcpStream, MessageCodec>>,
+    sink: Arc<tokio::sync::Mutex<MessageSink>>,
+    cancel: CancellationToken,
+    extensions: Arc<Mutex<ExtensionRegistry>>,
+}
+
+impl PeerReader {
+    /// The next message, or `None` once the peer closed the connection.
+    pub async fn recv(&mut self) -> Result<Option<PeerMessage>, ConnectionError> {
+        loop {
+            let message = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => return Err(ConnectionError::Cancelled),
+                message = self.stream.next() => message.transpose()?,
+            };
+            let Some(PeerMessage::Extended { id, payload }) = message else {
+                return Ok(message);
+            };
+            let replies = self.extensions.lock().unwrap().handle(id, &payload)?;
+            for reply in replies {
+                tokio::select! {
+                    biased;
+                    _ = self.cancel.cancelled() => return Err(ConnectionError::Cancelled),
+                    result = async { self.sink.lock().await.send(reply).await } => result?,
+                }
+            }
+        }
+    }
+}
+
+pub struct PeerWriter {
+    sink: Arc<tokio::sync::Mutex<MessageSink>>,
+    cancel: CancellationToken,
+    extensions: Arc<Mutex<ExtensionRegistry>>,
+}
+
+impl PeerWriter {
+    /// Queues `message` and flushes everything queued so far.
+    pub async fn send(&mut self, message: PeerMessage) -> Result<(), ConnectionError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(ConnectionError::Cancelled),
+            result = async { self.sink.lock().await.send(message).await } => Ok(result?),
+        }
+    }
+
+    /// Queues `message` without flushing. Only waits, for the socket to drain, once more than
+    /// the backpressure boundary is queued, so a slow peer holds back the sender.
+    pub async fn feed(&mut self, message: PeerMessage) -> Result<(), ConnectionError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(ConnectionError::Cancelled),
+            result = async { self.sink.lock().await.feed(message).await } => Ok(result?),
+        }
+    }
+
+    /// Sends `payload` to the peer's side of extension `name`. Returns false, without sending,
+    /// if the peer does not support it.
+    pub async fn send_extension(
+        &mut self,
+        name: &str,
+        payload: Vec<u8>,
+    ) -> Result<bool, ConnectionError> {
+        let message = self.extensions.lock().unwrap().message(name, payload);
+        match message {
+            Some(message) => self.send(message).await.map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Sends whatever the extensions want to send unprompted, like periodic PEX updates.
+    pub async fn tick_extensions(&mut self) -> Result<(), ConnectionError> {
+        let messages = self.extensions.lock().unwrap().tick();
+        for message in messages {
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<(), ConnectionError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(ConnectionError::Cancelled),
+            result = async { self.sink.lock().await.flush().await } => Ok(result?),
+        }
+    }
+
+    /// Flushes queued messages and closes our side of the connection.
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        tokio::select! {
+            biased;
+            _ = self.cancel.cancelled() => Err(ConnectionError::Cancelled),
+            result = async { self.sink.lock().await.close().await } => Ok(result?),
+        }
+    }
+}
@@ -0,0 +1,891 @@
+use crate::bencode::BencodeValue;
+use crate::proxy::{self, ProxyConfig};
+use crate::tracker::Peer;
+use crate::utils::random_u64;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Environment variable naming the file the routing table is kept in between runs.
+pub const DHT_STATE_ENV: &str = "BITTORRENT_DHT_STATE";
+/// Well-known nodes used to join the network when nothing else is known.
+pub const DEFAULT_ROUTERS: &[(&str, u16)] = &[
+    ("router.bittorrent.com", 6881),
+    ("dht.transmissionbt.com", 6881),
+    ("router.utorrent.com", 6881),
+];
+/// Nodes per bucket, Kademlia's `k`.
+pub const K: usize = 8;
+/// Queries in flight per lookup round.
+const ALPHA: usize = 3;
+/// Upper bound on the queries one lookup sends, so a hostile network cannot keep it going.
+const MAX_LOOKUP_QUERIES: usize = 128;
+/// Unanswered queries after which a node may be replaced by a newcomer.
+const MAX_FAILURES: u32 = 2;
+/// How often the token secret changes. Tokens from the previous secret are still accepted.
+const TOKEN_ROTATION: Duration = Duration::from_secs(300);
+/// How long an announced peer is handed out without announcing again.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const MAX_VALUES: usize = 50;
+const COMPACT_NODE_LEN: usize = 26;
+/// How often the receive loop checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+const ERROR_PROTOCOL: i64 = 203;
+const ERROR_METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Error)]
+pub enum DhtError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("node did not answer in time")]
+    Timeout,
+    #[error("malformed KRPC message: {0}")]
+    Malformed(String),
+    #[error("node replied with error {code}: {message}")]
+    Remote { code: i64, message: String },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn generate() -> Self {
+        let random = [
+            random_u64().to_be_bytes(),
+            random_u64().to_be_bytes(),
+            random_u64().to_be_bytes(),
+        ]
+        .concat();
+        NodeId(random[..20].try_into().unwrap())
+    }
+
+    /// XOR distance to `target`, comparable as a big-endian number.
+    pub fn distance(&self, target: &[u8; 20]) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ target[i];
+        }
+        distance
+    }
+
+    /// Number of leading bits shared with `other`, 160 for the same ID.
+    fn common_prefix(&self, other: &NodeId) -> usize {
+        let distance = self.distance(&other.0);
+        match distance.iter().position(|byte| *byte != 0) {
+            Some(i) => i * 8 + distance[i].leading_zeros() as usize,
+            None => 160,
+        }
+    }
+}
+
+impl TryFrom<&[u8]> for NodeId {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(id: &[u8]) -> Result<Self, Self::Error> {
+        Ok(NodeId(id.try_into()?))
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+/// A DHT node as exchanged in `nodes`: its ID and IPv4 address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+}
+
+impl NodeInfo {
+    /// Parses compact node info, 26 bytes per node. A trailing partial entry is ignored.
+    pub fn from_compact(bytes: &[u8]) -> Vec<NodeInfo> {
+        bytes
+            .chunks_exact(COMPACT_NODE_LEN)
+            .map(|chunk| NodeInfo {
+                id: NodeId(chunk[..20].try_into().unwrap()),
+                addr: SocketAddrV4::new(
+                    Ipv4Addr::new(chunk[20], chunk[21], chunk[22], chunk[23]),
+                    u16::from_be_bytes([chunk[24], chunk[25]]),
+                ),
+            })
+            .collect()
+    }
+
+    pub fn to_compact(nodes: &[NodeInfo]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(nodes.len() * COMPACT_NODE_LEN);
+        for node in nodes {
+            bytes.extend(node.id.0);
+            bytes.extend(node.addr.ip().octets());
+            bytes.extend(node.addr.port().to_be_bytes());
+        }
+        bytes
+    }
+}
+
+struct Entry {
+    node: NodeInfo,
+    failures: u32,
+}
+
+/// Kademlia routing table. Bucket `i` holds up to `K` nodes whose IDs share exactly `i`
+/// leading bits with ours, which is the bucket layout a fully split tree ends up with.
+pub struct RoutingTable {
+    id: NodeId,
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(id: NodeId) -> Self {
+        Self {
+            id,
+            buckets: (0..160).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records that `node` is alive and returns whether it is in the table afterwards. A full
+    /// bucket only makes room by dropping a node that stopped answering; nodes that keep
+    /// answering are never evicted, as Kademlia prefers long-lived nodes.
+    pub fn insert(&mut self, node: NodeInfo) -> bool {
+        if node.id == self.id {
+            return false;
+        }
+        let bucket = &mut self.buckets[self.id.common_prefix(&node.id).min(159)];
+        if let Some(pos) = bucket.iter().position(|entry| entry.node.id == node.id) {
+            // Most recently seen nodes go last.
+            bucket.remove(pos);
+        } else if bucket.len() >= K {
+            match bucket
+                .iter()
+                .position(|entry| entry.failures >= MAX_FAILURES)
+            {
+                Some(pos) => {
+                    bucket.remove(pos);
+                }
+                None => return false,
+            }
+        }
+        bucket.push(Entry { node, failures: 0 });
+        true
+    }
+
+    /// Counts an unanswered query against the node at `addr`.
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        for entry in self.buckets.iter_mut().flatten() {
+            if entry.node.addr == addr {
+                entry.failures += 1;
+            }
+        }
+    }
+
+    /// Up to `count` nodes closest to `target`, nearest first, preferring responsive ones.
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<NodeInfo> {
+        let mut entries: Vec<&Entry> = self.buckets.iter().flatten().collect();
+        entries.sort_by_key(|entry| {
+            (
+                entry.failures >= MAX_FAILURES,
+                entry.node.id.distance(target),
+            )
+        });
+        entries
+            .into_iter()
+            .take(count)
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect()
+    }
+
+    /// Writes our ID and the known nodes as a bencoded dictionary.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let state = BencodeValue::dictionary([
+            ("id", BencodeValue::ByteString(self.id.0.to_vec())),
+            (
+                "nodes",
+                BencodeValue::ByteString(NodeInfo::to_compact(&self.nodes())),
+            ),
+        ]);
+        fs::write(path, state.encode())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        #[derive(Deserialize)]
+        struct RawState {
+            id: ByteBuf,
+            nodes: ByteBuf,
+        }
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+        let bencode =
+            BencodeValue::from_bytes(&fs::read(path)?).map_err(|err| invalid(err.to_string()))?;
+        let raw = RawState::deserialize(bencode).map_err(|err| invalid(err.to_string()))?;
+        let id = NodeId::try_from(raw.id.as_slice())
+            .map_err(|_| invalid("node ID must be 20 bytes".to_owned()))?;
+        let mut table = Self::new(id);
+        for node in NodeInfo::from_compact(&raw.nodes) {
+            table.insert(node);
+        }
+        Ok(table)
+    }
+}
+
+/// Tokens are a keyed hash of the querying IP, so announces need no per-node state and a
+/// node can only announce for its own address.
+struct TokenSecrets {
+    current: u64,
+    previous: u64,
+    rotated: Instant,
+}
+
+impl TokenSecrets {
+    fn new() -> Self {
+        Self {
+            current: random_u64(),
+            previous: random_u64(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= TOKEN_ROTATION {
+            self.previous = self.current;
+            self.current = random_u64();
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn token(secret: u64, ip: IpAddr) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+        hasher.update(secret.to_be_bytes());
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn issue(&self, ip: IpAddr) -> Vec<u8> {
+        Self::token(self.current, ip)
+    }
+
+    fn is_valid(&self, token: &[u8], ip: IpAddr) -> bool {
+        token == Self::token(self.current, ip) || token == Self::token(self.previous, ip)
+    }
+}
+
+/// What a `get_peers` query returned: peers if the node knows any, and closer nodes.
+#[derive(Debug, Clone, Default)]
+pub struct GetPeersReply {
+    pub peers: Vec<Peer>,
+    pub nodes: Vec<NodeInfo>,
+    /// Needed to `announce_peer` to this node.
+    pub token: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct RawMessage {
+    t: ByteBuf,
+    y: ByteBuf,
+    q: Option<ByteBuf>,
+    a: Option<RawBody>,
+    r: Option<RawBody>,
+    e: Option<(i64, ByteBuf)>,
+}
+
+/// Query arguments and response values share their keys, so one struct parses both.
+#[derive(Deserialize)]
+struct RawBody {
+    id: Option<ByteBuf>,
+    target: Option<ByteBuf>,
+    info_hash: Option<ByteBuf>,
+    port: Option<i64>,
+    implied_port: Option<i64>,
+    token: Option<ByteBuf>,
+    nodes: Option<ByteBuf>,
+    values: Option<Vec<ByteBuf>>,
+}
+
+impl RawBody {
+    fn id(&self) -> Result<NodeId, DhtError> {
+        self.id
+            .as_deref()
+            .and_then(|id| NodeId::try_from(id.as_slice()).ok())
+            .ok_or_else(|| DhtError::Malformed("missing node ID".to_owned()))
+    }
+
+    fn hash(field: &Option<ByteBuf>, name: &str) -> Result<[u8; 20], DhtError> {
+        field
+            .as_deref()
+            .and_then(|hash| hash.as_slice().try_into().ok())
+            .ok_or_else(|| DhtError::Malformed(format!("missing {}", name)))
+    }
+}
+
+fn byte_string(bytes: &[u8]) -> BencodeValue {
+    BencodeValue::ByteString(bytes.to_vec())
+}
+
+fn response(t: &[u8], values: Vec<(&str, BencodeValue)>) -> Vec<u8> {
+    BencodeValue::dictionary([
+        ("t", byte_string(t)),
+        ("y", byte_string(b"r")),
+        ("r", BencodeValue::dictionary(values)),
+    ])
+    .encode()
+}
+
+fn error(t: &[u8], code: i64, message: &str) -> Vec<u8> {
+    BencodeValue::dictionary([
+        ("t", byte_string(t)),
+        ("y", byte_string(b"e")),
+        (
+            "e",
+            BencodeValue::List(vec![
+                BencodeValue::Integer(code),
+                byte_string(message.as_bytes()),
+            ]),
+        ),
+    ])
+    .encode()
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    pub bind: SocketAddr,
+    /// Nodes contacted on every bootstrap, in addition to the ones passed to it.
+    pub routers: Vec<(String, u16)>,
+    pub query_timeout: Duration,
+    /// Where the routing table is loaded from on start and saved to on shutdown.
+    pub state_path: Option<PathBuf>,
+    /// DHT traffic goes to many nodes from one socket, which no proxy can relay, so a
+    /// proxy-only configuration refuses to start the node.
+    pub proxy: ProxyConfig,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 6881)),
+            routers: DEFAULT_ROUTERS
+                .iter()
+                .map(|(host, port)| (host.to_string(), *port))
+                .collect(),
+            query_timeout: Duration::from_secs(2),
+            state_path: None,
+            proxy: ProxyConfig::global().clone(),
+        }
+    }
+}
+
+/// Outcome of an iterative lookup.
+struct Lookup {
+    /// Nodes closest to the target that answered, nearest first, with their `get_peers` tokens.
+    closest: Vec<(NodeInfo, Option<Vec<u8>>)>,
+    peers: Vec<Peer>,
+}
+
+type PendingQuery = (SocketAddr, Sender<Result<RawBody, DhtError>>);
+
+struct Shared {
+    socket: UdpSocket,
+    table: Mutex<RoutingTable>,
+    /// Peers announced to us, by info hash.
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddr, Instant>>>,
+    tokens: Mutex<TokenSecrets>,
+    pending: Mutex<HashMap<[u8; 2], PendingQuery>>,
+    next_transaction: AtomicU16,
+    shutdown: AtomicBool,
+    query_timeout: Duration,
+}
+
+/// A mainline DHT node (BEP 5). It answers queries on a background thread and offers
+/// blocking queries and iterative lookups on the handle, which may be shared across threads.
+pub struct Dht {
+    shared: Arc<Shared>,
+    routers: Vec<(String, u16)>,
+    state_path: Option<PathBuf>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Dht {
+    /// Binds the socket and starts answering queries, restoring the routing table from
+    /// `state_path` when it exists.
+    pub fn start(config: DhtConfig) -> io::Result<Self> {
+        if config.proxy.proxy_only {
+            return Err(proxy::refused("DHT traffic"));
+        }
+        let table = match &config.state_path {
+            Some(path) if path.exists() => RoutingTable::load(path)?,
+            _ => RoutingTable::new(NodeId::generate()),
+        };
+        let socket = UdpSocket::bind(config.bind)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let shared = Arc::new(Shared {
+            socket,
+            table: Mutex::new(table),
+            peers: Mutex::new(HashMap::new()),
+            tokens: Mutex::new(TokenSecrets::new()),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(random_u64() as u16),
+            shutdown: AtomicBool::new(false),
+            query_timeout: config.query_timeout,
+        });
+        let receiver = shared.clone();
+        let handle = thread::spawn(move || receiver.run());
+        Ok(Self {
+            shared,
+            routers: config.routers,
+            state_path: config.state_path,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.shared.table.lock().unwrap().id()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.shared.socket.local_addr()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.shared.table.lock().unwrap().len()
+    }
+
+    pub fn nodes(&self) -> Vec<NodeInfo> {
+        self.shared.table.lock().unwrap().nodes()
+    }
+
+    pub fn ping(&self, addr: SocketAddr) -> Result<NodeId, DhtError> {
+        self.shared.query(addr, "ping", Vec::new())?.id()
+    }
+
+    pub fn find_node(&self, addr: SocketAddr, target: NodeId) -> Result<Vec<NodeInfo>, DhtError> {
+        self.shared.find_node(addr, &target.0)
+    }
+
+    pub fn get_peers(
+        &self,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+    ) -> Result<GetPeersReply, DhtError> {
+        self.shared.get_peers(addr, &info_hash)
+    }
+
+    pub fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        port: u16,
+        token: &[u8],
+    ) -> Result<(), DhtError> {
+        self.shared.announce_peer(addr, &info_hash, port, token)
+    }
+
+    /// Joins the network through `nodes`, e.g. a torrent's `nodes`, the configured routers
+    /// and whatever the routing table already holds, then looks up our own ID to fill the
+    /// buckets near us. Returns the number of nodes known afterwards.
+    pub fn bootstrap(&self, nodes: &[(String, u16)]) -> usize {
+        let addrs: Vec<SocketAddr> = nodes
+            .iter()
+            .chain(&self.routers)
+            .filter_map(|(host, port)| (host.as_str(), *port).to_socket_addrs().ok())
+            .flatten()
+            .filter(SocketAddr::is_ipv4)
+            .collect();
+        thread::scope(|scope| {
+            for addr in addrs {
+                // Answers land in the routing table; failures just leave it as it was.
+                scope.spawn(move || self.ping(addr));
+            }
+        });
+        let id = self.id();
+        self.shared.lookup(&id.0, false);
+        self.node_count()
+    }
+
+    /// Asks the nodes closest to `info_hash` for peers.
+    pub fn lookup_peers(&self, info_hash: [u8; 20]) -> Vec<Peer> {
+        self.shared.lookup(&info_hash, true).peers
+    }
+
+    /// Looks up `info_hash` and announces that we accept peers on `port` to the closest nodes
+    /// that handed out a token. Returns the peers found on the way.
+    pub fn announce(&self, info_hash: [u8; 20], port: u16) -> Vec<Peer> {
+        let lookup = self.shared.lookup(&info_hash, true);
+        thread::scope(|scope| {
+            for (node, token) in &lookup.closest {
+                if let Some(token) = token {
+                    let addr = SocketAddr::V4(node.addr);
+                    scope.spawn(move || self.announce_peer(addr, info_hash, port, token));
+                }
+            }
+        });
+        lookup.peers
+    }
+
+    /// Writes the routing table to the configured state path, if any.
+    pub fn save(&self) -> io::Result<()> {
+        match &self.state_path {
+            Some(path) => self.shared.table.lock().unwrap().save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops answering queries and saves the routing table.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop();
+        self.save()
+    }
+
+    fn stop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Dht {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            self.stop();
+            let _ = self.save();
+        }
+    }
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut buf = [0; 2048];
+        while !self.shutdown.load(Ordering::Relaxed) {
+            // Timeouts let the loop notice shutdown; other errors, like ICMP unreachable
+            // reports, concern a single remote node.
+            let Ok((n, from)) = self.socket.recv_from(&mut buf) else {
+                continue;
+            };
+            self.handle(&buf[..n], from);
+        }
+    }
+
+    fn handle(&self, packet: &[u8], from: SocketAddr) {
+        let Some(message) = BencodeValue::from_bytes(packet)
+            .ok()
+            .and_then(|bencode| RawMessage::deserialize(bencode).ok())
+        else {
+            return;
+        };
+        if message.y.as_slice() == b"q" {
+            let reply = self.answer(&message, from);
+            // A node that went away is no reason to stop serving.
+            let _ = self.socket.send_to(&reply, from);
+            return;
+        }
+        let Ok(t) = <[u8; 2]>::try_from(message.t.as_slice()) else {
+            return;
+        };
+        let mut pending = self.pending.lock().unwrap();
+        // Only the node we asked may answer, so a third party cannot inject replies.
+        if pending.get(&t).map_or(true, |(addr, _)| *addr != from) {
+            return;
+        }
+        let (_, reply) = pending.remove(&t).unwrap();
+        let result = match (message.y.as_slice(), message.r, message.e) {
+            (b"r", Some(body), _) => Ok(body),
+            (b"e", _, Some((code, text))) => Err(DhtError::Remote {
+                code,
+                message: String::from_utf8_lossy(&text).into_owned(),
+            }),
+            _ => Err(DhtError::Malformed("response without body".to_owned())),
+        };
+        let _ = reply.send(result);
+    }
+
+    fn answer(&self, message: &RawMessage, from: SocketAddr) -> Vec<u8> {
+        let t = &message.t;
+        let (Some(method), Some(args)) = (&message.q, &message.a) else {
+            return error(t, ERROR_PROTOCOL, "missing method or arguments");
+        };
+        let Ok(id) = args.id() else {
+            return error(t, ERROR_PROTOCOL, "missing id");
+        };
+        let mut table = self.table.lock().unwrap();
+        if let SocketAddr::V4(addr) = from {
+            table.insert(NodeInfo { id, addr });
+        }
+        let own_id = ("id", byte_string(&table.id().0));
+        match method.as_slice() {
+            b"ping" => response(t, vec![own_id]),
+            b"find_node" => {
+                let Ok(target) = RawBody::hash(&args.target, "target") else {
+                    return error(t, ERROR_PROTOCOL, "missing target");
+                };
+                let nodes = NodeInfo::to_compact(&table.closest(&target, K));
+                response(t, vec![own_id, ("nodes", BencodeValue::ByteString(nodes))])
+            }
+            b"get_peers" => {
+                let Ok(info_hash) = RawBody::hash(&args.info_hash, "info_hash") else {
+                    return error(t, ERROR_PROTOCOL, "missing info_hash");
+                };
+                let mut tokens = self.tokens.lock().unwrap();
+                tokens.rotate_if_due();
+                let token = ("token", BencodeValue::ByteString(tokens.issue(from.ip())));
+                let values: Vec<BencodeValue> = self
+                    .peers
+                    .lock()
+                    .unwrap()
+                    .get(&info_hash)
+                    .into_iter()
+                    .flatten()
+                    .filter(|(addr, seen)| {
+                        addr.is_ipv4() == from.is_ipv4() && seen.elapsed() < PEER_TTL
+                    })
+                    .take(MAX_VALUES)
+                    .map(|(addr, _)| BencodeValue::ByteString(Peer::new(*addr).to_compact()))
+                    .collect();
+                if values.is_empty() {
+                    let nodes = NodeInfo::to_compact(&table.closest(&info_hash, K));
+                    response(
+                        t,
+                        vec![own_id, token, ("nodes", BencodeValue::ByteString(nodes))],
+                    )
+                } else {
+                    response(
+                        t,
+                        vec![own_id, token, ("values", BencodeValue::List(values))],
+                    )
+                }
+            }
+            b"announce_peer" => {
+                let Ok(info_hash) = RawBody::hash(&args.info_hash, "info_hash") else {
+                    return error(t, ERROR_PROTOCOL, "missing info_hash");
+                };
+                let mut tokens = self.tokens.lock().unwrap();
+                tokens.rotate_if_due();
+                if !args
+                    .token
+                    .as_ref()
+                    .is_some_and(|token| tokens.is_valid(token, from.ip()))
+                {
+                    return error(t, ERROR_PROTOCOL, "bad token");
+                }
+                let port = match (args.implied_port, args.port) {
+                    (Some(1), _) => from.port(),
+                    (_, Some(port @ 1..=65535)) => port as u16,
+                    _ => return error(t, ERROR_PROTOCOL, "missing port"),
+                };
+                let mut peers = self.peers.lock().unwrap();
+                let swarm = peers.entry(info_hash).or_default();
+                swarm.retain(|_, seen| seen.elapsed() < PEER_TTL);
+                swarm.insert(SocketAddr::new(from.ip(), port), Instant::now());
+                response(t, vec![own_id])
+            }
+            _ => error(t, ERROR_METHOD_UNKNOWN, "method unknown"),
+        }
+    }
+
+    /// Sends a query and waits for its answer. Nodes that answer are added to the routing
+    /// table; nodes that do not are counted as failing.
+    fn query(
+        &self,
+        addr: SocketAddr,
+        method: &str,
+        mut args: Vec<(&str, BencodeValue)>,
+    ) -> Result<RawBody, DhtError> {
+        let t = self
+            .next_transaction
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes();
+        let own_id = self.table.lock().unwrap().id();
+        args.push(("id", byte_string(&own_id.0)));
+        let packet = BencodeValue::dictionary([
+            ("t", byte_string(&t)),
+            ("y", byte_string(b"q")),
+            ("q", byte_string(method.as_bytes())),
+            ("a", BencodeValue::dictionary(args)),
+        ])
+        .encode();
+
+        let (sender, receiver) = mpsc::channel();
+        self.pending.lock().unwrap().insert(t, (addr, sender));
+        let result = match self.socket.send_to(&packet, addr) {
+            Ok(_) => receiver
+                .recv_timeout(self.query_timeout)
+                .unwrap_or(Err(DhtError::Timeout)),
+            Err(err) => Err(err.into()),
+        };
+        self.pending.lock().unwrap().remove(&t);
+
+        let SocketAddr::V4(addr) = addr else {
+            return result;
+        };
+        let mut table = self.table.lock().unwrap();
+        match &result {
+            Ok(body) => {
+                if let Ok(id) = body.id() {
+                    table.insert(NodeInfo { id, addr });
+                }
+            }
+            Err(DhtError::Timeout) => table.failed(addr),
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn find_node(&self, addr: SocketAddr, target: &[u8; 20]) -> Result<Vec<NodeInfo>, DhtError> {
+        let body = self.query(addr, "find_node", vec![("target", byte_string(target))])?;
+        Ok(NodeInfo::from_compact(
+            body.nodes.as_deref().map_or(&[][..], Vec::as_slice),
+        ))
+    }
+
+    fn get_peers(&self, addr: SocketAddr, info_hash: &[u8; 20]) -> Result<GetPeersReply, DhtError> {
+        let body = self.query(
+            addr,
+            "get_peers",
+            vec![("info_hash", byte_string(info_hash))],
+        )?;
+        Ok(GetPeersReply {
+            peers: body
+                .values
+                .unwrap_or_default()
+                .iter()
+                .flat_map(|value| Peer::from_compact(value, value.len() == 18))
+                .collect(),
+            nodes: NodeInfo::from_compact(body.nodes.as_deref().map_or(&[][..], Vec::as_slice)),
+            token: body.token.map(ByteBuf::into_vec),
+        })
+    }
+
+    fn announce_peer(
+        &self,
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        port: u16,
+        token: &[u8],
+    ) -> Result<(), DhtError> {
+        let args = vec![
+            ("info_hash", byte_string(info_hash)),
+            ("port", BencodeValue::Integer(port as i64)),
+            ("token", byte_string(token)),
+            ("implied_port", BencodeValue::Integer(0)),
+        ];
+        self.query(addr, "announce_peer", args).map(|_| ())
+    }
+
+    /// Iterative Kademlia lookup: keeps querying the closest nodes not asked yet, `ALPHA` at
+    /// a time, until the `K` closest known nodes have all been asked.
+    fn lookup(&self, target: &[u8; 20], get_peers: bool) -> Lookup {
+        let own_id = self.table.lock().unwrap().id();
+        let mut candidates: BTreeMap<[u8; 20], NodeInfo> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (node.id.distance(target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut answered = BTreeMap::new();
+        let mut peers = Vec::new();
+        let mut seen_peers = HashSet::new();
+
+        while queried.len() < MAX_LOOKUP_QUERIES {
+            let batch: Vec<NodeInfo> = candidates
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            queried.extend(batch.iter().map(|node| node.id));
+            let results: Vec<(NodeInfo, Result<GetPeersReply, DhtError>)> =
+                thread::scope(|scope| {
+                    let queries: Vec<_> = batch
+                        .iter()
+                        .map(|node| {
+                            scope.spawn(move || {
+                                let addr = SocketAddr::V4(node.addr);
+                                let result = if get_peers {
+                                    self.get_peers(addr, target)
+                                } else {
+                                    self.find_node(addr, target).map(|nodes| GetPeersReply {
+                                        nodes,
+                                        ..GetPeersReply::default()
+                                    })
+                                };
+                                (*node, result)
+                            })
+                        })
+                        .collect();
+                    queries
+                        .into_iter()
+                        .map(|query| query.join().expect("DHT query panicked"))
+                        .collect()
+                });
+            for (node, result) in results {
+                let distance = node.id.distance(target);
+                let Ok(reply) = result else {
+                    candidates.remove(&distance);
+                    continue;
+                };
+                for peer in reply.peers {
+                    if seen_peers.insert(peer.socket_addr()) {
+                        peers.push(peer);
+                    }
+                }
+                for found in reply.nodes {
+                    if found.id != own_id {
+                        candidates.entry(found.id.distance(target)).or_insert(found);
+                    }
+                }
+                answered.insert(distance, (node, reply.token));
+            }
+        }
+        Lookup {
+            closest: answered.into_values().take(K).collect(),
+            peers,
+        }
+    }
+}
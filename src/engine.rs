@@ -0,0 +1,813 @@
+use crate::ban::SmartBan;
+use crate::bitfield::Bitfield;
+use crate::connection::{ConnectionError, PeerConnection};
+use crate::extension::ExtensionRegistry;
+use crate::message::{MessageError, MessageId, PeerMessage};
+use crate::peer_id::PeerId;
+use crate::pex::{PexFlags, UtPex};
+use crate::picker::PiecePicker;
+use crate::pool::CandidatePool;
+use crate::seeder::PieceStore;
+use crate::session::{PeerSession, PipelineConfig, SessionError, SessionState, BLOCK_SIZE};
+use crate::torrent::TorrentFileInfo;
+use crate::tracker::TransferStats;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+/// How often a peer task wakes up without messages, to run extensions and check timeouts.
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+/// Peers drop connections that stay silent for two minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// How often the engine looks for new candidates while its peer slots are full.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Most peers asked for the same block in endgame.
+const ENDGAME_REQUESTERS: u8 = 3;
+
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// Most peers connected at once.
+    pub max_peers: usize,
+    pub connect_timeout: Duration,
+    /// A peer that leaves our requests unanswered this long is dropped, freeing its slot
+    /// and its pieces for others.
+    pub peer_timeout: Duration,
+    /// Gives up after this long with no peer connected and none queued.
+    pub stall_timeout: Duration,
+    pub pipeline: PipelineConfig,
+    /// A peer is banned once this many pieces failing their hash check are blamed on it.
+    pub max_hash_failures: u32,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 30,
+            connect_timeout: Duration::from_secs(10),
+            peer_timeout: Duration::from_secs(60),
+            stall_timeout: Duration::from_secs(120),
+            pipeline: PipelineConfig::default(),
+            max_hash_failures: 3,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EngineError {
+    #[error("no peers to download from for {0:?}")]
+    Stalled(Duration),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Missing,
+    /// Requested from this many peers.
+    Requested(u8),
+    Received,
+}
+
+/// A piece being downloaded, possibly from several peers at once.
+struct PartialPiece {
+    data: Vec<u8>,
+    blocks: Vec<Block>,
+    /// Who sent each received block.
+    sources: Vec<Option<IpAddr>>,
+}
+
+/// How many blocks a piece of `length` bytes is requested in.
+fn block_count(length: u32) -> usize {
+    (length as usize + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize
+}
+
+impl PartialPiece {
+    fn new(length: u32) -> Self {
+        let blocks = block_count(length);
+        Self {
+            data: vec![0; length as usize],
+            blocks: vec![Block::Missing; blocks],
+            sources: vec![None; blocks],
+        }
+    }
+
+    /// `(begin, length)` of block number `block`.
+    fn block(&self, block: usize) -> (u32, u32) {
+        let begin = block as u32 * BLOCK_SIZE;
+        (begin, BLOCK_SIZE.min(self.data.len() as u32 - begin))
+    }
+
+    /// The number of the block starting at `begin`.
+    fn block_at(&self, begin: u32) -> Option<usize> {
+        let block = (begin / BLOCK_SIZE) as usize;
+        (begin % BLOCK_SIZE == 0 && block < self.blocks.len()).then_some(block)
+    }
+
+    fn is_started(&self) -> bool {
+        self.blocks.contains(&Block::Received)
+    }
+
+    fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|block| *block == Block::Received)
+    }
+}
+
+/// A block request sent to a peer.
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+enum Arrival {
+    Stored {
+        complete: bool,
+        /// Whether other peers were asked for the block too and should cancel.
+        duplicated: bool,
+    },
+    /// We had the block already.
+    Redundant,
+    Unexpected,
+}
+
+/// Which pieces are done and which are in progress, shared by all peer tasks.
+struct Progress {
+    picker: PiecePicker,
+    /// Pieces with blocks requested or received, whether or not a peer still works on them.
+    partial: HashMap<u32, PartialPiece>,
+    ban: SmartBan,
+    /// Blocks of missing pieces that nobody has been asked for, counting every block of the
+    /// pieces not started yet.
+    missing_blocks: usize,
+    /// The peer working on each picked piece. A piece that fails its hash check loses its
+    /// owner, so the peer that picked it no longer fetches it by default.
+    owners: HashMap<u32, SocketAddr>,
+}
+
+impl Progress {
+    /// Endgame starts once every block of every missing piece has been requested. From then
+    /// on, blocks still on their way are requested from further peers as well.
+    fn in_endgame(&self) -> bool {
+        self.missing_blocks == 0
+    }
+
+    /// The next block to ask a peer in `state` for: a missing block of a piece it works on,
+    /// or of a newly picked one, or in endgame one already requested from someone else.
+    fn next_block(
+        &mut self,
+        info: &TorrentFileInfo,
+        state: &SessionState,
+        work: &mut PeerWork,
+    ) -> Option<Request> {
+        // Pieces that failed with this peer's data are left to others while anyone else has
+        // them, so the next attempt tells who was at fault.
+        let avoided: Vec<u32> = self
+            .ban
+            .contributed_to(work.addr.ip())
+            .into_iter()
+            .filter(|index| {
+                self.picker.availability(*index) as usize > self.ban.contributor_count(*index)
+            })
+            .collect();
+        work.owned.retain(|index| {
+            self.picker.is_missing(*index) && self.owners.get(index) == Some(&work.addr)
+        });
+        let (owned, handed_back): (Vec<u32>, Vec<u32>) = work
+            .owned
+            .iter()
+            .partition(|index| !avoided.contains(index));
+        work.owned = owned;
+        for index in handed_back {
+            self.release(index, work.addr);
+        }
+        let Self {
+            picker,
+            partial,
+            missing_blocks,
+            owners,
+            ..
+        } = self;
+        loop {
+            for &index in &work.owned {
+                if !state.can_request(index) {
+                    continue;
+                }
+                let piece = partial
+                    .entry(index)
+                    .or_insert_with(|| PartialPiece::new(info.piece_size(index)));
+                if let Some(block) = piece.blocks.iter().position(|b| *b == Block::Missing) {
+                    piece.blocks[block] = Block::Requested(1);
+                    *missing_blocks -= 1;
+                    let (begin, length) = piece.block(block);
+                    return Some(Request {
+                        index,
+                        begin,
+                        length,
+                    });
+                }
+            }
+            let suggested = state.suggested.iter().copied();
+            match picker.pick_suggested(suggested, |index| {
+                state.can_request(index) && !avoided.contains(&index)
+            }) {
+                Some(index) => {
+                    owners.insert(index, work.addr);
+                    work.owned.push(index);
+                }
+                None => break,
+            }
+        }
+        if !self.in_endgame() {
+            return None;
+        }
+        for (&index, piece) in &mut self.partial {
+            if !self.picker.is_missing(index)
+                || !state.can_request(index)
+                || avoided.contains(&index)
+            {
+                continue;
+            }
+            for block in 0..piece.blocks.len() {
+                let Block::Requested(count) = piece.blocks[block] else {
+                    continue;
+                };
+                let (begin, length) = piece.block(block);
+                if count >= ENDGAME_REQUESTERS || work.requested(index, begin).is_some() {
+                    continue;
+                }
+                piece.blocks[block] = Block::Requested(count + 1);
+                return Some(Request {
+                    index,
+                    begin,
+                    length,
+                });
+            }
+        }
+        None
+    }
+
+    /// Stores a block from the peer at `from`.
+    fn received(&mut self, from: IpAddr, index: u32, begin: u32, data: &[u8]) -> Arrival {
+        if !self.picker.is_missing(index) {
+            return match self.picker.have().has(index as usize) {
+                true => Arrival::Redundant,
+                false => Arrival::Unexpected,
+            };
+        }
+        let Some(piece) = self.partial.get_mut(&index) else {
+            return Arrival::Unexpected;
+        };
+        let Some(block) = piece.block_at(begin) else {
+            return Arrival::Unexpected;
+        };
+        if piece.block(block).1 != data.len() as u32 {
+            return Arrival::Unexpected;
+        }
+        let duplicated = match piece.blocks[block] {
+            Block::Received => return Arrival::Redundant,
+            Block::Requested(count) => count > 1,
+            Block::Missing => {
+                self.missing_blocks -= 1;
+                false
+            }
+        };
+        piece.data[begin as usize..begin as usize + data.len()].copy_from_slice(data);
+        piece.blocks[block] = Block::Received;
+        piece.sources[block] = Some(from);
+        Arrival::Stored {
+            complete: piece.is_complete(),
+            duplicated,
+        }
+    }
+
+    /// A request will not be answered. Returns whether the block needs a new request.
+    fn unrequest(&mut self, index: u32, begin: u32) -> bool {
+        let Some(piece) = self.partial.get_mut(&index) else {
+            return false;
+        };
+        let Some(block) = piece.block_at(begin) else {
+            return false;
+        };
+        match piece.blocks[block] {
+            Block::Requested(1) => {
+                piece.blocks[block] = Block::Missing;
+                self.missing_blocks += 1;
+                true
+            }
+            Block::Requested(count) => {
+                piece.blocks[block] = Block::Requested(count - 1);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a request is still worth waiting for: the block is neither received nor part
+    /// of a piece that was completed or reset since.
+    fn is_pending(&self, request: &Request) -> bool {
+        self.picker.is_missing(request.index)
+            && self.partial.get(&request.index).is_some_and(|piece| {
+                piece
+                    .block_at(request.begin)
+                    .is_some_and(|block| matches!(piece.blocks[block], Block::Requested(_)))
+            })
+    }
+
+    /// Hands a piece the peer at `owner` stopped working on back to the picker, keeping its
+    /// blocks. Does nothing if the piece has changed hands since.
+    fn release(&mut self, index: u32, owner: SocketAddr) {
+        if self.owners.get(&index) != Some(&owner) {
+            return;
+        }
+        self.owners.remove(&index);
+        let partial = self
+            .partial
+            .get(&index)
+            .is_some_and(PartialPiece::is_started);
+        self.picker.release(index, partial);
+    }
+}
+
+struct Shared {
+    info: TorrentFileInfo,
+    info_hash: [u8; 20],
+    config: EngineConfig,
+    pool: CandidatePool,
+    stats: Arc<TransferStats>,
+    progress: Mutex<Progress>,
+    verified: Arc<VerifiedPieces>,
+    /// Signalled when blocks or pieces become available to request, or a block arrived that
+    /// other peers were asked for too.
+    changed: Notify,
+    complete: Notify,
+    cancel: CancellationToken,
+}
+
+impl Shared {
+    /// Stores a block and, once its piece is complete, checks it against its hash. A piece
+    /// that fails is downloaded again from scratch, and the peers that sent it are held to
+    /// account; those banned for it are disconnected.
+    fn block_arrived(&self, from: IpAddr, index: u32, begin: u32, data: &[u8]) {
+        let mut progress = self.progress.lock().unwrap();
+        let banned = match progress.received(from, index, begin, data) {
+            Arrival::Stored {
+                complete: false,
+                duplicated,
+            } => {
+                if duplicated {
+                    self.changed.notify_waiters();
+                }
+                return;
+            }
+            Arrival::Stored { complete: true, .. } => {
+                let piece = progress.partial.remove(&index).expect("piece in progress");
+                let banned = if self.info.verify_piece(index, &piece.data) {
+                    self.stats.piece_completed(piece.data.len() as u64);
+                    progress.picker.complete(index);
+                    progress.owners.remove(&index);
+                    let banned = progress.ban.piece_passed(index, &piece.data);
+                    self.verified.insert(index, piece.data);
+                    if progress.picker.is_complete() {
+                        self.complete.notify_one();
+                    }
+                    banned
+                } else {
+                    progress.picker.release(index, false);
+                    progress.owners.remove(&index);
+                    progress.missing_blocks += piece.blocks.len();
+                    let sources: Vec<IpAddr> = piece.sources.into_iter().flatten().collect();
+                    progress.ban.piece_failed(index, &piece.data, &sources)
+                };
+                self.changed.notify_waiters();
+                banned
+            }
+            Arrival::Redundant => {
+                self.stats.add_redundant(data.len() as u64);
+                return;
+            }
+            Arrival::Unexpected => return,
+        };
+        for ip in banned {
+            self.pool.ban(ip);
+        }
+    }
+
+    fn unrequest(&self, request: &Request) {
+        if self
+            .progress
+            .lock()
+            .unwrap()
+            .unrequest(request.index, request.begin)
+        {
+            self.changed.notify_waiters();
+        }
+    }
+
+    fn release(&self, index: u32, owner: SocketAddr) {
+        self.progress.lock().unwrap().release(index, owner);
+        self.changed.notify_waiters();
+    }
+
+    fn is_complete(&self) -> bool {
+        self.progress.lock().unwrap().picker.is_complete()
+    }
+
+    /// Whether the peer has a piece we still need, whoever is working on it.
+    fn wants_from(&self, pieces: &Bitfield) -> bool {
+        let progress = self.progress.lock().unwrap();
+        pieces
+            .iter()
+            .any(|index| progress.picker.is_missing(index as u32))
+    }
+}
+
+/// Downloads a torrent from many peers at once. Candidates come from a `CandidatePool`, fed
+/// by whoever finds peers; each connection runs on its own task, asks for the pieces that
+/// peer has and nobody else is fetching, and hands them back when it gets choked or goes away.
+/// For the last blocks, in endgame, several peers are asked for the same block and the
+/// others get a `cancel` once it arrives.
+pub struct Engine {
+    info: TorrentFileInfo,
+    config: EngineConfig,
+    wanted: Bitfield,
+    stats: Arc<TransferStats>,
+    verified: Arc<VerifiedPieces>,
+}
+
+impl Engine {
+    /// An engine fetching every piece of the torrent.
+    pub fn new(info: TorrentFileInfo, stats: Arc<TransferStats>) -> Self {
+        let wanted = Bitfield::full(info.piece_count());
+        let verified = Arc::new(VerifiedPieces::new(info.piece_count()));
+        Self {
+            info,
+            config: EngineConfig::default(),
+            wanted,
+            stats,
+            verified,
+        }
+    }
+
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Fetches only the listed pieces instead of all of them.
+    pub fn with_pieces(mut self, indexes: impl IntoIterator<Item = u32>) -> Self {
+        self.wanted = Bitfield::new(self.info.piece_count());
+        for index in indexes {
+            self.wanted.set(index as usize);
+        }
+        self
+    }
+
+    /// The pieces verified so far, for serving them to other peers during the download.
+    pub fn verified(&self) -> Arc<VerifiedPieces> {
+        self.verified.clone()
+    }
+
+    /// Runs until every wanted piece is downloaded and verified, and returns them
+    /// concatenated in order.
+    pub async fn run(self, pool: CandidatePool) -> Result<Vec<u8>, EngineError> {
+        let piece_count = self.info.piece_count();
+        let max_hash_failures = self.config.max_hash_failures;
+        let missing_blocks = self
+            .wanted
+            .iter()
+            .map(|index| block_count(self.info.piece_size(index as u32)))
+            .sum();
+        let shared = Arc::new(Shared {
+            info_hash: self.info.info_hash(),
+            info: self.info,
+            config: self.config,
+            pool,
+            stats: self.stats,
+            progress: Mutex::new(Progress {
+                picker: PiecePicker::new(piece_count).with_wanted(self.wanted),
+                partial: HashMap::new(),
+                ban: SmartBan::new(max_hash_failures),
+                missing_blocks,
+                owners: HashMap::new(),
+            }),
+            verified: self.verified,
+            changed: Notify::new(),
+            complete: Notify::new(),
+            cancel: CancellationToken::new(),
+        });
+        let mut peers = JoinSet::new();
+        let mut idle_since: Option<Instant> = None;
+        while !shared.is_complete() {
+            while peers.len() < shared.config.max_peers {
+                let Some((addr, _)) = shared.pool.pop() else {
+                    break;
+                };
+                peers.spawn(peer_task(shared.clone(), addr));
+            }
+            if peers.is_empty() {
+                let since = *idle_since.get_or_insert_with(Instant::now);
+                if since.elapsed() >= shared.config.stall_timeout {
+                    return Err(EngineError::Stalled(shared.config.stall_timeout));
+                }
+            } else {
+                idle_since = None;
+            }
+            tokio::select! {
+                _ = shared.complete.notified() => {}
+                Some(_) = peers.join_next(), if !peers.is_empty() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+        shared.cancel.cancel();
+        while peers.join_next().await.is_some() {}
+
+        let wanted = shared.progress.lock().unwrap().picker.wanted().clone();
+        Ok(shared.verified.take(&wanted))
+    }
+}
+
+/// The pieces an engine has verified, held in memory until `run` returns them.
+pub struct VerifiedPieces {
+    pieces: Mutex<Vec<Option<Vec<u8>>>>,
+}
+
+impl VerifiedPieces {
+    fn new(piece_count: usize) -> Self {
+        Self {
+            pieces: Mutex::new(vec![None; piece_count]),
+        }
+    }
+
+    fn insert(&self, index: u32, data: Vec<u8>) {
+        self.pieces.lock().unwrap()[index as usize] = Some(data);
+    }
+
+    /// Removes the `wanted` pieces and returns them concatenated in order.
+    fn take(&self, wanted: &Bitfield) -> Vec<u8> {
+        let mut pieces = self.pieces.lock().unwrap();
+        wanted
+            .iter()
+            .flat_map(|index| pieces[index].take().unwrap_or_default())
+            .collect()
+    }
+}
+
+impl PieceStore for VerifiedPieces {
+    fn have(&self) -> Bitfield {
+        let pieces = self.pieces.lock().unwrap();
+        let mut have = Bitfield::new(pieces.len());
+        for (index, piece) in pieces.iter().enumerate() {
+            if piece.is_some() {
+                have.set(index);
+            }
+        }
+        have
+    }
+
+    fn read(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let pieces = self.pieces.lock().unwrap();
+        let piece = pieces
+            .get(index as usize)
+            .and_then(Option::as_ref)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("piece {index} is not verified"),
+                )
+            })?;
+        piece
+            .get(begin as usize..begin as usize + length as usize)
+            .map(<[u8]>::to_vec)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+    }
+}
+
+/// What one peer task holds in the shared progress.
+struct PeerWork {
+    addr: SocketAddr,
+    /// Pieces this peer picked.
+    owned: Vec<u32>,
+    requests: Vec<(Request, Instant)>,
+    /// The peer's pieces as counted in the picker's availability.
+    counted: Bitfield,
+}
+
+impl PeerWork {
+    fn requested(&self, index: u32, begin: u32) -> Option<usize> {
+        self.requests
+            .iter()
+            .position(|(request, _)| (request.index, request.begin) == (index, begin))
+    }
+
+    /// Brings the picker's availability in line with what the peer has now told us.
+    fn count(&mut self, shared: &Shared, message: &PeerMessage, pieces: &Bitfield) {
+        if *pieces == self.counted {
+            return;
+        }
+        let picker = &mut shared.progress.lock().unwrap().picker;
+        match message {
+            PeerMessage::Have { index } if pieces.count() == self.counted.count() + 1 => {
+                picker.peer_has(*index);
+            }
+            _ => {
+                picker.remove_peer(&self.counted);
+                picker.add_peer(pieces);
+            }
+        }
+        self.counted = pieces.clone();
+    }
+
+    /// Removes the requests for blocks that arrived from another peer, to be cancelled.
+    fn withdraw(&mut self, shared: &Shared) -> Vec<Request> {
+        let progress = shared.progress.lock().unwrap();
+        let mut withdrawn = Vec::new();
+        self.requests.retain(|(request, _)| {
+            let pending = progress.is_pending(request);
+            if !pending {
+                withdrawn.push(*request);
+            }
+            pending
+        });
+        withdrawn
+    }
+}
+
+async fn peer_task(shared: Arc<Shared>, addr: SocketAddr) {
+    let mut work = PeerWork {
+        addr,
+        owned: Vec::new(),
+        requests: Vec::new(),
+        counted: Bitfield::new(shared.info.piece_count()),
+    };
+    // Errors only end this connection; its pieces and blocks go to other peers.
+    let _ = drive_peer(&shared, addr, &mut work).await;
+    {
+        let mut progress = shared.progress.lock().unwrap();
+        progress.picker.remove_peer(&work.counted);
+        for (request, _) in &work.requests {
+            progress.unrequest(request.index, request.begin);
+        }
+        for index in work.owned {
+            progress.release(index, addr);
+        }
+    }
+    shared.changed.notify_waiters();
+    shared.pool.disconnected(addr);
+}
+
+async fn connect(shared: &Shared, addr: SocketAddr) -> Result<PeerSession, SessionError> {
+    let connect = PeerConnection::connect(
+        addr,
+        shared.info_hash,
+        PeerId::session(),
+        shared.cancel.child_token(),
+    );
+    let mut connection = tokio::time::timeout(shared.config.connect_timeout, connect)
+        .await
+        .map_err(|_| ConnectionError::Io(io::ErrorKind::TimedOut.into()))??;
+    let mut registry = ExtensionRegistry::new();
+    if let Some(pex) = UtPex::for_torrent(&shared.info, shared.pool.clone(), addr) {
+        registry.register(Box::new(pex));
+    }
+    connection.start_extensions(registry).await?;
+    shared.pool.connected(addr, PexFlags::REACHABLE);
+    let have = shared.progress.lock().unwrap().picker.have().clone();
+    Ok(PeerSession::start(connection, &have)
+        .await?
+        .with_pipeline(shared.config.pipeline))
+}
+
+/// Keeps one peer's pipeline full, until the download is complete or the peer fails or
+/// stops answering.
+async fn drive_peer(
+    shared: &Shared,
+    addr: SocketAddr,
+    work: &mut PeerWork,
+) -> Result<(), SessionError> {
+    let mut session = connect(shared, addr).await?;
+    let mut last_block = Instant::now();
+    let mut last_tick = Instant::now();
+    let mut last_sent = Instant::now();
+    while !shared.is_complete() {
+        // Registered before looking for work, so any change from now on wakes this peer.
+        let changed = shared.changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        if shared.pool.is_banned(addr.ip()) {
+            return Ok(());
+        }
+
+        for request in work.withdraw(shared) {
+            session.state_mut().pipeline.cancelled(1);
+            let cancel = PeerMessage::Cancel {
+                index: request.index,
+                begin: request.begin,
+                length: request.length,
+            };
+            session.send(cancel).await?;
+        }
+        while session.state().pipeline.has_room() {
+            let request =
+                shared
+                    .progress
+                    .lock()
+                    .unwrap()
+                    .next_block(&shared.info, session.state(), work);
+            let Some(request) = request else {
+                break;
+            };
+            session.state_mut().pipeline.sent();
+            work.requests.push((request, Instant::now()));
+            let message = PeerMessage::Request {
+                index: request.index,
+                begin: request.begin,
+                length: request.length,
+            };
+            session.send(message).await?;
+            last_sent = Instant::now();
+        }
+        let interested = !work.owned.is_empty() || shared.wants_from(&session.state().pieces);
+        if interested != session.state().am_interested {
+            session.set_interested(interested).await?;
+            last_sent = Instant::now();
+        }
+        if work.requests.is_empty() {
+            last_block = Instant::now();
+        } else if last_block.elapsed() >= shared.config.peer_timeout {
+            return Ok(());
+        }
+        if last_tick.elapsed() >= TICK_INTERVAL {
+            last_tick = Instant::now();
+            session.connection_mut().tick_extensions().await?;
+            if last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                session.send(PeerMessage::KeepAlive).await?;
+                last_sent = Instant::now();
+            }
+        }
+
+        let message = tokio::select! {
+            message = session.recv() => message?,
+            _ = changed => continue,
+            _ = tokio::time::sleep(TICK_INTERVAL) => continue,
+        };
+        let state = session.state_mut();
+        match &message {
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                shared.stats.add_downloaded(block.len() as u64);
+                last_block = Instant::now();
+                if let Some(pos) = work.requested(*index, *begin) {
+                    let (request, sent) = work.requests.remove(pos);
+                    if block.len() as u32 != request.length {
+                        return Err(MessageError::Malformed {
+                            id: MessageId::Piece,
+                            reason: "block length differs from the request",
+                        }
+                        .into());
+                    }
+                    state.pipeline.received(sent.elapsed(), block.len());
+                }
+                shared.block_arrived(addr.ip(), *index, *begin, block);
+            }
+            PeerMessage::RejectRequest { index, begin, .. } => {
+                if let Some(pos) = work.requested(*index, *begin) {
+                    let (request, _) = work.requests.remove(pos);
+                    state.pipeline.cancelled(1);
+                    // Being refused while allowed to ask means the peer will not serve the
+                    // piece at all.
+                    if state.can_request(*index) {
+                        state.pieces.clear(*index as usize);
+                    }
+                    shared.unrequest(&request);
+                }
+            }
+            // Without the fast extension a choke silently drops every outstanding request.
+            PeerMessage::Choke if !state.fast => {
+                state.pipeline.cancelled(work.requests.len());
+                for (request, _) in std::mem::take(&mut work.requests) {
+                    shared.unrequest(&request);
+                }
+            }
+            _ => {}
+        }
+        // Pieces this peer will not serve for now go back to the others.
+        let (owned, refused) = work
+            .owned
+            .iter()
+            .partition(|index| state.can_request(**index));
+        work.owned = owned;
+        for index in refused {
+            shared.release(index, work.addr);
+        }
+        work.count(shared, &message, &state.pieces);
+    }
+    Ok(())
+}
@@ -0,0 +1,267 @@
+use crate::bencode::BencodeValue;
+use crate::message::PeerMessage;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use thiserror::Error;
+
+/// Extended message ID of the BEP 10 handshake itself.
+pub const HANDSHAKE_ID: u8 = 0;
+/// Outstanding requests we advertise in `reqq`.
+pub const DEFAULT_REQQ: u32 = 250;
+
+#[derive(Debug, Error)]
+pub enum ExtensionError {
+    #[error("malformed extended handshake: {0}")]
+    Malformed(String),
+    #[error("{name}: {reason}")]
+    Extension { name: &'static str, reason: String },
+}
+
+/// The dictionary exchanged as extended message 0 (BEP 10).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message ID the sender wants to receive them under.
+    pub m: BTreeMap<String, u8>,
+    /// Client name and version.
+    pub v: Option<String>,
+    /// Port the sender listens on.
+    pub p: Option<u16>,
+    /// How many outstanding requests the sender accepts.
+    pub reqq: Option<u32>,
+    /// Our address as the sender sees it.
+    pub yourip: Option<IpAddr>,
+    /// Size of the info dictionary, for ut_metadata (BEP 9).
+    pub metadata_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct RawExtendedHandshake {
+    m: Option<BTreeMap<String, i64>>,
+    v: Option<ByteBuf>,
+    p: Option<i64>,
+    reqq: Option<i64>,
+    yourip: Option<ByteBuf>,
+    metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let bencode = BencodeValue::from_bytes(payload)
+            .map_err(|err| ExtensionError::Malformed(err.to_string()))?;
+        let raw = RawExtendedHandshake::deserialize(bencode)
+            .map_err(|err| ExtensionError::Malformed(err.to_string()))?;
+        Ok(Self {
+            // ID 0 means the sender disabled the extension; anything else out of range is junk.
+            m: raw
+                .m
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(name, id)| {
+                    let id = u8::try_from(id).ok().filter(|id| *id != 0)?;
+                    Some((name, id))
+                })
+                .collect(),
+            v: raw.v.map(|v| String::from_utf8_lossy(&v).into_owned()),
+            p: raw
+                .p
+                .and_then(|p| u16::try_from(p).ok())
+                .filter(|p| *p != 0),
+            reqq: raw.reqq.and_then(|reqq| u32::try_from(reqq).ok()),
+            yourip: raw.yourip.and_then(|ip| match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(&ip[..]).unwrap())),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(&ip[..]).unwrap())),
+                _ => None,
+            }),
+            metadata_size: raw.metadata_size.and_then(|size| u64::try_from(size).ok()),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.as_str(), BencodeValue::Integer(*id as i64)));
+        let mut entries = vec![("m", BencodeValue::dictionary(m))];
+        if let Some(v) = &self.v {
+            entries.push(("v", BencodeValue::ByteString(v.clone().into_bytes())));
+        }
+        if let Some(p) = self.p {
+            entries.push(("p", BencodeValue::Integer(p as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            entries.push(("reqq", BencodeValue::Integer(reqq as i64)));
+        }
+        if let Some(ip) = self.yourip {
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            entries.push(("yourip", BencodeValue::ByteString(bytes)));
+        }
+        if let Some(size) = self.metadata_size {
+            entries.push(("metadata_size", BencodeValue::Integer(size as i64)));
+        }
+        BencodeValue::dictionary(entries).encode()
+    }
+
+    pub fn to_message(&self) -> PeerMessage {
+        PeerMessage::Extended {
+            id: HANDSHAKE_ID,
+            payload: self.to_bytes(),
+        }
+    }
+}
+
+/// A BEP 10 extension such as ut_metadata or ut_pex. Extensions only see their own
+/// messages and only produce payloads; the registry handles IDs on both ends.
+pub trait Extension: Send {
+    /// Name advertised in the `m` dictionary, e.g. `ut_pex`.
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys, like `metadata_size`, to our handshake.
+    fn extend_handshake(&self, _handshake: &mut ExtendedHandshake) {}
+
+    /// Called once with the peer's handshake, whether or not the peer supports this
+    /// extension. Returns payloads to send to the peer's side of the extension.
+    fn on_handshake(
+        &mut self,
+        _remote: &ExtendedHandshake,
+    ) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        Ok(Vec::new())
+    }
+
+    /// Handles a payload the peer sent to this extension and returns payloads to send back.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError>;
+
+    /// Called regularly by the connection's owner, only once the peer announced support for
+    /// this extension. Returns payloads to send unprompted; extensions rate-limit themselves.
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+/// The extensions enabled on one connection and the IDs both sides assigned to them.
+/// Our IDs are the registration order starting at 1.
+#[derive(Default)]
+pub struct ExtensionRegistry {
+    extensions: Vec<Box<dyn Extension>>,
+    client: Option<String>,
+    listen_port: Option<u16>,
+    remote: Option<ExtendedHandshake>,
+    remote_ids: HashMap<String, u8>,
+}
+
+impl ExtensionRegistry {
+    pub fn new() -> Self {
+        Self {
+            client: Some(concat!("bittorrent-rust/", env!("CARGO_PKG_VERSION")).to_owned()),
+            ..Self::default()
+        }
+    }
+
+    pub fn with(mut self, extension: impl Extension + 'static) -> Self {
+        self.register(Box::new(extension));
+        self
+    }
+
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.listen_port = Some(port);
+        self
+    }
+
+    pub fn register(&mut self, extension: Box<dyn Extension>) {
+        assert!(
+            self.extensions.len() < u8::MAX as usize,
+            "too many extensions"
+        );
+        self.extensions.push(extension);
+    }
+
+    /// The handshake to send, with `yourip` set to the peer's address as we see it.
+    pub fn local_handshake(&self, peer_ip: Option<IpAddr>) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake {
+            m: self
+                .extensions
+                .iter()
+                .enumerate()
+                .map(|(index, extension)| (extension.name().to_owned(), index as u8 + 1))
+                .collect(),
+            v: self.client.clone(),
+            p: self.listen_port,
+            reqq: Some(DEFAULT_REQQ),
+            yourip: peer_ip,
+            metadata_size: None,
+        };
+        for extension in &self.extensions {
+            extension.extend_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    /// The peer's handshake, once it arrived.
+    pub fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    /// Whether the peer announced support for the extension called `name`.
+    pub fn supports(&self, name: &str) -> bool {
+        self.remote_ids.contains_key(name)
+    }
+
+    /// Wraps `payload` for the peer's side of extension `name`, or returns `None` if the peer
+    /// does not support it.
+    pub fn message(&self, name: &str, payload: Vec<u8>) -> Option<PeerMessage> {
+        let id = *self.remote_ids.get(name)?;
+        Some(PeerMessage::Extended { id, payload })
+    }
+
+    /// Routes an extended message to its extension and returns the messages to send back.
+    /// Messages under IDs we never assigned are ignored, as BEP 10 asks.
+    pub fn handle(&mut self, id: u8, payload: &[u8]) -> Result<Vec<PeerMessage>, ExtensionError> {
+        if id == HANDSHAKE_ID {
+            return self.handle_handshake(payload);
+        }
+        let Some(extension) = self.extensions.get_mut(id as usize - 1) else {
+            return Ok(Vec::new());
+        };
+        let name = extension.name();
+        let replies = extension.on_message(payload)?;
+        Ok(self.wrap(name, replies))
+    }
+
+    fn handle_handshake(&mut self, payload: &[u8]) -> Result<Vec<PeerMessage>, ExtensionError> {
+        // A later handshake may update the mapping; extensions it leaves out are disabled.
+        let remote = ExtendedHandshake::from_bytes(payload)?;
+        self.remote_ids = remote.m.clone().into_iter().collect();
+        let mut messages = Vec::new();
+        for index in 0..self.extensions.len() {
+            let name = self.extensions[index].name();
+            let replies = self.extensions[index].on_handshake(&remote)?;
+            messages.extend(self.wrap(name, replies));
+        }
+        self.remote = Some(remote);
+        Ok(messages)
+    }
+
+    /// Collects what the extensions the peer supports want to send unprompted.
+    pub fn tick(&mut self) -> Vec<PeerMessage> {
+        let mut messages = Vec::new();
+        for index in 0..self.extensions.len() {
+            let name = self.extensions[index].name();
+            if self.supports(name) {
+                let payloads = self.extensions[index].tick();
+                messages.extend(self.wrap(name, payloads));
+            }
+        }
+        messages
+    }
+
+    fn wrap(&self, name: &str, payloads: Vec<Vec<u8>>) -> Vec<PeerMessage> {
+        payloads
+            .into_iter()
+            .filter_map(|payload| self.message(name, payload))
+            .collect()
+    }
+}
@@ -1,10 +1,76 @@
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-};
+use crate::peer_id::PeerId;
+use crate::proxy::ProxyConfig;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use thiserror::Error;
 
-#[repr(C)]
-#[repr(packed)]
+pub const HANDSHAKE_SIZE: usize = 68;
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+/// How long a peer gets to answer our handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("peer did not complete the handshake in time")]
+    Timeout,
+    #[error("peer does not speak the BitTorrent protocol")]
+    Protocol,
+    #[error("peer is serving a different torrent")]
+    InfoHashMismatch,
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("peer asked for a torrent we do not serve")]
+    UnknownTorrent,
+}
+
+/// Protocol extensions advertised in the handshake's reserved bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// BEP 10 extension protocol.
+    pub extension_protocol: bool,
+    /// BEP 6 fast extension.
+    pub fast: bool,
+    /// BEP 5 DHT, i.e. the peer accepts `port` messages.
+    pub dht: bool,
+}
+
+impl Extensions {
+    pub fn from_reserved(reserved: [u8; 8]) -> Self {
+        Self {
+            extension_protocol: reserved[5] & 0x10 != 0,
+            fast: reserved[7] & 0x04 != 0,
+            dht: reserved[7] & 0x01 != 0,
+        }
+    }
+
+    pub fn to_reserved(self) -> [u8; 8] {
+        let mut reserved = [0; 8];
+        if self.extension_protocol {
+            reserved[5] |= 0x10;
+        }
+        if self.fast {
+            reserved[7] |= 0x04;
+        }
+        if self.dht {
+            reserved[7] |= 0x01;
+        }
+        reserved
+    }
+
+    /// Extensions both sides support, which are the only ones either may use.
+    pub fn intersect(self, other: Self) -> Self {
+        Self {
+            extension_protocol: self.extension_protocol && other.extension_protocol,
+            fast: self.fast && other.fast,
+            dht: self.dht && other.dht,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Handshake {
     pub protocol_length: u8,
     pub protocol: [u8; 19],
@@ -13,33 +79,107 @@ pub struct Handshake {
     pub peer_id: [u8; 20],
 }
 
-const HANDSHAKE_SIZE: usize = std::mem::size_of::<Handshake>();
-
 impl Handshake {
-    pub fn new(info_hash: [u8; 20]) -> Self {
+    pub fn new(info_hash: [u8; 20], peer_id: PeerId) -> Self {
         Self {
             protocol_length: 19,
-            protocol: *b"BitTorrent protocol",
+            protocol: *PROTOCOL,
             reserved_bytes: [0; 8],
             info_hash,
-            peer_id: *b"00112233445566778899",
+            peer_id: peer_id.0,
         }
     }
-    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        let bytes = self as *mut Self as *mut [u8; HANDSHAKE_SIZE];
-        // Safety: Self is a POD with repr(c) and repr(packed)
-        let bytes: &mut [u8; HANDSHAKE_SIZE] = unsafe { &mut *bytes };
+
+    pub fn with_extensions(mut self, extensions: Extensions) -> Self {
+        self.reserved_bytes = extensions.to_reserved();
+        self
+    }
+
+    pub fn extensions(&self) -> Extensions {
+        Extensions::from_reserved(self.reserved_bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8; HANDSHAKE_SIZE]) -> Self {
+        Self {
+            protocol_length: bytes[0],
+            protocol: bytes[1..20].try_into().unwrap(),
+            reserved_bytes: bytes[20..28].try_into().unwrap(),
+            info_hash: bytes[28..48].try_into().unwrap(),
+            peer_id: bytes[48..68].try_into().unwrap(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut bytes = [0; HANDSHAKE_SIZE];
+        bytes[0] = self.protocol_length;
+        bytes[1..20].copy_from_slice(&self.protocol);
+        bytes[20..28].copy_from_slice(&self.reserved_bytes);
+        bytes[28..48].copy_from_slice(&self.info_hash);
+        bytes[48..68].copy_from_slice(&self.peer_id);
         bytes
     }
+
+    /// Checks the peer's handshake `self` against the one we sent.
+    pub fn verify(&self, local: &Handshake) -> Result<HandshakeResult, HandshakeError> {
+        if self.protocol_length != 19 || self.protocol != *PROTOCOL {
+            return Err(HandshakeError::Protocol);
+        }
+        if self.info_hash != local.info_hash {
+            return Err(HandshakeError::InfoHashMismatch);
+        }
+        if self.peer_id == local.peer_id {
+            return Err(HandshakeError::SelfConnection);
+        }
+        Ok(HandshakeResult {
+            peer_id: PeerId(self.peer_id),
+            extensions: self.extensions().intersect(local.extensions()),
+        })
+    }
+}
+
+/// What a successful handshake tells about the peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandshakeResult {
+    pub peer_id: PeerId,
+    /// Extensions supported by both sides.
+    pub extensions: Extensions,
+}
+
+/// Sends `local` and reads the peer's full handshake, failing if it takes longer than
+/// `timeout` or does not match.
+pub fn perform_handshake(
+    stream: &mut TcpStream,
+    local: &Handshake,
+    timeout: Duration,
+) -> Result<HandshakeResult, HandshakeError> {
+    let previous_timeouts = (stream.read_timeout()?, stream.write_timeout()?);
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut buffer = [0; HANDSHAKE_SIZE];
+    let exchange = stream
+        .write_all(&local.to_bytes())
+        .and_then(|_| stream.read_exact(&mut buffer));
+    if let Err(err) = exchange {
+        return Err(match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => HandshakeError::Timeout,
+            _ => err.into(),
+        });
+    }
+    stream.set_read_timeout(previous_timeouts.0)?;
+    stream.set_write_timeout(previous_timeouts.1)?;
+    Handshake::from_bytes(&buffer).verify(local)
 }
 
-pub fn tcp_handshake(peer_addr: &str, info_hash: Vec<u8>) -> TcpStream {
-    let mut stream = TcpStream::connect(peer_addr).unwrap();
-    let mut handshake = Handshake::new(info_hash.try_into().unwrap());
-    stream.write(handshake.as_bytes_mut()).unwrap();
-    let mut buffer = [0; 68];
-    let bytes_read = stream.read(&mut buffer[..]).unwrap();
-    let response = buffer[..bytes_read][48..].to_vec();
-    println!("Server response: {:?}", hex::encode(response));
-    stream
+pub fn tcp_handshake(
+    peer_addr: &str,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+) -> Result<(TcpStream, HandshakeResult), HandshakeError> {
+    let mut stream = ProxyConfig::global().connect(peer_addr)?;
+    let local = Handshake::new(info_hash, peer_id).with_extensions(Extensions {
+        fast: true,
+        ..Extensions::default()
+    });
+    let result = perform_handshake(&mut stream, &local, HANDSHAKE_TIMEOUT)?;
+    Ok((stream, result))
 }
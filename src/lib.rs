@@ -1,6 +1,24 @@
+pub mod announcer;
+pub mod ban;
 pub mod bencode;
+pub mod bitfield;
+pub mod connection;
+pub mod dht;
+pub mod engine;
+pub mod extension;
 pub mod handshake;
 pub mod message;
-pub mod peer;
+pub mod peer_id;
+pub mod pex;
+pub mod picker;
+pub mod pool;
+pub mod proxy;
+pub mod seeder;
+pub mod session;
 pub mod torrent;
+pub mod tracker;
+pub mod tracker_client;
+pub mod tracker_server;
+pub mod udp_tracker;
+pub mod udp_tracker_server;
 pub mod utils;
@@ -1,8 +1,18 @@
-use bittorrent_starter_rust::{torrent, utils::decode};
+use anyhow::{anyhow, bail, Context};
+use bittorrent_starter_rust::dht::{Dht, DhtConfig, DHT_STATE_ENV};
+use bittorrent_starter_rust::proxy::ProxyConfig;
+use bittorrent_starter_rust::seeder::DEFAULT_LISTEN_PORT;
+use bittorrent_starter_rust::tracker_server::{self, HttpTrackerServer, PeerStore};
+use bittorrent_starter_rust::udp_tracker_server::UdpTrackerServer;
+use bittorrent_starter_rust::{torrent::TorrentFile, tracker, utils::decode};
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::fs::{self};
+use std::time::Duration;
 
-fn main() {
+fn main() -> anyhow::Result<()> {
+    if let Some(proxy) = ProxyConfig::from_env()? {
+        let _ = ProxyConfig::set_global(proxy);
+    }
     let args: Vec<String> = env::args().collect();
     let command = &args[1];
 
@@ -11,41 +21,165 @@ fn main() {
         decode(encoded_value);
     } else if command == "info" {
         let file_path = &args[2];
-        let contents = fs::read(file_path).unwrap();
-        let mut chars = contents.iter().peekable();
-        let torrent_file = torrent::parse_torrent_file(&mut chars);
+        let torrent_file = TorrentFile::from_path(file_path)?;
         torrent_file.show_info();
     } else if command == "peers" {
         let file_path = &args[2];
-        let contents = fs::read(file_path).unwrap();
-        let mut chars = contents.iter().peekable();
-        let torrent_file = torrent::parse_torrent_file(&mut chars);
-        let peers = torrent_file.peers();
+        let show_client = client_flag(&args[3..])?;
+        let torrent_file = TorrentFile::from_path(file_path)?;
+        let peers = torrent_file
+            .peers()
+            .with_context(|| format!("announcing to {}", torrent_file.announce))?;
         for peer in peers {
-            println!("Peer: {}", peer.ip_addr.to_string())
+            match peer.peer_id.and_then(|id| id.client()) {
+                Some(client) if show_client => println!("Peer: {} ({})", peer, client),
+                _ => println!("Peer: {}", peer),
+            }
+        }
+    } else if command == "dht_peers" {
+        let file_path = &args[2];
+        let torrent_file = TorrentFile::from_path(file_path)?;
+        let config = DhtConfig {
+            state_path: env::var_os(DHT_STATE_ENV).map(Into::into),
+            ..DhtConfig::default()
+        };
+        let dht = Dht::start(config).context("starting the DHT node")?;
+        let nodes = dht.bootstrap(&torrent_file.nodes);
+        if nodes == 0 {
+            bail!("could not reach any DHT node");
+        }
+        for peer in dht.lookup_peers(torrent_file.info.info_hash()) {
+            println!("Peer: {}", peer);
         }
+        dht.shutdown().context("saving the DHT routing table")?;
     } else if command == "handshake" {
         let file_path = &args[2];
-        let contents = fs::read(file_path).unwrap();
-        let mut chars = contents.iter().peekable();
-        let torrent_file = torrent::parse_torrent_file(&mut chars);
-        torrent_file.perform_handshake();
+        let show_client = client_flag(&args[3..])?;
+        let torrent_file = TorrentFile::from_path(file_path)?;
+        let (_, handshake) = torrent_file
+            .perform_handshake()
+            .with_context(|| format!("handshaking with a peer from {}", torrent_file.announce))?;
+        let peer_id = handshake.peer_id.to_string();
+        match handshake.peer_id.client() {
+            Some(client) if show_client => println!("Server response: {:?} ({})", peer_id, client),
+            _ => println!("Server response: {:?}", peer_id),
+        }
     } else if command == "download_piece" {
         let output_file_path = &args[3];
         let file_path = &args[4];
-        let piece_index = &args[5].parse().unwrap();
-        let contents = fs::read(file_path).unwrap();
-        let mut chars = contents.iter().peekable();
-        let torrent_file = torrent::parse_torrent_file(&mut chars);
-        torrent_file.download_piece(*piece_index, output_file_path)
+        let piece_index = &args[5].parse()?;
+        let torrent_file = TorrentFile::from_path(file_path)?;
+        torrent_file
+            .download_piece(*piece_index, output_file_path)
+            .with_context(|| format!("downloading piece {piece_index}"))?;
     } else if command == "download" {
         let output_file_path = &args[3];
         let file_path = &args[4];
-        let contents = fs::read(file_path).unwrap();
-        let mut chars = contents.iter().peekable();
-        let torrent_file = torrent::parse_torrent_file(&mut chars);
-        torrent_file.download(output_file_path)
+        let torrent_file = TorrentFile::from_path(file_path)?;
+        torrent_file
+            .download(output_file_path)
+            .with_context(|| format!("downloading {}", file_path))?;
+    } else if command == "seed" {
+        let file_path = &args[2];
+        let data_path = &args[3];
+        let listen = match args.get(4).map(String::as_str) {
+            Some("--listen") => args.get(5).context("--listen needs a value")?.clone(),
+            Some(flag) => bail!("unknown flag {}", flag),
+            None => format!("0.0.0.0:{}", DEFAULT_LISTEN_PORT),
+        };
+        let torrent_file = TorrentFile::from_path(file_path)?;
+        torrent_file
+            .seed(data_path, &listen)
+            .with_context(|| format!("seeding {}", data_path))?;
+    } else if command == "scrape" {
+        // Torrents sharing a tracker are scraped together in one request.
+        let mut by_tracker: HashMap<String, Vec<[u8; 20]>> = HashMap::new();
+        for file_path in &args[2..] {
+            let torrent_file = TorrentFile::from_path(file_path)?;
+            by_tracker
+                .entry(torrent_file.announce)
+                .or_default()
+                .push(torrent_file.info.info_hash());
+        }
+        for (announce, info_hashes) in by_tracker {
+            let stats = tracker::scrape(&announce, &info_hashes)
+                .with_context(|| format!("scraping {}", announce))?;
+            for info_hash in info_hashes {
+                match stats.get(&info_hash) {
+                    Some(stats) => println!(
+                        "{}: seeders {}, leechers {}, completed {}",
+                        hex::encode(info_hash),
+                        stats.complete,
+                        stats.incomplete,
+                        stats.downloaded
+                    ),
+                    None => println!("{}: unknown to {}", hex::encode(info_hash), announce),
+                }
+            }
+        }
+    } else if command == "tracker" && args.get(2).map(String::as_str) == Some("serve") {
+        let mut http_addr = None;
+        let mut udp_addr = None;
+        let mut allowlist: Option<HashSet<[u8; 20]>> = None;
+        let mut interval = tracker_server::DEFAULT_INTERVAL;
+        let mut trust_ip = false;
+        let mut flags = args[3..].iter();
+        while let Some(flag) = flags.next() {
+            if flag == "--trust-ip" {
+                trust_ip = true;
+                continue;
+            }
+            let value = flags
+                .next()
+                .with_context(|| format!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--http" => http_addr = Some(value.clone()),
+                "--udp" => udp_addr = Some(value.clone()),
+                "--allow" => {
+                    let info_hash = hex::decode(value)?
+                        .try_into()
+                        .map_err(|_| anyhow!("info hash must be 20 bytes"))?;
+                    allowlist.get_or_insert_with(HashSet::new).insert(info_hash);
+                }
+                "--interval" => interval = Duration::from_secs(value.parse()?),
+                _ => bail!("unknown flag {}", flag),
+            }
+        }
+        if http_addr.is_none() && udp_addr.is_none() {
+            bail!("usage: tracker serve [--http <addr>] [--udp <addr>] [--trust-ip]");
+        }
+        // Both frontends serve the same swarms.
+        let store = PeerStore::with_allowlist(interval * 2, allowlist);
+        let mut servers = Vec::new();
+        if let Some(addr) = http_addr {
+            let server = HttpTrackerServer::bind(&addr, store.clone())?
+                .with_interval(interval)
+                .with_trusted_ip(trust_ip);
+            println!("Serving HTTP tracker on {}", server.announce_url()?);
+            servers.push(server.spawn());
+        }
+        if let Some(addr) = udp_addr {
+            let server = UdpTrackerServer::bind(&addr, store)?
+                .with_interval(interval)
+                .with_trusted_ip(trust_ip);
+            println!("Serving UDP tracker on {}", server.announce_url()?);
+            servers.push(server.spawn());
+        }
+        for server in servers {
+            server.join().expect("tracker server panicked")?;
+        }
     } else {
         println!("unknown command: {}", args[1])
     }
+    Ok(())
+}
+
+/// Whether `--client` asks to name the remote clients. It is opt-in so the plain lines stay as
+/// scripts expect them.
+fn client_flag(flags: &[String]) -> anyhow::Result<bool> {
+    match flags.first().map(String::as_str) {
+        Some("--client") => Ok(true),
+        Some(flag) => bail!("unknown flag {}", flag),
+        None => Ok(false),
+    }
 }
@@ -1,3 +1,11 @@
+use bytes::{Buf, BufMut, BytesMut};
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+/// Largest frame accepted from a peer, counting the ID but not the length prefix. Leaves room
+/// for 128 KiB blocks and the bitfield of a torrent with two million pieces.
+pub const MAX_MESSAGE_LEN: usize = 1 << 18;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageId {
     Choke = 0,
@@ -9,11 +17,20 @@ pub enum MessageId {
     Request = 6,
     Piece = 7,
     Cancel = 8,
+    Port = 9,
+    SuggestPiece = 13,
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
+    Extended = 20,
 }
 
-impl From<u8> for MessageId {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for MessageId {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
             0 => MessageId::Choke,
             1 => MessageId::Unchoke,
             2 => MessageId::Interested,
@@ -23,13 +40,326 @@ impl From<u8> for MessageId {
             6 => MessageId::Request,
             7 => MessageId::Piece,
             8 => MessageId::Cancel,
-            _ => unreachable!(),
+            9 => MessageId::Port,
+            13 => MessageId::SuggestPiece,
+            14 => MessageId::HaveAll,
+            15 => MessageId::HaveNone,
+            16 => MessageId::RejectRequest,
+            17 => MessageId::AllowedFast,
+            20 => MessageId::Extended,
+            _ => return Err(value),
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MessageError {
+    #[error("message of {len} bytes exceeds the limit of {max}")]
+    TooLong { len: usize, max: usize },
+    #[error("malformed {id:?} message: {reason}")]
+    Malformed { id: MessageId, reason: &'static str },
+    #[error("peer sent {0:?} without negotiating the fast extension")]
+    NotNegotiated(MessageId),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A message of the peer wire protocol, without its length prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    /// One bit per piece, most significant bit of the first byte for piece 0.
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The peer's DHT port (BEP 5).
+    Port(u16),
+    /// The following five belong to the fast extension (BEP 6) and are only valid once both
+    /// handshakes set its reserved bit.
+    SuggestPiece {
+        index: u32,
+    },
+    HaveAll,
+    HaveNone,
+    /// The sender will not answer this request.
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// The piece may be requested even while the sender chokes us.
+    AllowedFast {
+        index: u32,
+    },
+    /// A BEP 10 extension message. ID 0 is the extended handshake, any other ID is the one
+    /// the receiver assigned to the extension in its own handshake.
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// A message this codec does not know, kept verbatim so extensions can handle it.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    /// Whether the message belongs to the fast extension.
+    pub fn is_fast(&self) -> bool {
+        matches!(
+            self,
+            PeerMessage::SuggestPiece { .. }
+                | PeerMessage::HaveAll
+                | PeerMessage::HaveNone
+                | PeerMessage::RejectRequest { .. }
+                | PeerMessage::AllowedFast { .. }
+        )
+    }
+
+    /// The wire ID, or `None` for keep-alives and unknown messages.
+    pub fn id(&self) -> Option<MessageId> {
+        Some(match self {
+            PeerMessage::KeepAlive | PeerMessage::Unknown { .. } => return None,
+            PeerMessage::Choke => MessageId::Choke,
+            PeerMessage::Unchoke => MessageId::Unchoke,
+            PeerMessage::Interested => MessageId::Interested,
+            PeerMessage::NotInterested => MessageId::NotInterested,
+            PeerMessage::Have { .. } => MessageId::Have,
+            PeerMessage::Bitfield(_) => MessageId::BitField,
+            PeerMessage::Request { .. } => MessageId::Request,
+            PeerMessage::Piece { .. } => MessageId::Piece,
+            PeerMessage::Cancel { .. } => MessageId::Cancel,
+            PeerMessage::Port(_) => MessageId::Port,
+            PeerMessage::SuggestPiece { .. } => MessageId::SuggestPiece,
+            PeerMessage::HaveAll => MessageId::HaveAll,
+            PeerMessage::HaveNone => MessageId::HaveNone,
+            PeerMessage::RejectRequest { .. } => MessageId::RejectRequest,
+            PeerMessage::AllowedFast { .. } => MessageId::AllowedFast,
+            PeerMessage::Extended { .. } => MessageId::Extended,
+        })
+    }
+
+    /// Appends the message to `buf`, length prefix included.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let (id, payload_len) = match self {
+            PeerMessage::KeepAlive => {
+                buf.put_u32(0);
+                return;
+            }
+            PeerMessage::Unknown { id, payload } => (*id, payload.len()),
+            PeerMessage::Bitfield(bits) => (MessageId::BitField as u8, bits.len()),
+            PeerMessage::Piece { block, .. } => (MessageId::Piece as u8, 8 + block.len()),
+            PeerMessage::Have { .. }
+            | PeerMessage::SuggestPiece { .. }
+            | PeerMessage::AllowedFast { .. } => (self.id().unwrap() as u8, 4),
+            PeerMessage::Request { .. }
+            | PeerMessage::Cancel { .. }
+            | PeerMessage::RejectRequest { .. } => (self.id().unwrap() as u8, 12),
+            PeerMessage::Port(_) => (MessageId::Port as u8, 2),
+            PeerMessage::Extended { payload, .. } => (MessageId::Extended as u8, 1 + payload.len()),
+            _ => (self.id().unwrap() as u8, 0),
+        };
+        buf.reserve(4 + 1 + payload_len);
+        buf.put_u32(1 + payload_len as u32);
+        buf.put_u8(id);
+        match self {
+            PeerMessage::Have { index }
+            | PeerMessage::SuggestPiece { index }
+            | PeerMessage::AllowedFast { index } => buf.put_u32(*index),
+            PeerMessage::Bitfield(bits) => buf.put_slice(bits),
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            }
+            | PeerMessage::RejectRequest {
+                index,
+                begin,
+                length,
+            } => {
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_u32(*length);
+            }
+            PeerMessage::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_slice(block);
+            }
+            PeerMessage::Port(port) => buf.put_u16(*port),
+            PeerMessage::Extended { id, payload } => {
+                buf.put_u8(*id);
+                buf.put_slice(payload);
+            }
+            PeerMessage::Unknown { payload, .. } => buf.put_slice(payload),
+            _ => {}
         }
     }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+        buf.to_vec()
+    }
+
+    /// Decodes a frame body: the ID followed by the payload. An empty frame is a keep-alive.
+    pub fn decode(frame: &[u8]) -> Result<Self, MessageError> {
+        let Some((&raw_id, mut payload)) = frame.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let Ok(id) = MessageId::try_from(raw_id) else {
+            return Ok(PeerMessage::Unknown {
+                id: raw_id,
+                payload: payload.to_vec(),
+            });
+        };
+        let expect_len = |len: usize| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(MessageError::Malformed {
+                    id,
+                    reason: "wrong payload length",
+                })
+            }
+        };
+        Ok(match id {
+            MessageId::Choke => expect_len(0).map(|_| PeerMessage::Choke)?,
+            MessageId::Unchoke => expect_len(0).map(|_| PeerMessage::Unchoke)?,
+            MessageId::Interested => expect_len(0).map(|_| PeerMessage::Interested)?,
+            MessageId::NotInterested => expect_len(0).map(|_| PeerMessage::NotInterested)?,
+            MessageId::Have | MessageId::SuggestPiece | MessageId::AllowedFast => {
+                expect_len(4)?;
+                let index = payload.get_u32();
+                match id {
+                    MessageId::Have => PeerMessage::Have { index },
+                    MessageId::SuggestPiece => PeerMessage::SuggestPiece { index },
+                    _ => PeerMessage::AllowedFast { index },
+                }
+            }
+            MessageId::HaveAll => expect_len(0).map(|_| PeerMessage::HaveAll)?,
+            MessageId::HaveNone => expect_len(0).map(|_| PeerMessage::HaveNone)?,
+            MessageId::BitField => PeerMessage::Bitfield(payload.to_vec()),
+            MessageId::Request | MessageId::Cancel | MessageId::RejectRequest => {
+                expect_len(12)?;
+                let (index, begin, length) =
+                    (payload.get_u32(), payload.get_u32(), payload.get_u32());
+                match id {
+                    MessageId::Request => PeerMessage::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    MessageId::Cancel => PeerMessage::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => PeerMessage::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            MessageId::Piece => {
+                if payload.len() < 8 {
+                    return Err(MessageError::Malformed {
+                        id,
+                        reason: "missing index or offset",
+                    });
+                }
+                PeerMessage::Piece {
+                    index: payload.get_u32(),
+                    begin: payload.get_u32(),
+                    block: payload.to_vec(),
+                }
+            }
+            MessageId::Port => {
+                expect_len(2)?;
+                PeerMessage::Port(payload.get_u16())
+            }
+            MessageId::Extended => {
+                let Some((&id, payload)) = payload.split_first() else {
+                    return Err(MessageError::Malformed {
+                        id: MessageId::Extended,
+                        reason: "missing extended message ID",
+                    });
+                };
+                PeerMessage::Extended {
+                    id,
+                    payload: payload.to_vec(),
+                }
+            }
+        })
+    }
+
+    /// Takes one complete frame off the front of `buf`, or returns `None` if more bytes are
+    /// needed. Frames longer than `max_len` are rejected before they are buffered.
+    pub fn decode_frame(buf: &mut BytesMut, max_len: usize) -> Result<Option<Self>, MessageError> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        if len > max_len {
+            return Err(MessageError::TooLong { len, max: max_len });
+        }
+        if buf.len() < 4 + len {
+            buf.reserve(4 + len - buf.len());
+            return Ok(None);
+        }
+        buf.advance(4);
+        let frame = buf.split_to(len);
+        Self::decode(&frame).map(Some)
+    }
+}
+
+/// Reads one message, enforcing `MAX_MESSAGE_LEN`.
+pub fn read_message(stream: &mut impl Read) -> Result<PeerMessage, MessageError> {
+    let mut length_bytes = [0; 4];
+    stream.read_exact(&mut length_bytes)?;
+    let len = u32::from_be_bytes(length_bytes) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(MessageError::TooLong {
+            len,
+            max: MAX_MESSAGE_LEN,
+        });
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame)?;
+    PeerMessage::decode(&frame)
 }
 
-#[derive(Debug)]
-pub struct Message {
-    pub message_id: MessageId,
-    pub payload: Vec<u8>,
+pub fn write_message(stream: &mut impl Write, message: &PeerMessage) -> io::Result<()> {
+    stream.write_all(&message.to_bytes())
 }
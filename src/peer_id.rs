@@ -0,0 +1,178 @@
+use crate::utils::random_u64;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Azureus-style prefix identifying this client: `BR` for bittorrent-rust, version 0.1.0.0.
+pub const CLIENT_PREFIX: &[u8; 8] = b"-BR0100-";
+
+static SESSION: OnceLock<PeerId> = OnceLock::new();
+
+/// The 20-byte ID a client announces to trackers and sends in handshakes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeerId(pub [u8; 20]);
+
+impl PeerId {
+    /// A fresh ID made of `CLIENT_PREFIX` followed by 12 random bytes.
+    pub fn generate() -> Self {
+        Self::with_prefix(CLIENT_PREFIX)
+    }
+
+    /// A fresh ID starting with `prefix` (truncated to 20 bytes) and padded with random bytes.
+    pub fn with_prefix(prefix: &[u8]) -> Self {
+        let mut id = [0; 20];
+        let random = [random_u64().to_be_bytes(), random_u64().to_be_bytes()].concat();
+        let prefix = &prefix[..prefix.len().min(20)];
+        id[..prefix.len()].copy_from_slice(prefix);
+        id[prefix.len()..].copy_from_slice(&random[..20 - prefix.len()]);
+        PeerId(id)
+    }
+
+    /// The ID used for every announce and handshake of this process. Generated on first use
+    /// unless `set_session` was called before.
+    pub fn session() -> Self {
+        *SESSION.get_or_init(Self::generate)
+    }
+
+    /// Fixes the session ID. Fails, returning the ID in use, once the session ID has been used.
+    pub fn set_session(id: PeerId) -> Result<(), PeerId> {
+        SESSION.set(id).map_err(|_| Self::session())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
+    /// Best-effort name and version of the client that generated this ID, e.g.
+    /// `qBittorrent 4.5.2`, recognizing the Azureus, Shadow and Mainline conventions.
+    pub fn client(&self) -> Option<String> {
+        let id = &self.0;
+        // Azureus style: -XXvvvv-
+        if id[0] == b'-' && id[7] == b'-' {
+            let code = std::str::from_utf8(&id[1..3]).ok()?;
+            let name = azureus_client(code)?;
+            return Some(format!("{} {}", name, version(&id[3..7])?));
+        }
+        // Mainline style: M4-3-6-- or M4-10-2-, with the version ending at the first `--`
+        // within those 8 bytes. The random bytes after them may contain digits too.
+        if id[0] == b'M' && id[1].is_ascii_digit() {
+            let end = id[..8].windows(2).position(|w| w == b"--").unwrap_or(8);
+            let version = &id[1..end];
+            if !version.iter().all(|b| b.is_ascii_digit() || *b == b'-') {
+                return None;
+            }
+            let version = std::str::from_utf8(version).ok()?;
+            return Some(format!(
+                "BitTorrent {}",
+                version.trim_end_matches('-').replace('-', ".")
+            ));
+        }
+        // Shadow style: S587----, one character per version component
+        if id[6..8] != *b"--" {
+            return None;
+        }
+        let name = shadow_client(id[0])?;
+        let digits: Vec<u8> = id[1..6]
+            .iter()
+            .copied()
+            .take_while(|b| *b != b'-')
+            .collect();
+        if digits.is_empty() {
+            return None;
+        }
+        Some(format!("{} {}", name, version(&digits)?))
+    }
+}
+
+/// Turns version characters into a dotted version, where `A`-`Z` stand for 10-35 as some
+/// clients use them. Trailing zero components beyond the second are dropped.
+fn version(chars: &[u8]) -> Option<String> {
+    let mut parts = chars
+        .iter()
+        .map(|c| match c {
+            b'0'..=b'9' => Some((c - b'0') as u32),
+            b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 10),
+            _ => None,
+        })
+        .collect::<Option<Vec<u32>>>()?;
+    while parts.len() > 2 && parts.last() == Some(&0) {
+        parts.pop();
+    }
+    Some(
+        parts
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<_>>()
+            .join("."),
+    )
+}
+
+fn azureus_client(code: &str) -> Option<&'static str> {
+    Some(match code {
+        "AG" | "A~" => "Ares",
+        "AZ" => "Vuze",
+        "BC" => "BitComet",
+        "BI" => "BiglyBT",
+        "BR" => "bittorrent-rust",
+        "BT" => "BitTorrent",
+        "DE" => "Deluge",
+        "FD" => "Free Download Manager",
+        "FW" => "FrostWire",
+        "KT" => "KTorrent",
+        "LT" => "libtorrent",
+        "lt" => "rTorrent",
+        "PI" => "PicoTorrent",
+        "qB" => "qBittorrent",
+        "SD" => "Thunder",
+        "TL" => "Tribler",
+        "TR" => "Transmission",
+        "UM" => "µTorrent Mac",
+        "UT" => "µTorrent",
+        "WD" => "WebTorrent Desktop",
+        "WW" => "WebTorrent",
+        "XL" => "Xunlei",
+        _ => return None,
+    })
+}
+
+fn shadow_client(code: u8) -> Option<&'static str> {
+    Some(match code {
+        b'A' => "ABC",
+        b'O' => "Osprey Permaseed",
+        b'Q' => "BTQueue",
+        b'R' => "Tribler",
+        b'S' => "Shadow",
+        b'T' => "BitTornado",
+        b'U' => "UPnP NAT Bit Torrent",
+        _ => return None,
+    })
+}
+
+impl From<[u8; 20]> for PeerId {
+    fn from(id: [u8; 20]) -> Self {
+        PeerId(id)
+    }
+}
+
+impl TryFrom<&[u8]> for PeerId {
+    type Error = std::array::TryFromSliceError;
+
+    fn try_from(id: &[u8]) -> Result<Self, Self::Error> {
+        Ok(PeerId(id.try_into()?))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.client() {
+            Some(client) => write!(f, "PeerId({}, {})", self, client),
+            None => write!(f, "PeerId({})", self),
+        }
+    }
+}
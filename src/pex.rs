@@ -0,0 +1,222 @@
+use crate::bencode::BencodeValue;
+use crate::extension::{Extension, ExtensionError};
+use crate::pool::{CandidatePool, PeerSource};
+use crate::torrent::TorrentFileInfo;
+use crate::tracker::Peer;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Name of the extension in the BEP 10 handshake.
+pub const PEX_NAME: &str = "ut_pex";
+/// Minimum time between two messages to the same peer (BEP 11).
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers listed as added or dropped in one message.
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+/// Per-peer flags sent alongside added peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PexFlags(pub u8);
+
+impl PexFlags {
+    pub const ENCRYPTION: PexFlags = PexFlags(0x01);
+    pub const SEED: PexFlags = PexFlags(0x02);
+    pub const UTP: PexFlags = PexFlags(0x04);
+    pub const HOLEPUNCH: PexFlags = PexFlags(0x08);
+    /// We reached the peer with an outgoing connection, so others can too.
+    pub const REACHABLE: PexFlags = PexFlags(0x10);
+
+    pub fn contains(self, flags: PexFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn union(self, flags: PexFlags) -> PexFlags {
+        PexFlags(self.0 | flags.0)
+    }
+}
+
+/// One ut_pex message: peers the sender connected to and disconnected from since its
+/// previous message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, PexFlags)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+#[derive(Deserialize)]
+struct RawPexMessage {
+    added: Option<ByteBuf>,
+    #[serde(rename = "added.f")]
+    added_flags: Option<ByteBuf>,
+    added6: Option<ByteBuf>,
+    #[serde(rename = "added6.f")]
+    added6_flags: Option<ByteBuf>,
+    dropped: Option<ByteBuf>,
+    dropped6: Option<ByteBuf>,
+}
+
+impl PexMessage {
+    pub fn from_bytes(payload: &[u8]) -> Result<Self, ExtensionError> {
+        let malformed = |reason: String| ExtensionError::Extension {
+            name: PEX_NAME,
+            reason,
+        };
+        let bencode =
+            BencodeValue::from_bytes(payload).map_err(|err| malformed(err.to_string()))?;
+        let raw = RawPexMessage::deserialize(bencode).map_err(|err| malformed(err.to_string()))?;
+        let peers = |compact: Option<ByteBuf>, ipv6| {
+            Peer::from_compact(compact.as_deref().map_or(&[][..], Vec::as_slice), ipv6)
+                .into_iter()
+                .map(|peer| peer.socket_addr())
+        };
+        // Flags are optional; a missing or short list leaves the rest unflagged.
+        let flagged = |compact, flags: Option<ByteBuf>, ipv6| {
+            let flags = flags.map(ByteBuf::into_vec).unwrap_or_default();
+            peers(compact, ipv6)
+                .enumerate()
+                .map(move |(i, addr)| (addr, PexFlags(flags.get(i).copied().unwrap_or(0))))
+        };
+        Ok(Self {
+            added: flagged(raw.added, raw.added_flags, false)
+                .chain(flagged(raw.added6, raw.added6_flags, true))
+                .collect(),
+            dropped: peers(raw.dropped, false)
+                .chain(peers(raw.dropped6, true))
+                .collect(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (mut added, mut added_flags, mut added6, mut added6_flags) =
+            (vec![], vec![], vec![], vec![]);
+        for (addr, flags) in &self.added {
+            let (compact, flag_list) = if addr.is_ipv4() {
+                (&mut added, &mut added_flags)
+            } else {
+                (&mut added6, &mut added6_flags)
+            };
+            compact.extend(Peer::new(*addr).to_compact());
+            flag_list.push(flags.0);
+        }
+        let (mut dropped, mut dropped6) = (vec![], vec![]);
+        for addr in &self.dropped {
+            let compact = if addr.is_ipv4() {
+                &mut dropped
+            } else {
+                &mut dropped6
+            };
+            compact.extend(Peer::new(*addr).to_compact());
+        }
+        BencodeValue::dictionary([
+            ("added", BencodeValue::ByteString(added)),
+            ("added.f", BencodeValue::ByteString(added_flags)),
+            ("added6", BencodeValue::ByteString(added6)),
+            ("added6.f", BencodeValue::ByteString(added6_flags)),
+            ("dropped", BencodeValue::ByteString(dropped)),
+            ("dropped6", BencodeValue::ByteString(dropped6)),
+        ])
+        .encode()
+    }
+}
+
+/// The ut_pex extension for one connection. Tells the peer which of our connections came and
+/// went since the last message, at most once per `PEX_INTERVAL`, and queues the peers it
+/// reports in the candidate pool.
+pub struct UtPex {
+    pool: CandidatePool,
+    /// The peer this instance talks to, which is never advertised back to itself.
+    remote: SocketAddr,
+    /// Connections the peer has heard about from us.
+    advertised: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+    interval: Duration,
+}
+
+impl UtPex {
+    /// Returns `None` for private torrents, which must only get peers from their tracker.
+    pub fn for_torrent(
+        info: &TorrentFileInfo,
+        pool: CandidatePool,
+        remote: SocketAddr,
+    ) -> Option<Self> {
+        if info.is_private() {
+            return None;
+        }
+        Some(Self {
+            pool,
+            remote,
+            advertised: HashSet::new(),
+            last_sent: None,
+            interval: PEX_INTERVAL,
+        })
+    }
+
+    /// Overrides `PEX_INTERVAL`; peers may disconnect us for sending more often.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    fn changes(&mut self) -> PexMessage {
+        let connected: Vec<_> = self
+            .pool
+            .connected_peers()
+            .into_iter()
+            .filter(|(addr, _)| *addr != self.remote)
+            .collect();
+        let current: HashSet<SocketAddr> = connected.iter().map(|(addr, _)| *addr).collect();
+        let added: Vec<_> = connected
+            .into_iter()
+            .filter(|(addr, _)| !self.advertised.contains(addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let dropped: Vec<_> = self
+            .advertised
+            .iter()
+            .filter(|addr| !current.contains(addr))
+            .copied()
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        // Whatever did not fit goes out with the next message.
+        self.advertised.extend(added.iter().map(|(addr, _)| *addr));
+        for addr in &dropped {
+            self.advertised.remove(addr);
+        }
+        PexMessage { added, dropped }
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        PEX_NAME
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        let message = PexMessage::from_bytes(payload)?;
+        let addrs = message
+            .added
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .filter(|addr| addr.port() != 0)
+            .take(MAX_PEERS_PER_MESSAGE);
+        self.pool.add(addrs, PeerSource::Pex);
+        Ok(Vec::new())
+    }
+
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        if self
+            .last_sent
+            .is_some_and(|sent| sent.elapsed() < self.interval)
+        {
+            return Vec::new();
+        }
+        let message = self.changes();
+        if message.added.is_empty() && message.dropped.is_empty() {
+            return Vec::new();
+        }
+        self.last_sent = Some(Instant::now());
+        vec![message.to_bytes()]
+    }
+}
@@ -0,0 +1,179 @@
+use crate::bitfield::Bitfield;
+use crate::utils::random_u64;
+use std::collections::HashSet;
+
+/// Chooses which piece to download next. Counts how many connected peers have each piece,
+/// from their bitfields and `have` messages, and prefers, in order:
+///
+/// 1. pieces already partially downloaded, so their blocks become usable soonest;
+/// 2. any piece at random until the first one completes, to get something to share quickly;
+/// 3. the rarest pieces, so they spread before the peers holding them leave.
+///
+/// Ties are broken at random, so peers downloading at the same time spread out.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    availability: Vec<u32>,
+    wanted: Bitfield,
+    have: Bitfield,
+    /// Pieces some blocks were received for, while nobody works on them.
+    partial: HashSet<u32>,
+    /// Pieces a peer is downloading.
+    busy: HashSet<u32>,
+}
+
+impl PiecePicker {
+    /// A picker wanting all `piece_count` pieces, none of them available yet.
+    pub fn new(piece_count: usize) -> Self {
+        Self {
+            availability: vec![0; piece_count],
+            wanted: Bitfield::full(piece_count),
+            have: Bitfield::new(piece_count),
+            partial: HashSet::new(),
+            busy: HashSet::new(),
+        }
+    }
+
+    /// Restricts picking to the pieces set in `wanted`.
+    pub fn with_wanted(mut self, wanted: Bitfield) -> Self {
+        self.wanted = wanted;
+        self
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.availability.len()
+    }
+
+    /// Number of connected peers that have piece `index`.
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    /// Counts the pieces of a newly connected peer, or of a peer's new bitfield.
+    pub fn add_peer(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Stops counting the pieces of a peer, as last added.
+    pub fn remove_peer(&mut self, pieces: &Bitfield) {
+        for index in pieces.iter() {
+            if let Some(count) = self.availability.get_mut(index) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// A peer announced piece `index` with `have`.
+    pub fn peer_has(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn wanted(&self) -> &Bitfield {
+        &self.wanted
+    }
+
+    /// Whether piece `index` is wanted and not downloaded yet.
+    pub fn is_missing(&self, index: u32) -> bool {
+        self.wanted.has(index as usize) && !self.have.has(index as usize)
+    }
+
+    /// Whether every wanted piece is downloaded.
+    pub fn is_complete(&self) -> bool {
+        self.wanted.iter().all(|index| self.have.has(index))
+    }
+
+    pub fn is_busy(&self, index: u32) -> bool {
+        self.busy.contains(&index)
+    }
+
+    /// Picks a missing piece nobody is working on and for which `available` holds, usually
+    /// whether the peer has it, and marks it busy until `release` or `complete`.
+    pub fn pick(&mut self, available: impl Fn(u32) -> bool) -> Option<u32> {
+        let candidates: Vec<u32> = (0..self.piece_count() as u32)
+            .filter(|index| self.is_missing(*index) && !self.is_busy(*index) && available(*index))
+            .collect();
+        let partial: Vec<u32> = candidates
+            .iter()
+            .copied()
+            .filter(|index| self.partial.contains(index))
+            .collect();
+        let index = if !partial.is_empty() {
+            self.rarest(&partial)?
+        } else if self.have.count() == 0 {
+            *choose(&candidates)?
+        } else {
+            self.rarest(&candidates)?
+        };
+        self.busy.insert(index);
+        Some(index)
+    }
+
+    /// Like `pick`, but takes the first of `suggested` that qualifies, as a peer sending
+    /// Suggest Piece (BEP 6) asks, before any other piece.
+    pub fn pick_suggested(
+        &mut self,
+        suggested: impl IntoIterator<Item = u32>,
+        available: impl Fn(u32) -> bool,
+    ) -> Option<u32> {
+        let suggested = suggested.into_iter().find(|index| {
+            (*index as usize) < self.piece_count()
+                && self.is_missing(*index)
+                && !self.is_busy(*index)
+                && available(*index)
+        });
+        match suggested {
+            Some(index) => {
+                self.busy.insert(index);
+                Some(index)
+            }
+            None => self.pick(available),
+        }
+    }
+
+    /// A peer stopped working on piece `index`; `partial` tells whether it keeps the blocks
+    /// received so far for the next one.
+    pub fn release(&mut self, index: u32, partial: bool) {
+        self.busy.remove(&index);
+        if partial && self.is_missing(index) {
+            self.partial.insert(index);
+        } else {
+            self.partial.remove(&index);
+        }
+    }
+
+    /// Piece `index` is downloaded and verified.
+    pub fn complete(&mut self, index: u32) {
+        self.busy.remove(&index);
+        self.partial.remove(&index);
+        self.have.set(index as usize);
+    }
+
+    fn rarest(&self, candidates: &[u32]) -> Option<u32> {
+        let fewest = candidates
+            .iter()
+            .map(|index| self.availability(*index))
+            .min()?;
+        let rarest: Vec<u32> = candidates
+            .iter()
+            .copied()
+            .filter(|index| self.availability(*index) == fewest)
+            .collect();
+        choose(&rarest).copied()
+    }
+}
+
+fn choose<T>(items: &[T]) -> Option<&T> {
+    if items.is_empty() {
+        return None;
+    }
+    items.get((random_u64() % items.len() as u64) as usize)
+}
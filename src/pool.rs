@@ -0,0 +1,97 @@
+use crate::pex::PexFlags;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// Where a candidate address came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Incoming,
+}
+
+#[derive(Default)]
+struct PoolState {
+    candidates: VecDeque<(SocketAddr, PeerSource)>,
+    known: HashSet<SocketAddr>,
+    connected: HashMap<SocketAddr, PexFlags>,
+    banned: HashSet<IpAddr>,
+}
+
+/// Addresses worth connecting to, fed by trackers, the DHT and PEX, plus the peers we are
+/// connected to, which PEX advertises. Clones share the same pool, one per torrent.
+#[derive(Clone, Default)]
+pub struct CandidatePool {
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl CandidatePool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the addresses not seen before and not banned, and returns how many that were.
+    pub fn add(&self, addrs: impl IntoIterator<Item = SocketAddr>, source: PeerSource) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut added = 0;
+        for addr in addrs {
+            if !state.banned.contains(&addr.ip()) && state.known.insert(addr) {
+                state.candidates.push_back((addr, source));
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// The oldest queued candidate.
+    pub fn pop(&self) -> Option<(SocketAddr, PeerSource)> {
+        self.state.lock().unwrap().candidates.pop_front()
+    }
+
+    /// Stops connecting to `ip` and drops its queued candidates. Peers already connected
+    /// from it are left for their owner to close.
+    pub fn ban(&self, ip: IpAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.banned.insert(ip);
+        state.candidates.retain(|(addr, _)| addr.ip() != ip);
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.state.lock().unwrap().banned.contains(&ip)
+    }
+
+    /// Number of queued candidates.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records an established connection, so it is advertised to other peers.
+    pub fn connected(&self, addr: SocketAddr, flags: PexFlags) {
+        let mut state = self.state.lock().unwrap();
+        state.known.insert(addr);
+        state.connected.insert(addr, flags);
+    }
+
+    /// Forgets a closed connection, so the address is queued again the next time a source
+    /// names it, unless its IP is banned by then.
+    pub fn disconnected(&self, addr: SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.connected.remove(&addr);
+        state.known.remove(&addr);
+    }
+
+    pub fn connected_peers(&self) -> Vec<(SocketAddr, PexFlags)> {
+        let state = self.state.lock().unwrap();
+        state
+            .connected
+            .iter()
+            .map(|(addr, flags)| (*addr, *flags))
+            .collect()
+    }
+}
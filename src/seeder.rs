@@ -0,0 +1,339 @@
+use crate::bitfield::Bitfield;
+use crate::connection::{ConnectionError, PeerConnection};
+use crate::extension::{ExtensionRegistry, DEFAULT_REQQ};
+use crate::handshake::{Extensions, Handshake};
+use crate::message::PeerMessage;
+use crate::peer_id::PeerId;
+use crate::session::{PeerSession, SessionError, BLOCK_SIZE};
+use crate::torrent::TorrentFileInfo;
+use crate::tracker::TransferStats;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+/// The port announced to trackers when nothing else is configured.
+pub const DEFAULT_LISTEN_PORT: u16 = 6881;
+/// How often a connection without messages wakes up to check its timers.
+const TICK_INTERVAL: Duration = Duration::from_secs(5);
+/// Peers drop connections that stay silent for two minutes.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+/// Pause after a failed `accept`.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where verified pieces are read back from to serve them.
+pub trait PieceStore: Send + Sync {
+    /// The pieces that may be served. Pieces may be added over time, e.g. while downloading.
+    fn have(&self) -> Bitfield;
+
+    /// Reads `length` bytes at `begin` in piece `index`.
+    fn read(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>>;
+}
+
+/// A single-file torrent's data on disk. Only the pieces that matched their hash when the
+/// file was opened are served.
+pub struct FileStore {
+    file: Mutex<File>,
+    piece_length: u64,
+    have: Bitfield,
+}
+
+impl FileStore {
+    /// Opens the data at `path` and checks every piece against `info`.
+    pub fn open(info: &TorrentFileInfo, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut have = Bitfield::new(info.piece_count());
+        let mut piece = Vec::with_capacity(info.piece_length as usize);
+        for index in 0..info.piece_count() as u32 {
+            piece.clear();
+            let size = info.piece_size(index) as u64;
+            (&mut file).take(size).read_to_end(&mut piece)?;
+            if piece.len() as u64 == size && info.verify_piece(index, &piece) {
+                have.set(index as usize);
+            }
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            piece_length: info.piece_length,
+            have,
+        })
+    }
+}
+
+impl PieceStore for FileStore {
+    fn have(&self) -> Bitfield {
+        self.have.clone()
+    }
+
+    fn read(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        if !self.have.has(index as usize) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("piece {index} is not verified"),
+            ));
+        }
+        let mut block = vec![0; length as usize];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start(
+            index as u64 * self.piece_length + begin as u64,
+        ))?;
+        file.read_exact(&mut block)?;
+        Ok(block)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SeedConfig {
+    /// Most inbound connections at once; further ones are closed right away. Every
+    /// connection is unchoked, so this also bounds how many peers we upload to.
+    pub max_peers: usize,
+    /// Largest block served. Clients ask for 16 KiB; larger requests are refused.
+    pub max_request_len: u32,
+    /// Requests queued per peer, as advertised in `reqq`; further ones are refused.
+    pub max_queued_requests: usize,
+    /// A peer that sends nothing for this long is disconnected.
+    pub peer_timeout: Duration,
+}
+
+impl Default for SeedConfig {
+    fn default() -> Self {
+        Self {
+            max_peers: 50,
+            max_request_len: BLOCK_SIZE,
+            max_queued_requests: DEFAULT_REQQ as usize,
+            peer_timeout: Duration::from_secs(180),
+        }
+    }
+}
+
+/// A torrent open for seeding.
+#[derive(Clone)]
+struct SeedTorrent {
+    info: TorrentFileInfo,
+    store: Arc<dyn PieceStore>,
+    stats: Arc<TransferStats>,
+}
+
+struct Shared {
+    torrents: Mutex<HashMap<[u8; 20], SeedTorrent>>,
+    peer_id: PeerId,
+    port: u16,
+    config: SeedConfig,
+    cancel: CancellationToken,
+}
+
+/// Accepts inbound peer connections and serves the torrents added to it. The incoming
+/// handshake names the torrent; connections for any other are refused. Each peer is sent
+/// our pieces, unchoked, and answered in order, with cancelled requests dropped from its
+/// queue.
+pub struct Seeder {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+}
+
+impl Seeder {
+    pub async fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let port = listener.local_addr()?.port();
+        Ok(Self {
+            listener,
+            shared: Arc::new(Shared {
+                torrents: Mutex::new(HashMap::new()),
+                peer_id: PeerId::session(),
+                port,
+                config: SeedConfig::default(),
+                cancel: CancellationToken::new(),
+            }),
+        })
+    }
+
+    pub fn with_config(mut self, config: SeedConfig) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("configured before use")
+            .config = config;
+        self
+    }
+
+    /// Answers handshakes with `peer_id` instead of this session's.
+    pub fn with_peer_id(mut self, peer_id: PeerId) -> Self {
+        Arc::get_mut(&mut self.shared)
+            .expect("configured before use")
+            .peer_id = peer_id;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Starts serving the pieces `store` has of `info`, counting uploads in `stats`. Pieces
+    /// the store gains later are announced to connected peers with `have`.
+    pub fn add(
+        &self,
+        info: TorrentFileInfo,
+        store: Arc<dyn PieceStore>,
+        stats: Arc<TransferStats>,
+    ) {
+        let torrent = SeedTorrent { info, store, stats };
+        let info_hash = torrent.info.info_hash();
+        self.shared
+            .torrents
+            .lock()
+            .unwrap()
+            .insert(info_hash, torrent);
+    }
+
+    /// Stops accepting connections for the torrent; open ones are left to finish.
+    pub fn remove(&self, info_hash: [u8; 20]) {
+        self.shared.torrents.lock().unwrap().remove(&info_hash);
+    }
+
+    /// Makes `run` close every connection and return.
+    pub fn shutdown(&self) {
+        self.shared.cancel.cancel();
+    }
+
+    /// Accepts connections until `shutdown`.
+    pub async fn run(&self) -> io::Result<()> {
+        let mut peers = JoinSet::new();
+        loop {
+            let stream = tokio::select! {
+                _ = self.shared.cancel.cancelled() => break,
+                Some(_) = peers.join_next(), if !peers.is_empty() => continue,
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    // Running out of file descriptors and the like pass; back off meanwhile.
+                    Err(_) => {
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                },
+            };
+            if peers.len() < self.shared.config.max_peers {
+                let shared = self.shared.clone();
+                peers.spawn(async move {
+                    // Errors only end this connection.
+                    let _ = serve_peer(&shared, stream).await;
+                });
+            }
+        }
+        while peers.join_next().await.is_some() {}
+        Ok(())
+    }
+}
+
+/// A request queued for upload: `(index, begin, length)`.
+type Request = (u32, u32, u32);
+
+async fn serve_peer(shared: &Shared, stream: TcpStream) -> Result<(), SessionError> {
+    let mut torrent = None;
+    let accept = |info_hash| {
+        torrent = shared.torrents.lock().unwrap().get(&info_hash).cloned();
+        torrent.as_ref()?;
+        let local = Handshake::new(info_hash, shared.peer_id).with_extensions(Extensions {
+            extension_protocol: true,
+            fast: true,
+            ..Extensions::default()
+        });
+        Some(local)
+    };
+    let mut connection =
+        PeerConnection::accept(stream, accept, shared.cancel.child_token()).await?;
+    let torrent = torrent.expect("accepted a known torrent");
+    let registry = ExtensionRegistry::new().with_listen_port(shared.port);
+    connection.start_extensions(registry).await?;
+    let mut have = torrent.store.have();
+    let mut session = PeerSession::start(connection, &have).await?;
+    session.set_choking(false).await?;
+
+    let config = &shared.config;
+    let mut queue: VecDeque<Request> = VecDeque::new();
+    let mut last_received = Instant::now();
+    let mut last_sent = Instant::now();
+    loop {
+        // Incoming messages go first, so a cancel takes effect before its block is sent.
+        let message = tokio::select! {
+            biased;
+            message = session.recv() => message?,
+            _ = std::future::ready(()), if !queue.is_empty() => {
+                let (index, begin, length) = queue.pop_front().unwrap();
+                let store = torrent.store.clone();
+                let block = tokio::task::spawn_blocking(move || store.read(index, begin, length))
+                    .await
+                    .expect("piece read panicked")
+                    .map_err(ConnectionError::from)?;
+                session.send(PeerMessage::Piece { index, begin, block }).await?;
+                torrent.stats.add_uploaded(length as u64);
+                last_sent = Instant::now();
+                continue;
+            }
+            _ = tokio::time::sleep(TICK_INTERVAL) => {
+                if last_received.elapsed() >= config.peer_timeout {
+                    return Ok(());
+                }
+                if last_sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+                    session.send(PeerMessage::KeepAlive).await?;
+                    last_sent = Instant::now();
+                }
+                session.connection_mut().tick_extensions().await?;
+                let now = torrent.store.have();
+                for index in now.iter().filter(|index| !have.has(*index)) {
+                    session.send(PeerMessage::Have { index: index as u32 }).await?;
+                    last_sent = Instant::now();
+                }
+                have = now;
+                continue;
+            }
+        };
+        last_received = Instant::now();
+        let fast = session.state().fast;
+        match message {
+            PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } => {
+                let valid = have.has(index as usize)
+                    && length > 0
+                    && length <= config.max_request_len
+                    && begin as u64 + length as u64 <= torrent.info.piece_size(index) as u64;
+                if valid && queue.len() < config.max_queued_requests {
+                    queue.push_back((index, begin, length));
+                } else if fast {
+                    session
+                        .send(PeerMessage::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        })
+                        .await?;
+                }
+            }
+            PeerMessage::Cancel {
+                index,
+                begin,
+                length,
+            } => {
+                let before = queue.len();
+                queue.retain(|request| *request != (index, begin, length));
+                // With the fast extension every request gets an answer, cancelled or not.
+                if fast && queue.len() < before {
+                    session
+                        .send(PeerMessage::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        })
+                        .await?;
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::bitfield::Bitfield;
+use crate::connection::{ConnectionError, PeerConnection};
+use crate::message::{MessageError, MessageId, PeerMessage};
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_SIZE: u32 = 1 << 14;
+/// Requests kept outstanding before anything is known about the peer's speed.
+pub const DEFAULT_QUEUE_DEPTH: usize = 5;
+/// Most requests kept outstanding, however fast the peer.
+pub const MAX_QUEUE_DEPTH: usize = 250;
+/// The queue never shrinks below this, so a slow sample cannot stall a peer.
+const MIN_QUEUE_DEPTH: usize = 2;
+/// Shortest span throughput is measured over.
+const MIN_RATE_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error(transparent)]
+    Connection(#[from] ConnectionError),
+    #[error(transparent)]
+    Protocol(#[from] MessageError),
+    #[error("peer closed the connection")]
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// Requests kept outstanding until the first throughput sample.
+    pub initial_depth: usize,
+    /// Most requests kept outstanding.
+    pub max_depth: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            initial_depth: DEFAULT_QUEUE_DEPTH,
+            max_depth: MAX_QUEUE_DEPTH,
+        }
+    }
+}
+
+/// How many block requests to keep outstanding with one peer. The depth follows the
+/// bandwidth-delay product: the measured throughput times the smoothed time a request takes
+/// to be answered, in blocks. It is sized to twice that, so a peer that could go faster gets
+/// the chance to show it, and never exceeds the `reqq` the peer advertised.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    config: PipelineConfig,
+    depth: usize,
+    /// The peer's `reqq`, or `max_depth` while unknown.
+    limit: usize,
+    in_flight: usize,
+    rtt: Option<Duration>,
+    /// Bytes per second, smoothed over windows of at least one round trip.
+    rate: f64,
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        let max_depth = config.max_depth.max(1);
+        Self {
+            config: PipelineConfig {
+                initial_depth: config.initial_depth.clamp(1, max_depth),
+                max_depth,
+            },
+            depth: config.initial_depth.clamp(1, max_depth),
+            limit: max_depth,
+            in_flight: 0,
+            rtt: None,
+            rate: 0.0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Requests to keep outstanding right now.
+    pub fn depth(&self) -> usize {
+        self.depth.min(self.limit)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Measured throughput in bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.in_flight < self.depth()
+    }
+
+    /// Caps the depth at the `reqq` from the peer's extended handshake.
+    pub fn set_peer_limit(&mut self, reqq: usize) {
+        self.limit = reqq.clamp(1, self.config.max_depth);
+    }
+
+    pub fn sent(&mut self) {
+        // Time spent with nothing outstanding says nothing about the peer.
+        if self.in_flight == 0 {
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+        self.in_flight += 1;
+    }
+
+    /// Forgets `count` requests the peer will not answer.
+    pub fn cancelled(&mut self, count: usize) {
+        self.in_flight = self.in_flight.saturating_sub(count);
+    }
+
+    /// Records a block of `bytes` that arrived `latency` after it was requested.
+    pub fn received(&mut self, latency: Duration, bytes: usize) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        let rtt = match self.rtt {
+            Some(rtt) => rtt.mul_f64(0.875) + latency.mul_f64(0.125),
+            None => latency,
+        };
+        self.rtt = Some(rtt);
+        self.window_bytes += bytes as u64;
+        let elapsed = self.window_start.elapsed();
+        if elapsed < rtt.max(MIN_RATE_WINDOW) {
+            return;
+        }
+        let sample = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            sample
+        } else {
+            0.75 * self.rate + 0.25 * sample
+        };
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        let bdp = self.rate * rtt.as_secs_f64() / BLOCK_SIZE as f64;
+        // `max_depth` wins over the floor, as a configured depth of one must stay one.
+        self.depth = ((2.0 * bdp).ceil() as usize)
+            .max(MIN_QUEUE_DEPTH)
+            .min(self.config.max_depth);
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new(PipelineConfig::default())
+    }
+}
+
+/// Everything the wire protocol tells about one connection: the four choke and interest
+/// flags and the peer's pieces. Any message may arrive at any time; each one just updates
+/// the state, and code waiting on it rechecks what it needs.
+#[derive(Debug, Clone)]
+pub struct SessionState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    /// Whether both handshakes enabled the fast extension (BEP 6).
+    pub fast: bool,
+    /// The peer's pieces, as far as it told us.
+    pub pieces: Bitfield,
+    /// Pieces we may request while choked.
+    pub allowed_fast: HashSet<u32>,
+    /// Pieces the peer suggested, oldest first.
+    pub suggested: VecDeque<u32>,
+    /// Outstanding block requests and how many to allow.
+    pub pipeline: Pipeline,
+}
+
+impl SessionState {
+    pub fn new(piece_count: usize, fast: bool) -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            fast,
+            pieces: Bitfield::new(piece_count),
+            allowed_fast: HashSet::new(),
+            suggested: VecDeque::new(),
+            pipeline: Pipeline::default(),
+        }
+    }
+
+    /// Whether the peer would serve a request for a block of piece `index` right now.
+    pub fn can_request(&self, index: u32) -> bool {
+        self.pieces.has(index as usize)
+            && (!self.peer_choking || self.allowed_fast.contains(&index))
+    }
+
+    /// Updates the state for a message from the peer. Fast extension messages are a protocol
+    /// error unless it was negotiated.
+    pub fn received(&mut self, message: &PeerMessage) -> Result<(), MessageError> {
+        if message.is_fast() && !self.fast {
+            return Err(MessageError::NotNegotiated(message.id().unwrap()));
+        }
+        let piece_count = self.pieces.len();
+        let check = |id, index: u32| {
+            if (index as usize) < piece_count {
+                Ok(index)
+            } else {
+                Err(MessageError::Malformed {
+                    id,
+                    reason: "piece index out of range",
+                })
+            }
+        };
+        match message {
+            PeerMessage::Choke => self.peer_choking = true,
+            PeerMessage::Unchoke => self.peer_choking = false,
+            PeerMessage::Interested => self.peer_interested = true,
+            PeerMessage::NotInterested => self.peer_interested = false,
+            PeerMessage::Have { index } => {
+                let index = check(MessageId::Have, *index)?;
+                self.pieces.set(index as usize);
+            }
+            PeerMessage::Bitfield(bytes) => {
+                self.pieces =
+                    Bitfield::from_bytes(bytes, piece_count).ok_or(MessageError::Malformed {
+                        id: MessageId::BitField,
+                        reason: "wrong length or spare bits set",
+                    })?;
+            }
+            PeerMessage::HaveAll => self.pieces = Bitfield::full(piece_count),
+            PeerMessage::HaveNone => self.pieces = Bitfield::new(piece_count),
+            PeerMessage::AllowedFast { index } => {
+                self.allowed_fast
+                    .insert(check(MessageId::AllowedFast, *index)?);
+            }
+            PeerMessage::SuggestPiece { index } => {
+                let index = check(MessageId::SuggestPiece, *index)?;
+                if !self.suggested.contains(&index) {
+                    self.suggested.push_back(index);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Updates our side of the flags for a message we send.
+    pub fn sent(&mut self, message: &PeerMessage) {
+        match message {
+            PeerMessage::Choke => self.am_choking = true,
+            PeerMessage::Unchoke => self.am_choking = false,
+            PeerMessage::Interested => self.am_interested = true,
+            PeerMessage::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+}
+
+/// A peer connection driven through `SessionState`: every message sent or received updates
+/// the flags, and downloads wait for whatever state they need instead of a fixed sequence.
+pub struct PeerSession {
+    connection: PeerConnection,
+    state: SessionState,
+}
+
+impl PeerSession {
+    /// Takes over an established connection and tells the peer which of the `have.len()`
+    /// pieces we have.
+    pub async fn start(connection: PeerConnection, have: &Bitfield) -> Result<Self, SessionError> {
+        let fast = connection.handshake().extensions.fast;
+        let mut session = Self {
+            connection,
+            state: SessionState::new(have.len(), fast),
+        };
+        let announcement = match (fast, have.count()) {
+            (true, 0) => Some(PeerMessage::HaveNone),
+            (true, count) if count == have.len() => Some(PeerMessage::HaveAll),
+            (_, 0) => None,
+            _ => Some(PeerMessage::Bitfield(have.as_bytes().to_vec())),
+        };
+        if let Some(message) = announcement {
+            session.send(message).await?;
+        }
+        Ok(session)
+    }
+
+    /// Replaces the default request pipeline.
+    pub fn with_pipeline(mut self, config: PipelineConfig) -> Self {
+        self.state.pipeline = Pipeline::new(config);
+        self
+    }
+
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    /// For the engine, which drives requests for several pieces at a time.
+    pub fn state_mut(&mut self) -> &mut SessionState {
+        &mut self.state
+    }
+
+    pub fn connection(&self) -> &PeerConnection {
+        &self.connection
+    }
+
+    pub fn connection_mut(&mut self) -> &mut PeerConnection {
+        &mut self.connection
+    }
+
+    pub fn into_connection(self) -> PeerConnection {
+        self.connection
+    }
+
+    pub async fn send(&mut self, message: PeerMessage) -> Result<(), SessionError> {
+        self.state.sent(&message);
+        Ok(self.connection.send(message).await?)
+    }
+
+    /// The next message, after it was applied to the state. Requests that arrive while we
+    /// choke the peer are never served: with the fast extension they are rejected, otherwise
+    /// dropped.
+    pub async fn recv(&mut self) -> Result<PeerMessage, SessionError> {
+        loop {
+            let message = self.connection.recv().await?.ok_or(SessionError::Closed)?;
+            self.state.received(&message)?;
+            self.apply_peer_limit();
+            if let PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } = message
+            {
+                if self.state.am_choking {
+                    if self.state.fast {
+                        let reject = PeerMessage::RejectRequest {
+                            index,
+                            begin,
+                            length,
+                        };
+                        self.send(reject).await?;
+                    }
+                    continue;
+                }
+            }
+            return Ok(message);
+        }
+    }
+
+    /// Caps the pipeline at the peer's `reqq` once its extended handshake arrived.
+    fn apply_peer_limit(&mut self) {
+        let extensions = self.connection.extensions().lock().unwrap();
+        if let Some(reqq) = extensions.remote().and_then(|remote| remote.reqq) {
+            self.state.pipeline.set_peer_limit(reqq as usize);
+        }
+    }
+
+    pub async fn set_interested(&mut self, interested: bool) -> Result<(), SessionError> {
+        if self.state.am_interested == interested {
+            return Ok(());
+        }
+        let message = if interested {
+            PeerMessage::Interested
+        } else {
+            PeerMessage::NotInterested
+        };
+        self.send(message).await
+    }
+
+    pub async fn set_choking(&mut self, choking: bool) -> Result<(), SessionError> {
+        if self.state.am_choking == choking {
+            return Ok(());
+        }
+        let message = if choking {
+            PeerMessage::Choke
+        } else {
+            PeerMessage::Unchoke
+        };
+        self.send(message).await
+    }
+}
@@ -1,28 +1,112 @@
+use crate::announcer::{AnnounceConfig, Announcer};
 use crate::bencode;
-use crate::bencode::decode_bencoded_value;
-use crate::handshake::tcp_handshake;
-use crate::message::Message;
-use crate::peer::{download_piece, send_message, wait_message};
-use crate::{message::MessageId, peer::download_all};
-use bytes::Bytes;
-use reqwest::Url;
+use crate::bencode::BencodeValue;
+use crate::dht::{Dht, DhtConfig};
+use crate::engine::{Engine, EngineError};
+use crate::handshake::{tcp_handshake, HandshakeError, HandshakeResult};
+use crate::message::MessageError;
+use crate::peer_id::PeerId;
+use crate::pool::{CandidatePool, PeerSource};
+use crate::seeder::{FileStore, PieceStore, Seeder, DEFAULT_LISTEN_PORT};
+use crate::tracker::{tracker_get, Peer, Tracker, TrackerError, TransferStats};
 use serde::{Deserialize, Serialize};
 use sha1::Digest;
 use sha1::Sha1;
 use std::fs;
-use std::iter::Peekable;
-use std::net::Ipv4Addr;
-use std::net::TcpStream;
-use std::slice::Iter;
-use std::str::from_utf8;
-
-#[derive(Clone)]
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How often a download asks the DHT for more peers.
+const DHT_LOOKUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Clone, Deserialize)]
 pub struct TorrentFile {
+    /// Empty for trackerless torrents, which find peers through the DHT.
+    #[serde(default)]
     pub announce: String,
+    /// DHT nodes to bootstrap from, as `(host, port)` (BEP 5).
+    #[serde(default)]
+    pub nodes: Vec<(String, u16)>,
     pub info: TorrentFileInfo,
 }
 
+#[derive(Debug, Error)]
+pub enum MetainfoError {
+    #[error("failed to read torrent file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{field}: missing")]
+    Missing { field: String },
+    #[error("{field}: {reason}")]
+    Invalid { field: String, reason: String },
+    #[error("malformed bencode: {0}")]
+    Malformed(String),
+}
+
+/// Why fetching pieces from a peer failed.
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error("handshake failed: {0}")]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Message(#[from] MessageError),
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<serde_path_to_error::Error<bencode::Error>> for MetainfoError {
+    fn from(err: serde_path_to_error::Error<bencode::Error>) -> Self {
+        let path = err.path().to_string();
+        match err.into_inner() {
+            bencode::Error::MissingField(name) => MetainfoError::Missing {
+                field: match path.as_str() {
+                    "." => name.to_owned(),
+                    parent => format!("{}.{}", parent, name),
+                },
+            },
+            bencode::Error::Message(reason) => MetainfoError::Invalid {
+                field: path,
+                reason,
+            },
+        }
+    }
+}
+
 impl TorrentFile {
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, MetainfoError> {
+        let contents = fs::read(path)?;
+        Self::from_bytes(&contents)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let value = BencodeValue::from_bytes(bytes)
+            .map_err(|err| MetainfoError::Malformed(err.to_string()))?;
+        let torrent_file: TorrentFile = serde_path_to_error::deserialize(value)?;
+        if torrent_file.info.pieces.len() % 20 != 0 {
+            return Err(MetainfoError::Invalid {
+                field: "info.pieces".to_owned(),
+                reason: "length is not a multiple of 20".to_owned(),
+            });
+        }
+        if torrent_file.info.piece_length == 0 {
+            return Err(MetainfoError::Invalid {
+                field: "info.piece length".to_owned(),
+                reason: "must be positive".to_owned(),
+            });
+        }
+        Ok(torrent_file)
+    }
+
     pub fn show_info(&self) {
         let hashed_pieces = self.info.hash_pieces();
         println!("Tracker Url: {}", self.announce);
@@ -36,67 +120,177 @@ impl TorrentFile {
         }
     }
 
-    pub fn peers(&self) -> Vec<Peer> {
-        let tracker = tracker_get(self.clone()).unwrap();
-        let bencode_tracker = decode_bencoded_value(&mut tracker.iter().peekable());
-        let parsed_response = parse_response(bencode_tracker.unwrap());
-        let peers = parsed_response.peers;
-        peers
+    pub fn tracker(&self, stats: Arc<TransferStats>) -> Tracker {
+        self.tracker_on_port(DEFAULT_LISTEN_PORT, stats)
     }
-    pub fn perform_handshake(&self) -> TcpStream {
-        let tracker = tracker_get(self.clone()).unwrap();
-        let bencode_tracker = decode_bencoded_value(&mut tracker.iter().peekable());
-        let parsed_response = parse_response(bencode_tracker.unwrap());
-        let peer = parsed_response.peers[0].to_string();
-        tcp_handshake(&peer, self.info.hash_nohex())
+
+    /// A tracker client announcing that we listen on `port`.
+    pub fn tracker_on_port(&self, port: u16, stats: Arc<TransferStats>) -> Tracker {
+        Tracker::new(
+            self.announce.clone(),
+            self.info.info_hash(),
+            PeerId::session(),
+            port,
+            stats,
+        )
     }
 
-    pub fn perform_peer_message(&self, stream: &mut TcpStream) -> Message {
-        wait_message(stream, MessageId::BitField).unwrap();
-        let interested_message = Message {
-            message_id: MessageId::Interested,
-            payload: Vec::new(),
-        };
-        send_message(stream, interested_message);
-        wait_message(stream, MessageId::Unchoke).unwrap()
+    pub fn peers(&self) -> Result<Vec<Peer>, TrackerError> {
+        Ok(tracker_get(self)?.peers)
     }
 
-    pub fn download_piece(&self, piece_index: u32, output_file_path: &String) {
-        let mut stream = self.perform_handshake();
-        self.perform_peer_message(&mut stream);
-        let piece = download_piece(self.clone(), &mut stream, piece_index).unwrap();
-        let _ = fs::write(&output_file_path, piece);
+    /// Handshakes with the first peer the tracker names.
+    pub fn perform_handshake(&self) -> Result<(TcpStream, HandshakeResult), DownloadError> {
+        let peer = first_peer(self.peers()?)?;
+        Ok(tcp_handshake(
+            &peer,
+            self.info.info_hash(),
+            PeerId::session(),
+        )?)
     }
 
-    pub fn download(&self, output_file_path: &String) {
-        let mut stream = self.perform_handshake();
-        self.perform_peer_message(&mut stream);
-        let piece = download_all(self.clone(), &mut stream);
-        let _ = fs::write(output_file_path, piece);
+    pub fn download_piece(
+        &self,
+        piece_index: u32,
+        output_file_path: &String,
+    ) -> Result<(), DownloadError> {
+        let stats = Arc::new(TransferStats::new(self.info.length));
+        let engine = Engine::new(self.info.clone(), stats.clone()).with_pieces([piece_index]);
+        let piece = self.run_engine(engine, stats)?;
+        fs::write(output_file_path, piece)?;
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct TorrentResponse {
-    pub interval: u64,
-    pub peers: Vec<Peer>,
-    pub complete: u64,
-    pub incomplete: u64,
-    pub min_interval: u64,
-}
+    pub fn download(&self, output_file_path: &String) -> Result<(), DownloadError> {
+        let stats = Arc::new(TransferStats::new(self.info.length));
+        let engine = Engine::new(self.info.clone(), stats.clone());
+        let data = self.run_engine(engine, stats)?;
+        fs::write(output_file_path, data)?;
+        Ok(())
+    }
 
-#[derive(Debug)]
-pub struct Peer {
-    pub ip_addr: Ipv4Addr,
-    pub port: u16,
-}
+    /// Serves the verified pieces of the data at `data_path` to peers connecting on
+    /// `listen`, announced to the tracker, until interrupted.
+    pub fn seed(&self, data_path: &str, listen: &str) -> Result<(), DownloadError> {
+        let store = FileStore::open(&self.info, data_path)?;
+        let have = store.have();
+        let left = (0..self.info.piece_count() as u32)
+            .filter(|index| !have.has(*index as usize))
+            .map(|index| self.info.piece_size(index) as u64)
+            .sum();
+        let stats = Arc::new(TransferStats::new(left));
+        let runtime = tokio::runtime::Runtime::new()?;
+        let seeder = runtime.block_on(Seeder::bind(listen))?;
+        let addr = seeder.local_addr()?;
+        println!(
+            "Seeding {} of {} pieces on {}",
+            have.count(),
+            have.len(),
+            addr
+        );
+        seeder.add(self.info.clone(), Arc::new(store), stats.clone());
+        let (announcer, _) = Announcer::spawn(
+            self.tracker_on_port(addr.port(), stats),
+            AnnounceConfig::default(),
+        );
+        let result = runtime.block_on(async {
+            tokio::select! {
+                result = seeder.run() => result,
+                result = tokio::signal::ctrl_c() => result,
+            }
+        });
+        announcer.shutdown();
+        Ok(result?)
+    }
+
+    /// Runs `engine` on its own runtime, feeding it the peers an `Announcer` and, for public
+    /// torrents, the DHT find, and reporting back how many it is connected to. The pieces
+    /// verified so far are served to peers connecting on the announced port meanwhile.
+    fn run_engine(
+        &self,
+        engine: Engine,
+        stats: Arc<TransferStats>,
+    ) -> Result<Vec<u8>, DownloadError> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        // Another client may hold the usual port; any port works as long as it is announced.
+        let seeder = match runtime.block_on(Seeder::bind(("0.0.0.0", DEFAULT_LISTEN_PORT))) {
+            Ok(seeder) => seeder,
+            Err(_) => runtime.block_on(Seeder::bind(("0.0.0.0", 0)))?,
+        };
+        seeder.add(self.info.clone(), engine.verified(), stats.clone());
+        let (announcer, peers) = Announcer::spawn(
+            self.tracker_on_port(seeder.local_addr()?.port(), stats),
+            AnnounceConfig::default(),
+        );
+        let pool = CandidatePool::new();
+        let done = AtomicBool::new(false);
+        let result = thread::scope(|scope| {
+            let (pool, done, announcer) = (&pool, &done, &announcer);
+            if !self.info.is_private() {
+                scope.spawn(move || self.find_dht_peers(pool, done));
+            }
+            scope.spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    match peers.recv_timeout(Duration::from_secs(1)) {
+                        Ok(peers) => {
+                            pool.add(peers.iter().map(Peer::socket_addr), PeerSource::Tracker);
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            announcer.set_peer_count(pool.connected_peers().len())
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            });
+            let result = runtime.block_on(async {
+                let download = async {
+                    let result = engine.run(pool.clone()).await;
+                    seeder.shutdown();
+                    result
+                };
+                tokio::join!(download, seeder.run()).0
+            });
+            done.store(true, Ordering::Relaxed);
+            result
+        });
+        announcer.shutdown();
+        Ok(result?)
+    }
 
-impl Peer {
-    pub fn to_string(&self) -> String {
-        format!("{}:{}", self.ip_addr, self.port)
+    /// Joins the DHT through the torrent's `nodes`, or the default routers if it has none,
+    /// and adds the peers it knows for the torrent to `pool` until `done` is set. A node
+    /// that cannot start, e.g. under a proxy-only configuration, leaves peers to the tracker.
+    fn find_dht_peers(&self, pool: &CandidatePool, done: &AtomicBool) {
+        let mut config = DhtConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            ..DhtConfig::default()
+        };
+        if !self.nodes.is_empty() {
+            config.routers.clear();
+        }
+        let Ok(dht) = Dht::start(config) else {
+            return;
+        };
+        dht.bootstrap(&self.nodes);
+        let info_hash = self.info.info_hash();
+        while !done.load(Ordering::Relaxed) {
+            let peers = dht.lookup_peers(info_hash);
+            pool.add(peers.iter().map(Peer::socket_addr), PeerSource::Dht);
+            let next_lookup = Instant::now() + DHT_LOOKUP_INTERVAL;
+            while !done.load(Ordering::Relaxed) && Instant::now() < next_lookup {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
     }
 }
 
+fn first_peer(peers: Vec<Peer>) -> Result<String, TrackerError> {
+    peers
+        .first()
+        .map(Peer::to_string)
+        .ok_or_else(|| TrackerError::Malformed("no peers in response".to_owned()))
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TorrentFileInfo {
     pub name: String,
@@ -105,9 +299,17 @@ pub struct TorrentFileInfo {
     #[serde(with = "serde_bytes")]
     pub pieces: Vec<u8>,
     pub length: u64,
+    /// Set to 1 by private trackers, which forbid finding peers anywhere but through them
+    /// (BEP 27). Kept so the info hash covers it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<i64>,
 }
 
 impl TorrentFileInfo {
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
     pub fn hash(&self) -> String {
         let bencoded_info_dictionary = serde_bencode::to_bytes(&self).unwrap();
         let mut hasher = Sha1::new();
@@ -124,189 +326,36 @@ impl TorrentFileInfo {
         hash.to_vec()
     }
 
-    pub fn hash_pieces(&self) -> Vec<String> {
-        let mut hashed_pieces = Vec::new();
-        for piece in self.pieces.chunks(20) {
-            hashed_pieces.push(hex::encode(piece));
-        }
-        hashed_pieces
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.hash_nohex()
+            .try_into()
+            .expect("SHA-1 digests are 20 bytes")
     }
-}
-
-pub fn parse_torrent_file(chars: &mut Peekable<Iter<u8>>) -> TorrentFile {
-    let decoded_value = decode_bencoded_value(chars);
-    let mut announce: Option<String> = None;
-    let mut length: Option<u64> = None;
-    let mut name: Option<String> = None;
-    let mut piece_length: Option<u64> = None;
-    let mut pieces: Option<Vec<u8>> = None;
-
-    if let bencode::BencodeValue::Dictionary(dict) = decoded_value.unwrap() {
-        if let bencode::BencodeValue::ByteString(s) = dict.get("announce").unwrap() {
-            announce = Some(from_utf8(s).unwrap().to_owned());
-        };
-
-        if let bencode::BencodeValue::Dictionary(info) = dict.get("info").unwrap() {
-            if let bencode::BencodeValue::Integer(n) = info.get("length").unwrap() {
-                length = Some(n.to_owned() as u64);
-            }
 
-            if let bencode::BencodeValue::ByteString(s) = info.get("name").unwrap() {
-                name = Some(from_utf8(&s).unwrap().to_owned());
-            }
-
-            if let bencode::BencodeValue::Integer(n) = info.get("piece length").unwrap() {
-                piece_length = Some(n.to_owned() as u64);
-            }
-
-            if let bencode::BencodeValue::ByteString(s) = info.get("pieces").unwrap() {
-                pieces = Some(s.to_vec());
-            }
-        }
-    }
-    TorrentFile {
-        announce: announce.unwrap(),
-        info: TorrentFileInfo {
-            length: length.unwrap(),
-            name: name.unwrap(),
-            piece_length: piece_length.unwrap(),
-            pieces: pieces.unwrap(),
-        },
-    }
-}
-
-pub struct Tracker {
-    url: String,
-    left: String,
-    info_hash: String,
-    peer_id: String,
-    port: u16,
-    uploaded: u64,
-    downloaded: u64,
-    compact: bool,
-}
-
-impl Tracker {
-    pub fn new(
-        url: String,
-        left: String,
-        info_hash: String,
-        peer_id: String,
-        port: u16,
-        uploaded: u64,
-        downloaded: u64,
-        compact: bool,
-    ) -> Self {
-        Self {
-            url,
-            left,
-            info_hash,
-            peer_id,
-            port,
-            uploaded,
-            downloaded,
-            compact,
-        }
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
     }
 
-    pub fn build_url(&self) -> String {
-        // build a url from the Tracker self
-        let mut base_url = self.url.clone();
-        let info_hash = format!("?info_hash={}", &self.info_hash);
-        base_url.push_str(&info_hash);
-        let mut url = Url::parse(&base_url).unwrap();
-        let compact = self.compact as u8;
-        let mut binding = url.query_pairs_mut();
-        let final_url = binding
-            .append_pair("peer_id", &self.peer_id)
-            .append_pair("port", &self.port.to_string())
-            .append_pair("uploaded", &self.uploaded.to_string())
-            .append_pair("downloaded", &self.downloaded.to_string())
-            .append_pair("left", &self.left)
-            .append_pair("compact", &compact.to_string());
-        let final_url = final_url.finish().to_string();
-        println!("{}", &final_url);
-        final_url
+    /// Length of piece `index`; only the last one may be shorter than `piece_length`.
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length;
+        self.piece_length.min(self.length.saturating_sub(start)) as u32
     }
-}
 
-pub fn tracker_get(torrent_file: TorrentFile) -> Result<Bytes, reqwest::Error> {
-    let url = torrent_file.announce;
-    let left = torrent_file.info.length.to_string();
-    let info_hash = hash_encode(&torrent_file.info.hash());
-    let peer_id = "00112233445566778899";
-    let port = 6881;
-    let uploaded = 0;
-    let downloaded = 0;
-    let compact = true;
-    let tracker = Tracker::new(
-        url,
-        left,
-        info_hash,
-        peer_id.to_string(),
-        port,
-        uploaded,
-        downloaded,
-        compact,
-    );
-    let response = reqwest::blocking::get(tracker.build_url())
-        .unwrap()
-        .bytes()
-        .unwrap();
-    println!("{:?}", response);
-
-    // let mut response = binding.as_bytes().iter().peekable();
-    // let response = decode_bencoded_value(&mut response).unwrap();
-
-    Ok(response)
-}
-
-pub fn hash_encode(t: &str) -> String {
-    let bytes = hex::decode(t).unwrap_or_default();
-    let encoded: String = bytes.iter().map(|b| format!("%{:02x}", b)).collect();
-    encoded
-}
-
-pub fn parse_response(bencode: bencode::BencodeValue) -> TorrentResponse {
-    let mut interval: Option<u64> = None;
-    let mut complete: Option<u64> = None;
-    let mut incomplete: Option<u64> = None;
-    let mut min_interval: Option<u64> = None;
-    let mut peers: Option<Vec<Peer>> = None;
-
-    if let bencode::BencodeValue::Dictionary(dict) = bencode {
-        if let bencode::BencodeValue::Integer(n) = dict.get("interval").unwrap() {
-            interval = Some(n.to_owned() as u64);
-        }
-
-        if let bencode::BencodeValue::Integer(n) = dict.get("complete").unwrap() {
-            complete = Some(n.to_owned() as u64);
-        }
-
-        if let bencode::BencodeValue::Integer(n) = dict.get("incomplete").unwrap() {
-            incomplete = Some(n.to_owned() as u64);
-        }
-
-        if let bencode::BencodeValue::Integer(n) = dict.get("min interval").unwrap() {
-            min_interval = Some(n.to_owned() as u64);
+    /// Whether `data` matches the SHA-1 listed for piece `index`.
+    pub fn verify_piece(&self, index: u32, data: &[u8]) -> bool {
+        let start = index as usize * 20;
+        match self.pieces.get(start..start + 20) {
+            Some(expected) => Sha1::digest(data).as_slice() == expected,
+            None => false,
         }
+    }
 
-        if let bencode::BencodeValue::ByteString(p) = dict.get("peers").unwrap() {
-            let mut vec = vec![];
-            for chunk in p.chunks(6) {
-                vec.push(Peer {
-                    ip_addr: Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
-                    port: u16::from_be_bytes([chunk[4], chunk[5]]),
-                })
-            }
-            peers = Some(vec);
+    pub fn hash_pieces(&self) -> Vec<String> {
+        let mut hashed_pieces = Vec::new();
+        for piece in self.pieces.chunks(20) {
+            hashed_pieces.push(hex::encode(piece));
         }
-    }
-    TorrentResponse {
-        interval: interval.unwrap(),
-        min_interval: min_interval.unwrap(),
-        incomplete: incomplete.unwrap(),
-        complete: complete.unwrap(),
-        peers: peers.unwrap(),
+        hashed_pieces
     }
 }
@@ -0,0 +1,614 @@
+use crate::bencode::BencodeValue;
+use crate::peer_id::PeerId;
+use crate::torrent::TorrentFile;
+use crate::tracker_client::{BlockingTrackerClient, TrackerClientConfig};
+use crate::udp_tracker::{UdpAnnounce, UdpTrackerError};
+use crate::utils::random_u64;
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use reqwest::Url;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("tracker refused the request: {0}")]
+    Failure(String),
+    #[error("malformed tracker response: {0}")]
+    Malformed(String),
+    #[error("invalid tracker url: {0}")]
+    Url(String),
+    #[error("unsupported tracker scheme `{0}`")]
+    UnsupportedScheme(String),
+    #[error("tracker does not support scrape")]
+    ScrapeUnsupported,
+    #[error("tracker answered with HTTP status {0}")]
+    Status(u16),
+    #[error("could not start tracker client: {0}")]
+    Client(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+    #[error(transparent)]
+    Udp(UdpTrackerError),
+}
+
+impl From<UdpTrackerError> for TrackerError {
+    fn from(err: UdpTrackerError) -> Self {
+        match err {
+            UdpTrackerError::Tracker(reason) => TrackerError::Failure(reason),
+            err => TrackerError::Udp(err),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TrackerResponse {
+    pub interval: u64,
+    pub min_interval: Option<u64>,
+    pub peers: Vec<Peer>,
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    pub tracker_id: Option<String>,
+    pub warning_message: Option<String>,
+}
+
+impl TrackerResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let bencode = BencodeValue::from_bytes(bytes)
+            .map_err(|err| TrackerError::Malformed(err.to_string()))?;
+        parse_response(bencode)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub ip_addr: IpAddr,
+    pub port: u16,
+    /// Only known when the tracker sent the non-compact dictionary model.
+    pub peer_id: Option<PeerId>,
+}
+
+impl Peer {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            ip_addr: addr.ip(),
+            port: addr.port(),
+            peer_id: None,
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.ip_addr, self.port)
+    }
+
+    /// Parses compact peers: 6 bytes per IPv4 peer, 18 bytes per IPv6 peer (BEP 7).
+    /// A trailing partial entry is ignored.
+    pub fn from_compact(bytes: &[u8], ipv6: bool) -> Vec<Peer> {
+        let width = if ipv6 { 18 } else { 6 };
+        bytes
+            .chunks_exact(width)
+            .map(|chunk| {
+                let (ip, port) = chunk.split_at(width - 2);
+                let ip_addr = if ipv6 {
+                    IpAddr::from(<[u8; 16]>::try_from(ip).unwrap())
+                } else {
+                    IpAddr::from(<[u8; 4]>::try_from(ip).unwrap())
+                };
+                Peer {
+                    ip_addr,
+                    port: u16::from_be_bytes([port[0], port[1]]),
+                    peer_id: None,
+                }
+            })
+            .collect()
+    }
+
+    /// Inverse of `from_compact` for a single peer.
+    pub fn to_compact(&self) -> Vec<u8> {
+        let mut bytes = match self.ip_addr {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        bytes.extend(self.port.to_be_bytes());
+        bytes
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.socket_addr())
+    }
+}
+
+/// Swarm statistics for a single info hash, as returned by a scrape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ScrapeStats {
+    pub complete: u64,
+    pub downloaded: u64,
+    pub incomplete: u64,
+}
+
+/// Byte counters reported to trackers, shared between the transfer code and the `Tracker`.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+    /// Bytes received for blocks we already had, kept to tune endgame duplicates.
+    redundant: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        Self {
+            left: AtomicU64::new(left),
+            ..Self::default()
+        }
+    }
+
+    pub fn add_uploaded(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_downloaded(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_redundant(&self, bytes: u64) {
+        self.redundant.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Records that `bytes` of the torrent are now complete and no longer count as left.
+    pub fn piece_completed(&self, bytes: u64) {
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn redundant(&self) -> u64 {
+        self.redundant.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    /// Value of the HTTP `event` parameter, which is omitted for regular announces.
+    pub fn as_str(&self) -> Option<&'static str> {
+        match self {
+            AnnounceEvent::None => None,
+            AnnounceEvent::Started => Some("started"),
+            AnnounceEvent::Completed => Some("completed"),
+            AnnounceEvent::Stopped => Some("stopped"),
+        }
+    }
+
+    /// Parses the HTTP `event` parameter; unknown values count as a regular announce.
+    pub fn from_name(event: &str) -> Self {
+        match event {
+            "started" => AnnounceEvent::Started,
+            "completed" => AnnounceEvent::Completed,
+            "stopped" => AnnounceEvent::Stopped,
+            _ => AnnounceEvent::None,
+        }
+    }
+
+    pub fn udp_code(&self) -> u32 {
+        match self {
+            AnnounceEvent::None => 0,
+            AnnounceEvent::Completed => 1,
+            AnnounceEvent::Started => 2,
+            AnnounceEvent::Stopped => 3,
+        }
+    }
+
+    pub fn from_udp_code(code: u32) -> Self {
+        match code {
+            1 => AnnounceEvent::Completed,
+            2 => AnnounceEvent::Started,
+            3 => AnnounceEvent::Stopped,
+            _ => AnnounceEvent::None,
+        }
+    }
+}
+
+/// Everything an announce tells the tracker, whichever protocol it is sent over.
+#[derive(Debug, Clone)]
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub compact: bool,
+    pub numwant: Option<u32>,
+    pub key: u32,
+    /// Address to hand out to other peers instead of the one the announce came from.
+    pub ip: Option<IpAddr>,
+    pub tracker_id: Option<String>,
+}
+
+impl AnnounceRequest {
+    /// The announce URL with this request's query parameters appended.
+    pub fn http_url(&self, announce: &str) -> Result<String, TrackerError> {
+        // info_hash and peer_id are raw bytes, so they are percent-encoded by hand rather than
+        // through query_pairs_mut, which only accepts UTF-8.
+        let mut base_url = announce.to_owned();
+        base_url.push(if base_url.contains('?') { '&' } else { '?' });
+        base_url.push_str(&format!(
+            "info_hash={}&peer_id={}",
+            percent_encode(&self.info_hash, NON_ALPHANUMERIC),
+            percent_encode(self.peer_id.as_bytes(), NON_ALPHANUMERIC)
+        ));
+        let mut url = Url::parse(&base_url).map_err(|err| TrackerError::Url(err.to_string()))?;
+        let compact = self.compact as u8;
+        let mut query = url.query_pairs_mut();
+        query
+            .append_pair("port", &self.port.to_string())
+            .append_pair("uploaded", &self.uploaded.to_string())
+            .append_pair("downloaded", &self.downloaded.to_string())
+            .append_pair("left", &self.left.to_string())
+            .append_pair("compact", &compact.to_string())
+            .append_pair("key", &format!("{:08x}", self.key));
+        if let Some(event) = self.event.as_str() {
+            query.append_pair("event", event);
+        }
+        if let Some(numwant) = self.numwant {
+            query.append_pair("numwant", &numwant.to_string());
+        }
+        if let Some(ip) = self.ip {
+            query.append_pair("ip", &ip.to_string());
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            query.append_pair("trackerid", tracker_id);
+        }
+        drop(query);
+        Ok(url.to_string())
+    }
+
+    pub fn udp_announce(&self) -> UdpAnnounce {
+        // The UDP protocol can only carry an IPv4 override.
+        let ip = match self.ip {
+            Some(IpAddr::V4(ip)) => u32::from(ip),
+            _ => 0,
+        };
+        UdpAnnounce {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            downloaded: self.downloaded,
+            left: self.left,
+            uploaded: self.uploaded,
+            event: self.event.udp_code(),
+            ip,
+            key: self.key,
+            num_want: self.numwant.map_or(-1, |n| n as i32),
+            port: self.port,
+        }
+    }
+}
+
+/// Announce state of one torrent on one tracker: which events are due, the tracker ID to echo
+/// and the counters to report. Requests go through a blocking `TrackerClient` facade.
+pub struct Tracker {
+    url: String,
+    info_hash: [u8; 20],
+    peer_id: PeerId,
+    port: u16,
+    stats: Arc<TransferStats>,
+    compact: bool,
+    numwant: Option<u32>,
+    key: u32,
+    ip: Option<IpAddr>,
+    tracker_id: Option<String>,
+    started: bool,
+    // Only a download that actually finishes while we are announced reports `completed`.
+    completed: bool,
+    client: Option<BlockingTrackerClient>,
+}
+
+impl Tracker {
+    pub fn new(
+        url: String,
+        info_hash: [u8; 20],
+        peer_id: PeerId,
+        port: u16,
+        stats: Arc<TransferStats>,
+    ) -> Self {
+        let completed = stats.left() == 0;
+        Self {
+            url,
+            info_hash,
+            peer_id,
+            port,
+            stats,
+            compact: true,
+            numwant: None,
+            key: random_u64() as u32,
+            ip: None,
+            tracker_id: None,
+            started: false,
+            completed,
+            client: None,
+        }
+    }
+
+    pub fn with_numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    /// Asks the tracker to hand out `ip` instead of the address the announce came from.
+    pub fn with_ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn with_key(mut self, key: u32) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn with_compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    /// Sends requests through `client` instead of one with the default configuration.
+    pub fn with_client(mut self, client: BlockingTrackerClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// The request an announce with `event` would send right now.
+    pub fn request(&self, event: AnnounceEvent) -> AnnounceRequest {
+        AnnounceRequest {
+            info_hash: self.info_hash,
+            peer_id: self.peer_id,
+            port: self.port,
+            uploaded: self.stats.uploaded(),
+            downloaded: self.stats.downloaded(),
+            left: self.stats.left(),
+            event,
+            compact: self.compact,
+            numwant: self.numwant,
+            key: self.key,
+            ip: self.ip,
+            tracker_id: self.tracker_id.clone(),
+        }
+    }
+
+    pub fn build_url(&self, event: AnnounceEvent) -> Result<String, TrackerError> {
+        self.request(event).http_url(&self.url)
+    }
+
+    /// The event the next regular announce has to carry given what was announced so far.
+    pub fn next_event(&self) -> AnnounceEvent {
+        if !self.started {
+            AnnounceEvent::Started
+        } else if !self.completed && self.stats.left() == 0 {
+            AnnounceEvent::Completed
+        } else {
+            AnnounceEvent::None
+        }
+    }
+
+    /// Announces to the tracker, sending `started` or `completed` when they are due.
+    pub fn announce(&mut self) -> Result<TrackerResponse, TrackerError> {
+        let event = self.next_event();
+        let response = self.announce_event(event)?;
+        match event {
+            AnnounceEvent::Started => {
+                self.started = true;
+                // A torrent that finishes before the first announce never reports `completed`.
+                self.completed = self.stats.left() == 0;
+            }
+            AnnounceEvent::Completed => self.completed = true,
+            _ => {}
+        }
+        Ok(response)
+    }
+
+    /// Tells the tracker we are leaving the swarm. Does nothing if we never announced.
+    pub fn stop(&mut self) -> Result<(), TrackerError> {
+        if self.started {
+            self.announce_event(AnnounceEvent::Stopped)?;
+            self.started = false;
+        }
+        Ok(())
+    }
+
+    /// Announces with an explicit event, speaking HTTP or UDP depending on the URL's scheme.
+    pub fn announce_event(
+        &mut self,
+        event: AnnounceEvent,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let request = self.request(event);
+        if self.client.is_none() {
+            self.client = Some(BlockingTrackerClient::new(TrackerClientConfig::default())?);
+        }
+        let response = self
+            .client
+            .as_ref()
+            .unwrap()
+            .announce(&self.url, &request)?;
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_id = Some(tracker_id.clone());
+        }
+        Ok(response)
+    }
+}
+
+/// One-shot announce used by the simple CLI paths: joins the swarm, collects peers and leaves.
+pub fn tracker_get(torrent_file: &TorrentFile) -> Result<TrackerResponse, TrackerError> {
+    let stats = Arc::new(TransferStats::new(torrent_file.info.length));
+    let mut tracker = torrent_file.tracker(stats);
+    let response = tracker.announce()?;
+    let _ = tracker.stop();
+    Ok(response)
+}
+
+/// Derives the scrape URL from an announce URL by the usual convention: the last path segment
+/// must start with `announce`, which is replaced by `scrape`. Other trackers cannot be scraped.
+/// UDP trackers scrape on the announce address itself.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    if announce.starts_with("udp://") {
+        return Some(announce.to_owned());
+    }
+    let (path, query) = match announce.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (announce, None),
+    };
+    let slash = path.rfind('/')?;
+    let rest = path[slash + 1..].strip_prefix("announce")?;
+    let mut url = format!("{}/scrape{}", &path[..slash], rest);
+    if let Some(query) = query {
+        url.push('?');
+        url.push_str(query);
+    }
+    Some(url)
+}
+
+/// Scrapes swarm statistics for several torrents of the same tracker in as few requests as
+/// possible. Info hashes the tracker does not know about are missing from the result.
+pub fn scrape(
+    announce: &str,
+    info_hashes: &[[u8; 20]],
+) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    BlockingTrackerClient::new(TrackerClientConfig::default())?.scrape(announce, info_hashes)
+}
+
+pub fn parse_scrape(bytes: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let malformed = TrackerError::Malformed;
+    let BencodeValue::Dictionary(mut response) =
+        BencodeValue::from_bytes(bytes).map_err(|err| malformed(err.to_string()))?
+    else {
+        return Err(malformed("expected a dictionary".to_owned()));
+    };
+    if let Some(reason) = response.remove(&b"failure reason"[..]) {
+        let reason = String::deserialize(reason).map_err(|err| malformed(err.to_string()))?;
+        return Err(TrackerError::Failure(reason));
+    }
+    // `files` is keyed by raw info hashes, so it is walked here rather than through serde,
+    // which only sees string keys.
+    let files = match response.remove(&b"files"[..]) {
+        Some(BencodeValue::Dictionary(files)) => files,
+        Some(_) => return Err(malformed("`files` is not a dictionary".to_owned())),
+        None => BTreeMap::new(),
+    };
+    let mut stats = HashMap::new();
+    for (info_hash, value) in files {
+        let value = ScrapeStats::deserialize(value).map_err(|err| malformed(err.to_string()))?;
+        if let Ok(info_hash) = info_hash.try_into() {
+            stats.insert(info_hash, value);
+        }
+    }
+    Ok(stats)
+}
+
+#[derive(Deserialize)]
+struct RawResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    #[serde(rename = "warning message")]
+    warning_message: Option<String>,
+    interval: Option<u64>,
+    #[serde(rename = "min interval")]
+    min_interval: Option<u64>,
+    #[serde(rename = "tracker id")]
+    tracker_id: Option<String>,
+    complete: Option<u64>,
+    incomplete: Option<u64>,
+    peers: Option<RawPeers>,
+    peers6: Option<ByteBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawPeers {
+    Compact(ByteBuf),
+    Dictionaries(Vec<RawPeer>),
+}
+
+#[derive(Deserialize)]
+struct RawPeer {
+    ip: String,
+    port: u16,
+    #[serde(rename = "peer id")]
+    peer_id: Option<ByteBuf>,
+}
+
+pub fn parse_response(bencode: BencodeValue) -> Result<TrackerResponse, TrackerError> {
+    let raw = RawResponse::deserialize(bencode)
+        .map_err(|err| TrackerError::Malformed(err.to_string()))?;
+    if let Some(reason) = raw.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
+    let interval = raw
+        .interval
+        .ok_or_else(|| TrackerError::Malformed("missing interval".to_owned()))?;
+
+    let mut peers = match raw.peers {
+        Some(RawPeers::Compact(bytes)) => Peer::from_compact(&bytes, false),
+        Some(RawPeers::Dictionaries(list)) => list
+            .into_iter()
+            // Hostnames are allowed here by the spec but are rare enough to skip.
+            .filter_map(|peer| {
+                Some(Peer {
+                    ip_addr: peer.ip.parse().ok()?,
+                    port: peer.port,
+                    peer_id: peer
+                        .peer_id
+                        .and_then(|id| PeerId::try_from(id.as_slice()).ok()),
+                })
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+    if let Some(bytes) = raw.peers6 {
+        peers.extend(Peer::from_compact(&bytes, true));
+    }
+
+    Ok(TrackerResponse {
+        interval,
+        min_interval: raw.min_interval,
+        peers,
+        complete: raw.complete,
+        incomplete: raw.incomplete,
+        tracker_id: raw.tracker_id,
+        warning_message: raw.warning_message,
+    })
+}
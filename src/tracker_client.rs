@@ -0,0 +1,238 @@
+use crate::bencode::BencodeValue;
+use crate::proxy::{self, ProxyConfig};
+use crate::tracker::{
+    parse_scrape, scrape_url, AnnounceRequest, ScrapeStats, TrackerError, TrackerResponse,
+};
+use crate::udp_tracker::{UdpTracker, UdpTrackerError, MAX_SCRAPE_HASHES};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use reqwest::{redirect, Url};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+/// Tracker replies are a few kilobytes; anything decompressing past this is not a reply.
+const MAX_RESPONSE_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct TrackerClientConfig {
+    /// Limit for a whole HTTP request, including redirects and reading the body.
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    pub user_agent: String,
+    /// Redirects followed before giving up; 0 treats any redirect as an error.
+    pub max_redirects: usize,
+    /// First UDP retransmission timeout, doubled on every retry as BEP 15 describes.
+    pub udp_timeout: Duration,
+    pub udp_max_retransmits: u32,
+    pub proxy: ProxyConfig,
+}
+
+impl Default for TrackerClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            user_agent: concat!("bittorrent-rust/", env!("CARGO_PKG_VERSION")).to_owned(),
+            max_redirects: 5,
+            udp_timeout: Duration::from_secs(15),
+            udp_max_retransmits: 8,
+            proxy: ProxyConfig::global().clone(),
+        }
+    }
+}
+
+/// Async client for HTTP and UDP trackers. Cloning is cheap and clones share connections,
+/// including the cached UDP connection IDs.
+#[derive(Clone)]
+pub struct TrackerClient {
+    http: reqwest::Client,
+    config: TrackerClientConfig,
+    udp: Arc<Mutex<HashMap<String, UdpTracker>>>,
+}
+
+impl TrackerClient {
+    pub fn new(config: TrackerClientConfig) -> Result<Self, TrackerError> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout)
+            .user_agent(config.user_agent.clone())
+            .redirect(match config.max_redirects {
+                0 => redirect::Policy::none(),
+                n => redirect::Policy::limited(n),
+            });
+        builder = match config.proxy.reqwest_proxy() {
+            Some(proxy) => {
+                builder.proxy(proxy.map_err(|err| TrackerError::Client(err.to_string()))?)
+            }
+            // Proxies from the environment must not carry what proxy-only refuses.
+            None if config.proxy.proxy_only => builder.no_proxy(),
+            None => builder,
+        };
+        let http = builder
+            .build()
+            .map_err(|err| TrackerError::Client(err.to_string()))?;
+        Ok(Self {
+            http,
+            config,
+            udp: Arc::default(),
+        })
+    }
+
+    /// Announces to `url`, speaking HTTP or UDP depending on its scheme.
+    pub async fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        match scheme(url)?.as_str() {
+            "http" | "https" => {
+                let body = self.get(&request.http_url(url)?).await?;
+                TrackerResponse::from_bytes(&body)
+            }
+            "udp" => {
+                let announce = request.udp_announce();
+                self.with_udp(url, move |tracker| tracker.announce(&announce))
+                    .await
+            }
+            scheme => Err(TrackerError::UnsupportedScheme(scheme.to_owned())),
+        }
+    }
+
+    /// Scrapes swarm statistics for several torrents of the same tracker in as few requests as
+    /// possible. Info hashes the tracker does not know about are missing from the result.
+    pub async fn scrape(
+        &self,
+        announce: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        let url = scrape_url(announce).ok_or(TrackerError::ScrapeUnsupported)?;
+        match scheme(&url)?.as_str() {
+            "http" | "https" => {
+                let mut url = url;
+                for info_hash in info_hashes {
+                    url.push(if url.contains('?') { '&' } else { '?' });
+                    url.push_str("info_hash=");
+                    url.push_str(&percent_encode(info_hash, NON_ALPHANUMERIC).to_string());
+                }
+                parse_scrape(&self.get(&url).await?)
+            }
+            "udp" => {
+                let info_hashes = info_hashes.to_vec();
+                self.with_udp(&url, move |tracker| {
+                    let mut stats = HashMap::new();
+                    for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+                        stats.extend(batch.iter().copied().zip(tracker.scrape(batch)?));
+                    }
+                    Ok(stats)
+                })
+                .await
+            }
+            scheme => Err(TrackerError::UnsupportedScheme(scheme.to_owned())),
+        }
+    }
+
+    /// Fetches a tracker URL; reqwest undoes gzip content encoding. Error statuses are reported
+    /// as the tracker's failure reason when the body carries one.
+    async fn get(&self, url: &str) -> Result<Vec<u8>, TrackerError> {
+        if self.config.proxy.proxy.is_none() && self.config.proxy.proxy_only {
+            return Err(proxy::refused("HTTP request").into());
+        }
+        let mut response = self.http.get(url).send().await?;
+        let status = response.status().as_u16();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            if body.len() + chunk.len() > MAX_RESPONSE_LEN {
+                return Err(TrackerError::Malformed("response too large".to_owned()));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        if !(200..300).contains(&status) {
+            return Err(match failure_reason(&body) {
+                Some(reason) => TrackerError::Failure(reason),
+                None => TrackerError::Status(status),
+            });
+        }
+        Ok(body)
+    }
+
+    /// Runs a blocking UDP exchange off the async threads, reusing the connection ID from
+    /// earlier requests to the same tracker.
+    async fn with_udp<T, F>(&self, url: &str, exchange: F) -> Result<T, TrackerError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut UdpTracker) -> Result<T, UdpTrackerError> + Send + 'static,
+    {
+        let cached = self.udp.lock().unwrap().remove(url);
+        let config = self.config.clone();
+        let owned_url = url.to_owned();
+        let (tracker, result) = tokio::task::spawn_blocking(move || {
+            let mut tracker = match cached {
+                Some(tracker) => tracker,
+                None => UdpTracker::connect_via(&owned_url, &config.proxy)?
+                    .with_timeouts(config.udp_timeout, config.udp_max_retransmits),
+            };
+            let result = exchange(&mut tracker);
+            Ok::<_, UdpTrackerError>((tracker, result))
+        })
+        .await
+        .expect("udp tracker task panicked")?;
+        self.udp.lock().unwrap().insert(url.to_owned(), tracker);
+        Ok(result?)
+    }
+}
+
+/// Blocking facade over `TrackerClient` for code running outside a tokio runtime, such as the
+/// CLI and the `Announcer` thread. Must not be used from within an async context.
+#[derive(Clone)]
+pub struct BlockingTrackerClient {
+    runtime: Arc<Runtime>,
+    client: TrackerClient,
+}
+
+impl BlockingTrackerClient {
+    pub fn new(config: TrackerClientConfig) -> Result<Self, TrackerError> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|err| TrackerError::Client(err.to_string()))?;
+        Ok(Self {
+            runtime: Arc::new(runtime),
+            client: TrackerClient::new(config)?,
+        })
+    }
+
+    pub fn announce(
+        &self,
+        url: &str,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        self.runtime.block_on(self.client.announce(url, request))
+    }
+
+    pub fn scrape(
+        &self,
+        announce: &str,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+        self.runtime
+            .block_on(self.client.scrape(announce, info_hashes))
+    }
+}
+
+fn scheme(url: &str) -> Result<String, TrackerError> {
+    Ok(Url::parse(url)
+        .map_err(|err| TrackerError::Url(err.to_string()))?
+        .scheme()
+        .to_owned())
+}
+
+fn failure_reason(body: &[u8]) -> Option<String> {
+    match BencodeValue::from_bytes(body).ok()? {
+        BencodeValue::Dictionary(dict) => match dict.get(&b"failure reason"[..])? {
+            BencodeValue::ByteString(reason) => Some(String::from_utf8_lossy(reason).into_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
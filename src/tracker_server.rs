@@ -0,0 +1,486 @@
+use crate::bencode::BencodeValue;
+use crate::peer_id::PeerId;
+use crate::tracker::{AnnounceEvent, Peer, ScrapeStats};
+use crate::utils::random_u64;
+use percent_encoding::percent_decode;
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Announce interval handed out to clients unless configured otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1800);
+pub(crate) const DEFAULT_NUMWANT: usize = 50;
+const MAX_NUMWANT: usize = 200;
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Connections served at once unless configured otherwise; each holds a thread.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+/// Pause after a failed `accept`.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Reasons for turning a request down. The message is what the client sees as `failure reason`.
+#[derive(Debug, Error)]
+pub enum TrackerServerError {
+    #[error("torrent is not tracked here")]
+    NotAllowed,
+    #[error("missing parameter `{0}`")]
+    MissingParameter(&'static str),
+    #[error("invalid parameter `{0}`")]
+    InvalidParameter(&'static str),
+}
+
+/// An announce as received by the tracker, independent of the frontend it arrived through.
+#[derive(Debug, Clone)]
+pub struct ClientAnnounce {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub numwant: usize,
+}
+
+#[derive(Debug)]
+pub struct SwarmReply {
+    pub peers: Vec<Peer>,
+    pub complete: u64,
+    pub incomplete: u64,
+}
+
+struct StoredPeer {
+    addr: SocketAddr,
+    seeding: bool,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<PeerId, StoredPeer>,
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.seeding).count() as u64;
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u64 - complete,
+        }
+    }
+}
+
+struct StoreInner {
+    swarms: HashMap<[u8; 20], Swarm>,
+    allowlist: Option<HashSet<[u8; 20]>>,
+    peer_ttl: Duration,
+}
+
+impl StoreInner {
+    fn expire(&mut self) {
+        let peer_ttl = self.peer_ttl;
+        for swarm in self.swarms.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| peer.last_seen.elapsed() < peer_ttl);
+        }
+        self.swarms
+            .retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+}
+
+/// Peer lists per info hash, shared by every tracker frontend serving the same swarms.
+/// Peers that have not announced within the TTL are dropped.
+#[derive(Clone)]
+pub struct PeerStore {
+    inner: Arc<Mutex<StoreInner>>,
+}
+
+impl PeerStore {
+    pub fn new(peer_ttl: Duration) -> Self {
+        Self::with_allowlist(peer_ttl, None)
+    }
+
+    /// A store that only tracks the given info hashes, or any if `allowlist` is `None`.
+    pub fn with_allowlist(peer_ttl: Duration, allowlist: Option<HashSet<[u8; 20]>>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(StoreInner {
+                swarms: HashMap::new(),
+                allowlist,
+                peer_ttl,
+            })),
+        }
+    }
+
+    pub fn announce(&self, announce: &ClientAnnounce) -> Result<SwarmReply, TrackerServerError> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(allowlist) = &inner.allowlist {
+            if !allowlist.contains(&announce.info_hash) {
+                return Err(TrackerServerError::NotAllowed);
+            }
+        }
+        inner.expire();
+        let swarm = inner.swarms.entry(announce.info_hash).or_default();
+        match announce.event {
+            AnnounceEvent::Stopped => {
+                swarm.peers.remove(&announce.peer_id);
+            }
+            event => {
+                if event == AnnounceEvent::Completed {
+                    swarm.downloaded += 1;
+                }
+                swarm.peers.insert(
+                    announce.peer_id,
+                    StoredPeer {
+                        addr: announce.addr,
+                        seeding: announce.left == 0,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let mut candidates: Vec<Peer> = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != announce.peer_id)
+            .map(|(peer_id, peer)| Peer {
+                peer_id: Some(*peer_id),
+                ..Peer::new(peer.addr)
+            })
+            .collect();
+        // Hand out a different slice of a large swarm to every client.
+        if !candidates.is_empty() {
+            let offset = random_u64() as usize % candidates.len();
+            candidates.rotate_left(offset);
+        }
+        candidates.truncate(announce.numwant.min(MAX_NUMWANT));
+
+        let stats = swarm.stats();
+        Ok(SwarmReply {
+            peers: candidates,
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+        })
+    }
+
+    /// Statistics for the given info hashes, or for every tracked torrent if none are given.
+    /// Unknown info hashes are left out.
+    pub fn scrape(&self, info_hashes: &[[u8; 20]]) -> HashMap<[u8; 20], ScrapeStats> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.expire();
+        if info_hashes.is_empty() {
+            return inner
+                .swarms
+                .iter()
+                .map(|(info_hash, swarm)| (*info_hash, swarm.stats()))
+                .collect();
+        }
+        info_hashes
+            .iter()
+            .filter_map(|info_hash| Some((*info_hash, inner.swarms.get(info_hash)?.stats())))
+            .collect()
+    }
+}
+
+/// HTTP frontend serving `/announce` and `/scrape` from a `PeerStore`. Besides `tracker serve`,
+/// it doubles as an in-process mock tracker: bind to port 0, `spawn` and announce to
+/// `announce_url`.
+pub struct HttpTrackerServer {
+    listener: TcpListener,
+    store: PeerStore,
+    interval: Duration,
+    trust_ip: bool,
+    max_connections: usize,
+}
+
+impl HttpTrackerServer {
+    pub fn bind(addr: impl ToSocketAddrs, store: PeerStore) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr)?,
+            store,
+            interval: DEFAULT_INTERVAL,
+            trust_ip: false,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        })
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Honors the `ip` parameter from every client instead of only local ones.
+    pub fn with_trusted_ip(mut self, trust_ip: bool) -> Self {
+        self.trust_ip = trust_ip;
+        self
+    }
+
+    /// Connections past `max_connections` are closed without an answer.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn announce_url(&self) -> io::Result<String> {
+        Ok(format!("http://{}/announce", self.local_addr()?))
+    }
+
+    /// Serves requests forever, one thread per connection.
+    pub fn run(self) -> io::Result<()> {
+        let active = Arc::new(AtomicUsize::new(0));
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                // Running out of file descriptors and the like pass; back off meanwhile.
+                Err(err) => {
+                    eprintln!("tracker: accept failed: {err}");
+                    thread::sleep(ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+            if active.load(Ordering::Relaxed) >= self.max_connections {
+                continue;
+            }
+            active.fetch_add(1, Ordering::Relaxed);
+            let (store, active) = (self.store.clone(), active.clone());
+            let (interval, trust_ip) = (self.interval, self.trust_ip);
+            thread::spawn(move || {
+                let _ = handle_connection(stream, &store, interval, trust_ip);
+                active.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+        Ok(())
+    }
+
+    pub fn spawn(self) -> JoinHandle<io::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    store: &PeerStore,
+    interval: Duration,
+    trust_ip: bool,
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = parse_query(query);
+    let body = match path {
+        "/announce" => announce_response(&params, stream.peer_addr()?, store, interval, trust_ip),
+        "/scrape" => scrape_response(&params, store),
+        _ => {
+            return stream.write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+    };
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)
+}
+
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| {
+            let key = percent_decode(key.as_bytes())
+                .decode_utf8_lossy()
+                .into_owned();
+            (key, percent_decode(value.as_bytes()).collect())
+        })
+        .collect()
+}
+
+fn param<'a>(params: &'a [(String, Vec<u8>)], name: &str) -> Option<&'a [u8]> {
+    params
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_slice())
+}
+
+fn parse_param<T: std::str::FromStr>(
+    params: &[(String, Vec<u8>)],
+    name: &'static str,
+) -> Result<Option<T>, TrackerServerError> {
+    match param(params, name) {
+        None => Ok(None),
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Some)
+            .ok_or(TrackerServerError::InvalidParameter(name)),
+    }
+}
+
+fn hash_param(value: &[u8], name: &'static str) -> Result<[u8; 20], TrackerServerError> {
+    value
+        .try_into()
+        .map_err(|_| TrackerServerError::InvalidParameter(name))
+}
+
+fn failure(err: TrackerServerError) -> Vec<u8> {
+    BencodeValue::dictionary([(
+        "failure reason",
+        BencodeValue::ByteString(err.to_string().into_bytes()),
+    )])
+    .encode()
+}
+
+fn announce_response(
+    params: &[(String, Vec<u8>)],
+    remote: SocketAddr,
+    store: &PeerStore,
+    interval: Duration,
+    trust_ip: bool,
+) -> Vec<u8> {
+    match parse_announce(params, remote, trust_ip).and_then(|announce| {
+        let compact = param(params, "compact") != Some(b"0");
+        let no_peer_id = param(params, "no_peer_id") == Some(b"1");
+        Ok((store.announce(&announce)?, compact, no_peer_id))
+    }) {
+        Ok((reply, compact, no_peer_id)) => {
+            let mut entries = vec![
+                ("interval", BencodeValue::Integer(interval.as_secs() as i64)),
+                ("complete", BencodeValue::Integer(reply.complete as i64)),
+                ("incomplete", BencodeValue::Integer(reply.incomplete as i64)),
+            ];
+            if compact {
+                let (v4, v6): (Vec<_>, Vec<_>) =
+                    reply.peers.iter().partition(|peer| peer.ip_addr.is_ipv4());
+                let pack = |peers: Vec<&Peer>| {
+                    BencodeValue::ByteString(
+                        peers.iter().flat_map(|peer| peer.to_compact()).collect(),
+                    )
+                };
+                entries.push(("peers", pack(v4)));
+                if !v6.is_empty() {
+                    entries.push(("peers6", pack(v6)));
+                }
+            } else {
+                let peers = reply
+                    .peers
+                    .into_iter()
+                    .map(|peer| {
+                        let mut dict = vec![
+                            (
+                                "ip",
+                                BencodeValue::ByteString(peer.ip_addr.to_string().into_bytes()),
+                            ),
+                            ("port", BencodeValue::Integer(peer.port as i64)),
+                        ];
+                        if let (Some(peer_id), false) = (peer.peer_id, no_peer_id) {
+                            dict.push(("peer id", BencodeValue::ByteString(peer_id.0.to_vec())));
+                        }
+                        BencodeValue::dictionary(dict)
+                    })
+                    .collect();
+                entries.push(("peers", BencodeValue::List(peers)));
+            }
+            BencodeValue::dictionary(entries).encode()
+        }
+        Err(err) => failure(err),
+    }
+}
+
+fn parse_announce(
+    params: &[(String, Vec<u8>)],
+    remote: SocketAddr,
+    trust_ip: bool,
+) -> Result<ClientAnnounce, TrackerServerError> {
+    let info_hash =
+        param(params, "info_hash").ok_or(TrackerServerError::MissingParameter("info_hash"))?;
+    let peer_id =
+        param(params, "peer_id").ok_or(TrackerServerError::MissingParameter("peer_id"))?;
+    let port: u16 =
+        parse_param(params, "port")?.ok_or(TrackerServerError::MissingParameter("port"))?;
+    let ip = announced_ip(parse_param(params, "ip")?, remote.ip(), trust_ip);
+    let event = param(params, "event")
+        .map(|event| AnnounceEvent::from_name(&String::from_utf8_lossy(event)))
+        .unwrap_or(AnnounceEvent::None);
+    Ok(ClientAnnounce {
+        info_hash: hash_param(info_hash, "info_hash")?,
+        peer_id: PeerId(hash_param(peer_id, "peer_id")?),
+        addr: SocketAddr::new(ip, port),
+        left: parse_param(params, "left")?.unwrap_or(0),
+        event,
+        numwant: parse_param(params, "numwant")?.unwrap_or(DEFAULT_NUMWANT),
+    })
+}
+
+/// Whether `ip` is loopback, private or link-local: a client on the tracker's own network,
+/// which may announce an address other than the one its requests come from.
+pub fn is_local(ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    };
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let prefix = ip.segments()[0];
+            // Unique local fc00::/7 and link-local fe80::/10.
+            ip.is_loopback() || prefix & 0xfe00 == 0xfc00 || prefix & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+/// The address peers are handed for an announce from `remote`. An `ip` override from the
+/// internet would let anyone point a swarm at a third party, so it is only honored from
+/// local clients unless the tracker trusts them all.
+pub(crate) fn announced_ip(requested: Option<IpAddr>, remote: IpAddr, trust_ip: bool) -> IpAddr {
+    match requested {
+        Some(ip) if trust_ip || is_local(remote) => ip,
+        _ => remote,
+    }
+}
+
+fn scrape_response(params: &[(String, Vec<u8>)], store: &PeerStore) -> Vec<u8> {
+    let info_hashes: Result<Vec<[u8; 20]>, _> = params
+        .iter()
+        .filter(|(key, _)| key == "info_hash")
+        .map(|(_, value)| hash_param(value, "info_hash"))
+        .collect();
+    let info_hashes = match info_hashes {
+        Ok(info_hashes) => info_hashes,
+        Err(err) => return failure(err),
+    };
+    let files = store
+        .scrape(&info_hashes)
+        .into_iter()
+        .map(|(info_hash, stats)| {
+            let stats = BencodeValue::dictionary([
+                ("complete", BencodeValue::Integer(stats.complete as i64)),
+                ("downloaded", BencodeValue::Integer(stats.downloaded as i64)),
+                ("incomplete", BencodeValue::Integer(stats.incomplete as i64)),
+            ]);
+            (info_hash.to_vec(), stats)
+        })
+        .collect();
+    BencodeValue::dictionary([("files", BencodeValue::Dictionary(files))]).encode()
+}
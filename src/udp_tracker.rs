@@ -0,0 +1,254 @@
+use crate::peer_id::PeerId;
+use crate::proxy::{ProxyConfig, UdpChannel};
+use crate::tracker::{Peer, ScrapeStats, TrackerResponse};
+use crate::utils::random_u64;
+use bytes::{Buf, BufMut, BytesMut};
+use reqwest::Url;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Magic constant identifying a BEP 15 connect request.
+pub(crate) const PROTOCOL_ID: u64 = 0x41727101980;
+/// Maximum number of info hashes that fit into a single scrape packet.
+pub(crate) const MAX_SCRAPE_HASHES: usize = 74;
+/// How long a connection ID may be reused before a new connect is required.
+const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+pub(crate) const ACTION_CONNECT: u32 = 0;
+pub(crate) const ACTION_ANNOUNCE: u32 = 1;
+pub(crate) const ACTION_SCRAPE: u32 = 2;
+pub(crate) const ACTION_ERROR: u32 = 3;
+
+#[derive(Debug, Error)]
+pub enum UdpTrackerError {
+    #[error("invalid udp tracker url: {0}")]
+    Url(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("tracker did not respond after {0} attempts")]
+    Timeout(u32),
+    #[error("tracker error: {0}")]
+    Tracker(String),
+    #[error("malformed tracker response: {0}")]
+    Malformed(&'static str),
+}
+
+/// Parameters of a single UDP announce, in the order they appear on the wire.
+#[derive(Debug, Clone)]
+pub struct UdpAnnounce {
+    pub info_hash: [u8; 20],
+    pub peer_id: PeerId,
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub event: u32,
+    /// IPv4 address to hand out to other peers, or 0 for the packet's source address.
+    pub ip: u32,
+    pub key: u32,
+    pub num_want: i32,
+    pub port: u16,
+}
+
+/// Client side of the UDP tracker protocol (BEP 15).
+pub struct UdpTracker {
+    socket: UdpChannel,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retransmits: u32,
+}
+
+impl UdpTracker {
+    /// Connects to the tracker at `url` according to the global proxy configuration.
+    pub fn connect(url: &str) -> Result<Self, UdpTrackerError> {
+        Self::connect_via(url, ProxyConfig::global())
+    }
+
+    pub fn connect_via(url: &str, proxy: &ProxyConfig) -> Result<Self, UdpTrackerError> {
+        let url = Url::parse(url).map_err(|err| UdpTrackerError::Url(err.to_string()))?;
+        // IPv6 literals come bracketed, as they appear in the URL.
+        let host = url
+            .host_str()
+            .ok_or_else(|| UdpTrackerError::Url("missing host".to_owned()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = url
+            .port()
+            .ok_or_else(|| UdpTrackerError::Url("missing port".to_owned()))?;
+        Ok(Self::with_channel(proxy.udp(host, port)?))
+    }
+
+    /// Talks to `addr` directly, whatever the proxy configuration.
+    pub fn with_addr(addr: SocketAddr) -> Result<Self, UdpTrackerError> {
+        Ok(Self::with_channel(UdpChannel::direct(addr)?))
+    }
+
+    fn with_channel(socket: UdpChannel) -> Self {
+        Self {
+            socket,
+            connection: None,
+            base_timeout: Duration::from_secs(15),
+            max_retransmits: 8,
+        }
+    }
+
+    /// Overrides the spec's retransmission schedule of `15 * 2^n` seconds for `n` up to 8.
+    pub fn with_timeouts(mut self, base_timeout: Duration, max_retransmits: u32) -> Self {
+        self.base_timeout = base_timeout;
+        self.max_retransmits = max_retransmits;
+        self
+    }
+
+    pub fn announce(&mut self, announce: &UdpAnnounce) -> Result<TrackerResponse, UdpTrackerError> {
+        let response = self.request(ACTION_ANNOUNCE, |buf| {
+            buf.put_slice(&announce.info_hash);
+            buf.put_slice(announce.peer_id.as_bytes());
+            buf.put_u64(announce.downloaded);
+            buf.put_u64(announce.left);
+            buf.put_u64(announce.uploaded);
+            buf.put_u32(announce.event);
+            buf.put_u32(announce.ip);
+            buf.put_u32(announce.key);
+            buf.put_i32(announce.num_want);
+            buf.put_u16(announce.port);
+        })?;
+        let mut response = &response[..];
+        if response.remaining() < 12 {
+            return Err(UdpTrackerError::Malformed("short announce response"));
+        }
+        let interval = response.get_u32() as u64;
+        let incomplete = response.get_u32() as u64;
+        let complete = response.get_u32() as u64;
+        // Peers come in the address family of the tracker we are talking to.
+        let ipv6 = self.socket.is_ipv6()?;
+        Ok(TrackerResponse {
+            interval,
+            min_interval: None,
+            peers: Peer::from_compact(response, ipv6),
+            complete: Some(complete),
+            incomplete: Some(incomplete),
+            tracker_id: None,
+            warning_message: None,
+        })
+    }
+
+    pub fn scrape(
+        &mut self,
+        info_hashes: &[[u8; 20]],
+    ) -> Result<Vec<ScrapeStats>, UdpTrackerError> {
+        let response = self.request(ACTION_SCRAPE, |buf| {
+            for info_hash in info_hashes {
+                buf.put_slice(info_hash);
+            }
+        })?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(UdpTrackerError::Malformed("short scrape response"));
+        }
+        Ok(response
+            .chunks_exact(12)
+            .map(|mut chunk| ScrapeStats {
+                complete: chunk.get_u32() as u64,
+                downloaded: chunk.get_u32() as u64,
+                incomplete: chunk.get_u32() as u64,
+            })
+            .collect())
+    }
+
+    /// Returns a connection ID younger than a minute, performing a connect exchange if needed.
+    fn connection_id(&mut self) -> Result<u64, UdpTrackerError> {
+        if let Some((id, obtained)) = self.connection {
+            if obtained.elapsed() < CONNECTION_ID_TTL {
+                return Ok(id);
+            }
+        }
+        for attempt in 0..=self.max_retransmits {
+            let transaction_id = random_u64() as u32;
+            let mut buf = BytesMut::with_capacity(16);
+            buf.put_u64(PROTOCOL_ID);
+            buf.put_u32(ACTION_CONNECT);
+            buf.put_u32(transaction_id);
+            if let Some(response) = self.exchange(&buf, ACTION_CONNECT, transaction_id, attempt)? {
+                let mut response = &response[..];
+                if response.remaining() < 8 {
+                    return Err(UdpTrackerError::Malformed("short connect response"));
+                }
+                let id = response.get_u64();
+                self.connection = Some((id, Instant::now()));
+                return Ok(id);
+            }
+        }
+        Err(UdpTrackerError::Timeout(self.max_retransmits + 1))
+    }
+
+    /// Sends a connected request, retransmitting with a fresh connection ID if it expires meanwhile.
+    fn request(
+        &mut self,
+        action: u32,
+        body: impl Fn(&mut BytesMut),
+    ) -> Result<Vec<u8>, UdpTrackerError> {
+        for attempt in 0..=self.max_retransmits {
+            let connection_id = self.connection_id()?;
+            let transaction_id = random_u64() as u32;
+            let mut buf = BytesMut::with_capacity(98);
+            buf.put_u64(connection_id);
+            buf.put_u32(action);
+            buf.put_u32(transaction_id);
+            body(&mut buf);
+            if let Some(response) = self.exchange(&buf, action, transaction_id, attempt)? {
+                return Ok(response);
+            }
+        }
+        Err(UdpTrackerError::Timeout(self.max_retransmits + 1))
+    }
+
+    /// Sends `packet` once and waits `base_timeout * 2^attempt` for the matching reply.
+    /// Returns the payload after the action and transaction ID, or `None` on timeout.
+    fn exchange(
+        &self,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>, UdpTrackerError> {
+        self.socket.send(packet)?;
+        let deadline = Instant::now() + self.base_timeout * 2u32.pow(attempt);
+        let mut buf = [0; 65536];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let n = match self.socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Ok(None)
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let mut response = &buf[..n];
+            if response.len() < 8 {
+                continue;
+            }
+            let response_action = response.get_u32();
+            if response.get_u32() != transaction_id {
+                // A late reply to an earlier retransmission.
+                continue;
+            }
+            if response_action == ACTION_ERROR {
+                let message = String::from_utf8_lossy(response).into_owned();
+                return Err(UdpTrackerError::Tracker(message));
+            }
+            if response_action != action {
+                return Err(UdpTrackerError::Malformed("unexpected action"));
+            }
+            return Ok(Some(response.to_vec()));
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::peer_id::PeerId;
+use crate::tracker::AnnounceEvent;
+use crate::tracker_server::{
+    announced_ip, ClientAnnounce, PeerStore, DEFAULT_INTERVAL, DEFAULT_NUMWANT,
+};
+use crate::udp_tracker::{
+    ACTION_ANNOUNCE, ACTION_CONNECT, ACTION_ERROR, ACTION_SCRAPE, MAX_SCRAPE_HASHES, PROTOCOL_ID,
+};
+use crate::utils::random_u64;
+use bytes::{Buf, BufMut, BytesMut};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How often the connection ID secret changes. IDs issued under the previous secret are still
+/// accepted, so every ID stays valid for at least this long, which covers the client's minute.
+const SECRET_ROTATION: Duration = Duration::from_secs(120);
+const ANNOUNCE_LEN: usize = 98;
+
+/// Connection IDs are a keyed hash of the client address, so the server keeps no per-client
+/// state and a spoofed source address cannot obtain a usable ID.
+struct ConnectionSecrets {
+    current: u64,
+    previous: u64,
+    rotated: Instant,
+}
+
+impl ConnectionSecrets {
+    fn new() -> Self {
+        Self {
+            current: random_u64(),
+            previous: random_u64(),
+            rotated: Instant::now(),
+        }
+    }
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated.elapsed() >= SECRET_ROTATION {
+            self.previous = self.current;
+            self.current = random_u64();
+            self.rotated = Instant::now();
+        }
+    }
+
+    fn connection_id(secret: u64, addr: SocketAddr) -> u64 {
+        let mut hasher = Sha1::new();
+        hasher.update(secret.to_be_bytes());
+        match addr.ip() {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(addr.port().to_be_bytes());
+        u64::from_be_bytes(hasher.finalize()[..8].try_into().unwrap())
+    }
+
+    fn issue(&self, addr: SocketAddr) -> u64 {
+        Self::connection_id(self.current, addr)
+    }
+
+    fn is_valid(&self, id: u64, addr: SocketAddr) -> bool {
+        id == Self::connection_id(self.current, addr)
+            || id == Self::connection_id(self.previous, addr)
+    }
+}
+
+/// UDP frontend (BEP 15) to a `PeerStore`, usually the same one an `HttpTrackerServer` serves.
+pub struct UdpTrackerServer {
+    socket: UdpSocket,
+    store: PeerStore,
+    interval: Duration,
+    trust_ip: bool,
+    secrets: ConnectionSecrets,
+}
+
+impl UdpTrackerServer {
+    pub fn bind(addr: impl ToSocketAddrs, store: PeerStore) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr)?,
+            store,
+            interval: DEFAULT_INTERVAL,
+            trust_ip: false,
+            secrets: ConnectionSecrets::new(),
+        })
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Honors the IP address field from every client instead of only local ones.
+    pub fn with_trusted_ip(mut self, trust_ip: bool) -> Self {
+        self.trust_ip = trust_ip;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn announce_url(&self) -> io::Result<String> {
+        Ok(format!("udp://{}/announce", self.local_addr()?))
+    }
+
+    /// Serves requests forever on the calling thread.
+    pub fn run(mut self) -> io::Result<()> {
+        let mut buf = [0; 2048];
+        loop {
+            let (n, from) = self.socket.recv_from(&mut buf)?;
+            self.secrets.rotate_if_due();
+            if let Some(reply) = self.handle(&buf[..n], from) {
+                // A client that went away is no reason to stop serving.
+                let _ = self.socket.send_to(&reply, from);
+            }
+        }
+    }
+
+    pub fn spawn(self) -> JoinHandle<io::Result<()>> {
+        thread::spawn(move || self.run())
+    }
+
+    fn handle(&self, mut packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let connection_id = packet.get_u64();
+        let action = packet.get_u32();
+        let transaction_id = packet.get_u32();
+        let mut reply = BytesMut::new();
+
+        if action == ACTION_CONNECT {
+            if connection_id != PROTOCOL_ID {
+                return None;
+            }
+            reply.put_u32(ACTION_CONNECT);
+            reply.put_u32(transaction_id);
+            reply.put_u64(self.secrets.issue(from));
+            return Some(reply.to_vec());
+        }
+        if !self.secrets.is_valid(connection_id, from) {
+            return Some(error_reply(transaction_id, "invalid connection id"));
+        }
+
+        match action {
+            ACTION_ANNOUNCE if packet.len() >= ANNOUNCE_LEN - 16 => {
+                let announce = parse_announce(packet, from, self.trust_ip);
+                let swarm = match self.store.announce(&announce) {
+                    Ok(swarm) => swarm,
+                    Err(err) => return Some(error_reply(transaction_id, &err.to_string())),
+                };
+                reply.put_u32(ACTION_ANNOUNCE);
+                reply.put_u32(transaction_id);
+                reply.put_u32(self.interval.as_secs() as u32);
+                reply.put_u32(swarm.incomplete as u32);
+                reply.put_u32(swarm.complete as u32);
+                // Peers are returned in the address family the request arrived over.
+                for peer in swarm.peers {
+                    if peer.ip_addr.is_ipv4() == from.is_ipv4() {
+                        reply.put_slice(&peer.to_compact());
+                    }
+                }
+            }
+            ACTION_SCRAPE => {
+                let info_hashes: Vec<[u8; 20]> = packet
+                    .chunks_exact(20)
+                    .take(MAX_SCRAPE_HASHES)
+                    .map(|chunk| chunk.try_into().unwrap())
+                    .collect();
+                let stats = self.store.scrape(&info_hashes);
+                reply.put_u32(ACTION_SCRAPE);
+                reply.put_u32(transaction_id);
+                // Unlike HTTP, entries are positional, so unknown torrents are reported as empty.
+                for info_hash in &info_hashes {
+                    let stats = stats.get(info_hash).copied().unwrap_or_default();
+                    reply.put_u32(stats.complete as u32);
+                    reply.put_u32(stats.downloaded as u32);
+                    reply.put_u32(stats.incomplete as u32);
+                }
+            }
+            _ => return Some(error_reply(transaction_id, "malformed request")),
+        }
+        Some(reply.to_vec())
+    }
+}
+
+fn parse_announce(mut packet: &[u8], from: SocketAddr, trust_ip: bool) -> ClientAnnounce {
+    let info_hash: [u8; 20] = packet[..20].try_into().unwrap();
+    let peer_id = PeerId::try_from(&packet[20..40]).unwrap();
+    packet.advance(40);
+    let _downloaded = packet.get_u64();
+    let left = packet.get_u64();
+    let _uploaded = packet.get_u64();
+    let event = AnnounceEvent::from_udp_code(packet.get_u32());
+    let ip = packet.get_u32();
+    let _key = packet.get_u32();
+    let numwant = packet.get_i32();
+    let port = packet.get_u16();
+    let requested = (ip != 0).then(|| IpAddr::V4(Ipv4Addr::from(ip)));
+    let ip = announced_ip(requested, from.ip(), trust_ip);
+    ClientAnnounce {
+        info_hash,
+        peer_id,
+        addr: SocketAddr::new(ip, port),
+        left,
+        event,
+        numwant: if numwant > 0 {
+            numwant as usize
+        } else {
+            DEFAULT_NUMWANT
+        },
+    }
+}
+
+fn error_reply(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = BytesMut::new();
+    reply.put_u32(ACTION_ERROR);
+    reply.put_u32(transaction_id);
+    reply.put_slice(message.as_bytes());
+    reply.to_vec()
+}
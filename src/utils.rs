@@ -4,5 +4,22 @@ pub fn decode(encoded_value: &String) {
     let mut chars = encoded_value.as_bytes().iter().peekable();
     let decoded_value = decode_bencoded_value(&mut chars).unwrap();
     let result: serde_json::Value = decoded_value.into_json().unwrap();
-    println!("{}", result.to_string());
+    println!("{}", result);
+}
+
+/// Returns a random value from the standard library's per-process hash seeds, which is plenty
+/// for transaction IDs and announce keys without pulling in a RNG crate.
+pub fn random_u64() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    hasher.finish()
 }
@@ -0,0 +1,219 @@
+//! The tracker client's announce parameters and lifecycle events against local trackers.
+
+use bittorrent_starter_rust::announcer::{AnnounceConfig, Announcer};
+use bittorrent_starter_rust::bencode::BencodeValue;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{scrape, ScrapeStats, Tracker, TransferStats};
+use bittorrent_starter_rust::tracker_server::{HttpTrackerServer, PeerStore};
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpListener, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INFO_HASH: [u8; 20] = [0x5a; 20];
+const LENGTH: u64 = 1000;
+
+/// Records the query of every announce and answers with `tracker id` set to `tracker_id`.
+/// Raw byte parameters such as `info_hash` are decoded lossily.
+fn mock_tracker(tracker_id: &'static str) -> (String, Receiver<HashMap<String, String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/announce", listener.local_addr().unwrap());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8_lossy(&request).into_owned();
+            let target = request.split_whitespace().nth(1).unwrap();
+            let query = target.split_once('?').unwrap().1;
+            let params = query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| {
+                    let value = percent_decode_str(&value.replace('+', " "))
+                        .decode_utf8_lossy()
+                        .into_owned();
+                    (key.to_owned(), value)
+                })
+                .collect();
+            let body = BencodeValue::dictionary([
+                ("interval", BencodeValue::Integer(1800)),
+                (
+                    "tracker id",
+                    BencodeValue::ByteString(tracker_id.as_bytes().to_vec()),
+                ),
+                ("peers", BencodeValue::ByteString(Vec::new())),
+            ])
+            .encode();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+            if tx.send(params).is_err() {
+                return;
+            }
+        }
+    });
+    (url, rx)
+}
+
+fn tracker(url: String, stats: &Arc<TransferStats>) -> Tracker {
+    Tracker::new(url, INFO_HASH, PeerId::generate(), 6881, stats.clone())
+}
+
+fn event(params: &HashMap<String, String>) -> Option<&str> {
+    params.get("event").map(String::as_str)
+}
+
+#[test]
+fn reports_lifecycle_events_and_live_counters() {
+    let (url, announces) = mock_tracker("swarm-7");
+    let stats = Arc::new(TransferStats::new(LENGTH));
+    let mut tracker = tracker(url, &stats);
+
+    tracker.announce().unwrap();
+    let started = announces.recv().unwrap();
+    assert_eq!(event(&started), Some("started"));
+    assert_eq!(started["left"], LENGTH.to_string());
+    assert_eq!(started["downloaded"], "0");
+    assert_eq!(started["port"], "6881");
+
+    stats.add_downloaded(400);
+    stats.add_uploaded(150);
+    stats.piece_completed(400);
+    tracker.announce().unwrap();
+    let regular = announces.recv().unwrap();
+    assert_eq!(event(&regular), None);
+    assert_eq!(
+        (
+            regular["downloaded"].as_str(),
+            regular["uploaded"].as_str(),
+            regular["left"].as_str()
+        ),
+        ("400", "150", "600")
+    );
+
+    stats.piece_completed(600);
+    tracker.announce().unwrap();
+    assert_eq!(event(&announces.recv().unwrap()), Some("completed"));
+    // `completed` is sent once.
+    tracker.announce().unwrap();
+    assert_eq!(event(&announces.recv().unwrap()), None);
+
+    tracker.stop().unwrap();
+    let stopped = announces.recv().unwrap();
+    assert_eq!(event(&stopped), Some("stopped"));
+    assert_eq!(stopped["left"], "0");
+    // Stopping again is a no-op.
+    tracker.stop().unwrap();
+    assert!(announces.recv_timeout(Duration::from_millis(100)).is_err());
+}
+
+#[test]
+fn seeds_never_report_completed() {
+    let (url, announces) = mock_tracker("seed");
+    let stats = Arc::new(TransferStats::new(0));
+    let mut tracker = tracker(url, &stats);
+    tracker.announce().unwrap();
+    tracker.announce().unwrap();
+    let events: Vec<_> = announces
+        .try_iter()
+        .map(|params| params.get("event").cloned())
+        .collect();
+    assert_eq!(events, [Some("started".to_owned()), None]);
+}
+
+#[test]
+fn sends_numwant_key_and_ip_and_echoes_the_tracker_id() {
+    let (url, announces) = mock_tracker("abc 123");
+    let stats = Arc::new(TransferStats::new(LENGTH));
+    let ip: IpAddr = "2001:db8::7".parse().unwrap();
+    let mut tracker = tracker(url, &stats)
+        .with_numwant(25)
+        .with_key(0x0badcafe)
+        .with_ip(ip);
+
+    tracker.announce().unwrap();
+    let first = announces.recv().unwrap();
+    assert_eq!(first["numwant"], "25");
+    assert_eq!(first["key"], "0badcafe");
+    assert_eq!(first["ip"], ip.to_string());
+    assert_eq!(first["compact"], "1");
+    assert!(!first.contains_key("trackerid"));
+
+    tracker.announce().unwrap();
+    let second = announces.recv().unwrap();
+    assert_eq!(second["trackerid"], "abc 123");
+    assert_eq!(second["key"], first["key"], "the key stays fixed");
+}
+
+#[test]
+fn events_move_us_through_a_real_swarm() {
+    let server =
+        HttpTrackerServer::bind("127.0.0.1:0", PeerStore::new(Duration::from_secs(60))).unwrap();
+    let url = server.announce_url().unwrap();
+    server.spawn();
+    let swarm = || scrape(&url, &[INFO_HASH]).unwrap()[&INFO_HASH];
+
+    let stats = Arc::new(TransferStats::new(LENGTH));
+    let mut leecher = tracker(url.clone(), &stats);
+    leecher.announce().unwrap();
+    assert_eq!(
+        swarm(),
+        ScrapeStats {
+            complete: 0,
+            downloaded: 0,
+            incomplete: 1,
+        }
+    );
+
+    let seed_stats = Arc::new(TransferStats::new(0));
+    let mut seed = tracker(url.clone(), &seed_stats).with_numwant(10);
+    let response = seed.announce().unwrap();
+    assert_eq!(response.peers.len(), 1, "the seed is handed the leecher");
+    assert_eq!(response.peers[0].port, 6881);
+
+    stats.piece_completed(LENGTH);
+    leecher.announce().unwrap();
+    assert_eq!(
+        swarm(),
+        ScrapeStats {
+            complete: 2,
+            downloaded: 1,
+            incomplete: 0,
+        }
+    );
+
+    leecher.stop().unwrap();
+    seed.stop().unwrap();
+    assert_eq!(swarm().complete, 0);
+}
+
+#[test]
+fn shutdown_does_not_wait_for_an_unresponsive_tracker() {
+    // Bound but never read, so every announce retransmits until its timeout.
+    let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let url = format!("udp://{}", silent.local_addr().unwrap());
+    let stats = Arc::new(TransferStats::new(LENGTH));
+    let config = AnnounceConfig {
+        shutdown_timeout: Duration::from_millis(200),
+        ..AnnounceConfig::default()
+    };
+    let (announcer, _peers) = Announcer::spawn(tracker(url, &stats), config);
+    thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    announcer.shutdown();
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
@@ -0,0 +1,169 @@
+//! Fixtures shared by the integration tests: random torrent content, and a peer played by
+//! the test itself over a blocking socket.
+
+#![allow(dead_code)]
+
+use bittorrent_starter_rust::engine::{Engine, EngineError};
+use bittorrent_starter_rust::handshake::{Extensions, Handshake, HANDSHAKE_SIZE};
+use bittorrent_starter_rust::message::{read_message, write_message, MessageError, PeerMessage};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::pool::CandidatePool;
+use bittorrent_starter_rust::torrent::TorrentFileInfo;
+use bittorrent_starter_rust::utils::random_u64;
+use sha1::{Digest, Sha1};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const PIECE_LENGTH: usize = 32 * 1024;
+pub const BLOCK: u32 = 16 * 1024;
+/// How long a scripted peer waits for a message it expects before failing the test.
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `length` random bytes and the info dictionary describing them in `PIECE_LENGTH` pieces.
+pub fn content(length: usize) -> (TorrentFileInfo, Arc<Vec<u8>>) {
+    let data: Vec<u8> = (0..length).map(|_| random_u64() as u8).collect();
+    let pieces = data
+        .chunks(PIECE_LENGTH)
+        .flat_map(|piece| Sha1::digest(piece).to_vec())
+        .collect();
+    let info = TorrentFileInfo {
+        name: "content".to_owned(),
+        piece_length: PIECE_LENGTH as u64,
+        pieces,
+        length: length as u64,
+        private: None,
+    };
+    (info, Arc::new(data))
+}
+
+pub async fn download(engine: Engine, pool: CandidatePool) -> Result<Vec<u8>, EngineError> {
+    tokio::time::timeout(Duration::from_secs(20), engine.run(pool))
+        .await
+        .expect("download did not finish")
+}
+
+/// One connection to a peer whose every message is written by the test.
+pub struct ScriptedPeer {
+    stream: TcpStream,
+    data: Arc<Vec<u8>>,
+}
+
+/// Accepts one connection for `info`, answers its handshake with `extensions` and plays
+/// `script` on it, on a thread of its own.
+pub fn scripted_peer<T: Send + 'static>(
+    info: &TorrentFileInfo,
+    data: Arc<Vec<u8>>,
+    extensions: Extensions,
+    script: impl FnOnce(ScriptedPeer) -> T + Send + 'static,
+) -> (SocketAddr, JoinHandle<T>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = info.info_hash();
+    let handle = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        drop(listener);
+        let mut buffer = [0; HANDSHAKE_SIZE];
+        stream.read_exact(&mut buffer).unwrap();
+        let reply = Handshake::new(info_hash, PeerId::generate()).with_extensions(extensions);
+        stream.write_all(&reply.to_bytes()).unwrap();
+        script(ScriptedPeer { stream, data })
+    });
+    (addr, handle)
+}
+
+impl ScriptedPeer {
+    /// A second handle on the same connection, to write from another thread.
+    pub fn try_clone(&self) -> Self {
+        Self {
+            stream: self.stream.try_clone().unwrap(),
+            data: self.data.clone(),
+        }
+    }
+
+    pub fn send(&mut self, message: PeerMessage) {
+        write_message(&mut self.stream, &message).unwrap();
+    }
+
+    /// The next message within `timeout`, leaving out keep-alives and extended messages.
+    /// `None` if nothing came or the connection closed.
+    pub fn try_recv(&mut self, timeout: Duration) -> Option<PeerMessage> {
+        self.stream.set_read_timeout(Some(timeout)).unwrap();
+        loop {
+            match read_message(&mut self.stream).ok()? {
+                PeerMessage::KeepAlive | PeerMessage::Extended { .. } => continue,
+                message => return Some(message),
+            }
+        }
+    }
+
+    /// The next message, which must come within `EXPECT_TIMEOUT`.
+    pub fn recv(&mut self) -> PeerMessage {
+        self.try_recv(EXPECT_TIMEOUT)
+            .expect("no message from the client")
+    }
+
+    /// The next request within `timeout`, as `(index, begin, length)`, skipping any other
+    /// message.
+    pub fn try_request(&mut self, timeout: Duration) -> Option<(u32, u32, u32)> {
+        loop {
+            if let PeerMessage::Request {
+                index,
+                begin,
+                length,
+            } = self.try_recv(timeout)?
+            {
+                return Some((index, begin, length));
+            }
+        }
+    }
+
+    /// The next request, which must come within `EXPECT_TIMEOUT`.
+    pub fn request(&mut self) -> (u32, u32, u32) {
+        self.try_request(EXPECT_TIMEOUT)
+            .expect("no request from the client")
+    }
+
+    /// Answers a request with the matching block of the content. A connection closed in
+    /// the meantime is left for the next read to notice.
+    pub fn serve(&mut self, (index, begin, length): (u32, u32, u32)) {
+        let start = index as usize * PIECE_LENGTH + begin as usize;
+        let block = PeerMessage::Piece {
+            index,
+            begin,
+            block: self.data[start..start + length as usize].to_vec(),
+        };
+        let _ = write_message(&mut self.stream, &block);
+    }
+
+    /// Answers every request until the connection closes, which the engine does once the
+    /// download is complete, and returns them.
+    pub fn serve_all(&mut self) -> Vec<(u32, u32, u32)> {
+        let mut served = Vec::new();
+        while let Some(request) = self.try_request(EXPECT_TIMEOUT) {
+            self.serve(request);
+            served.push(request);
+        }
+        served
+    }
+
+    /// Whether the client closes the connection within `EXPECT_TIMEOUT`, whatever it sends
+    /// before.
+    pub fn closed(&mut self) -> bool {
+        self.stream.set_read_timeout(Some(EXPECT_TIMEOUT)).unwrap();
+        loop {
+            match read_message(&mut self.stream) {
+                Ok(_) => continue,
+                Err(MessageError::Io(err)) => {
+                    return matches!(
+                        err.kind(),
+                        ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset
+                    )
+                }
+                Err(_) => return false,
+            }
+        }
+    }
+}
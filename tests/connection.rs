@@ -0,0 +1,200 @@
+//! The handshake and message codecs, and framed peer connections over loopback.
+
+use bittorrent_starter_rust::connection::{
+    ConnectionError, HandshakeCodec, MessageCodec, PeerConnection,
+};
+use bittorrent_starter_rust::handshake::{Extensions, Handshake, HandshakeError};
+use bittorrent_starter_rust::message::{MessageError, PeerMessage};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bytes::BytesMut;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::sync::CancellationToken;
+
+const INFO_HASH: [u8; 20] = [0x38; 20];
+
+fn handshake() -> Handshake {
+    Handshake::new(INFO_HASH, PeerId::generate()).with_extensions(Extensions {
+        fast: true,
+        ..Extensions::default()
+    })
+}
+
+async fn pair() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let outgoing = TcpStream::connect(listener.local_addr().unwrap());
+    let (outgoing, incoming) = tokio::join!(outgoing, listener.accept());
+    (outgoing.unwrap(), incoming.unwrap().0)
+}
+
+#[test]
+fn handshake_codec_waits_for_all_68_bytes() {
+    let local = handshake();
+    let mut wire = BytesMut::new();
+    HandshakeCodec.encode(local, &mut wire).unwrap();
+    assert_eq!(wire.len(), 68);
+    // Whatever follows the handshake stays buffered for the message codec.
+    wire.extend_from_slice(&PeerMessage::Unchoke.to_bytes());
+
+    let mut buf = BytesMut::from(&wire[..67]);
+    assert!(HandshakeCodec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(&wire[67..]);
+    assert_eq!(HandshakeCodec.decode(&mut buf).unwrap(), Some(local));
+    assert_eq!(
+        MessageCodec::default().decode(&mut buf).unwrap(),
+        Some(PeerMessage::Unchoke)
+    );
+}
+
+#[test]
+fn message_codec_frames_split_input_and_enforces_its_limit() {
+    let mut codec = MessageCodec::new(16);
+    let mut wire = BytesMut::new();
+    for message in [PeerMessage::Have { index: 1 }, PeerMessage::KeepAlive] {
+        codec.encode(message, &mut wire).unwrap();
+    }
+    let mut buf = BytesMut::from(&wire[..6]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(&wire[6..]);
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(PeerMessage::Have { index: 1 })
+    );
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(PeerMessage::KeepAlive)
+    );
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+
+    let mut oversized = BytesMut::new();
+    PeerMessage::Bitfield(vec![0; 16]).encode(&mut oversized);
+    assert!(matches!(
+        codec.decode(&mut oversized),
+        Err(MessageError::TooLong { len: 17, max: 16 })
+    ));
+}
+
+#[tokio::test]
+async fn establish_and_accept_negotiate_and_exchange_messages() {
+    let (outgoing, incoming) = pair().await;
+    let (local, remote) = (
+        handshake(),
+        handshake().with_extensions(Extensions::default()),
+    );
+    let cancel = CancellationToken::new();
+    let (dialer, acceptor) = tokio::join!(
+        PeerConnection::establish(outgoing, local, cancel.clone()),
+        PeerConnection::accept(incoming, |_| Some(remote), cancel.clone()),
+    );
+    let (mut dialer, acceptor) = (dialer.unwrap(), acceptor.unwrap());
+    assert_eq!(dialer.handshake().peer_id.0, remote.peer_id);
+    assert_eq!(acceptor.handshake().peer_id.0, local.peer_id);
+    assert!(
+        !dialer.handshake().extensions.fast,
+        "only extensions both sides set count"
+    );
+
+    let (mut reader, mut writer) = acceptor.split();
+    writer.feed(PeerMessage::Interested).await.unwrap();
+    writer.feed(PeerMessage::Have { index: 3 }).await.unwrap();
+    writer.flush().await.unwrap();
+    assert_eq!(dialer.recv().await.unwrap(), Some(PeerMessage::Interested));
+    assert_eq!(
+        dialer.recv().await.unwrap(),
+        Some(PeerMessage::Have { index: 3 })
+    );
+    dialer.send(PeerMessage::Unchoke).await.unwrap();
+    assert_eq!(reader.recv().await.unwrap(), Some(PeerMessage::Unchoke));
+    writer.close().await.unwrap();
+    assert_eq!(dialer.recv().await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn keeps_messages_sent_right_behind_the_handshake() {
+    let (outgoing, mut incoming) = pair().await;
+    let mut remote = handshake().to_bytes().to_vec();
+    remote.extend(PeerMessage::HaveAll.to_bytes());
+    incoming.write_all(&remote).await.unwrap();
+    let mut connection = PeerConnection::establish(outgoing, handshake(), CancellationToken::new())
+        .await
+        .unwrap();
+    assert_eq!(connection.recv().await.unwrap(), Some(PeerMessage::HaveAll));
+}
+
+#[tokio::test]
+async fn refuses_mismatched_and_unknown_torrents() {
+    let (outgoing, mut incoming) = pair().await;
+    let other = Handshake::new([0; 20], PeerId::generate());
+    incoming.write_all(&other.to_bytes()).await.unwrap();
+    let result = PeerConnection::establish(outgoing, handshake(), CancellationToken::new()).await;
+    assert!(matches!(
+        result,
+        Err(ConnectionError::Handshake(HandshakeError::InfoHashMismatch))
+    ));
+
+    let (mut outgoing, incoming) = pair().await;
+    outgoing.write_all(&other.to_bytes()).await.unwrap();
+    let result = PeerConnection::accept(
+        incoming,
+        |info_hash| (info_hash == INFO_HASH).then(handshake),
+        CancellationToken::new(),
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(ConnectionError::Handshake(HandshakeError::UnknownTorrent))
+    ));
+}
+
+#[tokio::test]
+async fn reports_a_peer_closing_during_the_handshake() {
+    let (outgoing, incoming) = pair().await;
+    drop(incoming);
+    let result = PeerConnection::establish(outgoing, handshake(), CancellationToken::new()).await;
+    assert!(
+        matches!(
+            result,
+            Err(ConnectionError::HandshakeClosed | ConnectionError::Io(_))
+        ),
+        "{:?}",
+        result.err()
+    );
+}
+
+#[tokio::test]
+async fn cancellation_ends_pending_operations() {
+    let (outgoing, incoming) = pair().await;
+    let cancel = CancellationToken::new();
+    let silent = tokio::spawn(PeerConnection::establish(
+        outgoing,
+        handshake(),
+        cancel.clone(),
+    ));
+    tokio::task::yield_now().await;
+    cancel.cancel();
+    assert!(matches!(
+        silent.await.unwrap(),
+        Err(ConnectionError::Cancelled)
+    ));
+    drop(incoming);
+
+    let (outgoing, incoming) = pair().await;
+    let cancel = CancellationToken::new();
+    let remote = handshake();
+    let (dialer, _acceptor) = tokio::join!(
+        PeerConnection::establish(outgoing, handshake(), cancel.clone()),
+        PeerConnection::accept(incoming, |_| Some(remote), CancellationToken::new()),
+    );
+    let (mut reader, mut writer) = dialer.unwrap().split();
+    let waiting = tokio::spawn(async move { reader.recv().await });
+    cancel.cancel();
+    assert!(matches!(
+        waiting.await.unwrap(),
+        Err(ConnectionError::Cancelled)
+    ));
+    assert!(matches!(
+        writer.send(PeerMessage::Choke).await,
+        Err(ConnectionError::Cancelled)
+    ));
+}
@@ -0,0 +1,114 @@
+//! A small DHT network on loopback.
+
+use bittorrent_starter_rust::dht::{Dht, DhtConfig, DhtError, NodeId, RoutingTable};
+use bittorrent_starter_rust::proxy::ProxyConfig;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn config() -> DhtConfig {
+    DhtConfig {
+        bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+        routers: Vec::new(),
+        query_timeout: Duration::from_millis(500),
+        state_path: None,
+        proxy: ProxyConfig::direct(),
+    }
+}
+
+fn bootstrap_node(dht: &Dht) -> (String, u16) {
+    let addr = dht.local_addr().unwrap();
+    (addr.ip().to_string(), addr.port())
+}
+
+/// Starts `count` nodes that all bootstrap from the first one.
+fn network(count: usize) -> Vec<Dht> {
+    let nodes: Vec<Dht> = (0..count).map(|_| Dht::start(config()).unwrap()).collect();
+    let seed = bootstrap_node(&nodes[0]);
+    for node in &nodes[1..] {
+        assert!(node.bootstrap(std::slice::from_ref(&seed)) > 0);
+    }
+    nodes
+}
+
+#[test]
+fn ping_and_find_node() {
+    let nodes = network(2);
+    let addr = nodes[0].local_addr().unwrap();
+    assert_eq!(nodes[1].ping(addr).unwrap(), nodes[0].id());
+    let found = nodes[0]
+        .find_node(nodes[1].local_addr().unwrap(), NodeId::generate())
+        .unwrap();
+    assert!(found.iter().any(|node| node.id == nodes[0].id()));
+}
+
+#[test]
+fn bootstrap_spreads_routing_tables() {
+    let nodes = network(12);
+    // Late joiners learn about each other through the seed's answers.
+    let last = nodes.last().unwrap();
+    assert!(
+        last.node_count() >= 8,
+        "only {} nodes known",
+        last.node_count()
+    );
+}
+
+#[test]
+fn announced_peer_is_found_by_another_node() {
+    let nodes = network(10);
+    let info_hash = [0x42; 20];
+    nodes[3].announce(info_hash, 51413);
+    let peers = nodes[8].lookup_peers(info_hash);
+    let addrs: Vec<String> = peers.iter().map(ToString::to_string).collect();
+    assert!(addrs.contains(&"127.0.0.1:51413".to_owned()), "{:?}", addrs);
+}
+
+#[test]
+fn announce_needs_a_valid_token() {
+    let nodes = network(2);
+    let addr = nodes[0].local_addr().unwrap();
+    let info_hash = [7; 20];
+    let result = nodes[1].announce_peer(addr, info_hash, 6881, b"forged");
+    assert!(matches!(result, Err(DhtError::Remote { code: 203, .. })));
+    let token = nodes[1].get_peers(addr, info_hash).unwrap().token.unwrap();
+    nodes[1]
+        .announce_peer(addr, info_hash, 6881, &token)
+        .unwrap();
+    let reply = nodes[1].get_peers(addr, info_hash).unwrap();
+    assert_eq!(reply.peers.len(), 1);
+}
+
+#[test]
+fn unanswered_queries_time_out() {
+    let node = Dht::start(config()).unwrap();
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let result = node.ping(silent.local_addr().unwrap());
+    assert!(matches!(result, Err(DhtError::Timeout)));
+}
+
+#[test]
+fn routing_table_survives_restart() {
+    let nodes = network(4);
+    let path = std::env::temp_dir().join(format!("dht-state-{}", NodeId::generate()));
+    let node = Dht::start(DhtConfig {
+        state_path: Some(path.clone()),
+        ..config()
+    })
+    .unwrap();
+    node.bootstrap(&[bootstrap_node(&nodes[0])]);
+    let (id, count) = (node.id(), node.node_count());
+    node.shutdown().unwrap();
+
+    let table = RoutingTable::load(&path).unwrap();
+    assert_eq!(table.id(), id);
+    assert_eq!(table.len(), count);
+    let restarted = Dht::start(DhtConfig {
+        state_path: Some(path.clone()),
+        ..config()
+    })
+    .unwrap();
+    assert_eq!(restarted.id(), id);
+    assert_eq!(restarted.node_count(), count);
+    drop(restarted);
+    std::fs::remove_file(path).unwrap();
+}
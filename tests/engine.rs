@@ -0,0 +1,335 @@
+//! The multi-peer engine against local seeders that misbehave in different ways.
+
+mod common;
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::engine::{Engine, EngineConfig, EngineError};
+use bittorrent_starter_rust::handshake::{Handshake, HANDSHAKE_SIZE};
+use bittorrent_starter_rust::message::{read_message, write_message, PeerMessage};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::pool::{CandidatePool, PeerSource};
+use bittorrent_starter_rust::torrent::TorrentFileInfo;
+use bittorrent_starter_rust::tracker::TransferStats;
+use common::{content, download, PIECE_LENGTH};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone)]
+enum Behavior {
+    Serve,
+    /// Closes the connection after sending this many blocks.
+    DieAfter(usize),
+    /// Chokes us for good after this many blocks.
+    ChokeAfter(usize),
+    /// Never answers requests, and reports the `(index, begin)` of every cancel.
+    Stall(mpsc::Sender<(u32, u32)>),
+    /// Sends every block with its bytes flipped.
+    Corrupt,
+    /// Sends this many blocks with their bytes flipped, then closes the connection.
+    CorruptThenDie(usize),
+    /// Closes the first connection after sending this many blocks, and serves a second one.
+    DropOnceAfter(usize),
+}
+
+/// Serves `data` to one connection, announcing only the pieces in `has`.
+fn seeder(
+    info: &TorrentFileInfo,
+    data: Arc<Vec<u8>>,
+    has: Bitfield,
+    behavior: Behavior,
+) -> SocketAddr {
+    seeder_at("127.0.0.1", info, data, has, behavior)
+}
+
+fn seeder_at(
+    ip: &str,
+    info: &TorrentFileInfo,
+    data: Arc<Vec<u8>>,
+    has: Bitfield,
+    behavior: Behavior,
+) -> SocketAddr {
+    let listener = TcpListener::bind((ip, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let info_hash = info.info_hash();
+    thread::spawn(move || {
+        // Only a peer that drops once is there for a second connection.
+        let connections = match behavior {
+            Behavior::DropOnceAfter(_) => 2,
+            _ => 1,
+        };
+        for (number, stream) in listener.incoming().take(connections).enumerate() {
+            let behavior = match behavior {
+                Behavior::DropOnceAfter(blocks) if number == 0 => Behavior::DieAfter(blocks),
+                Behavior::DropOnceAfter(_) => Behavior::Serve,
+                ref behavior => behavior.clone(),
+            };
+            serve(stream.unwrap(), info_hash, &data, &has, behavior);
+        }
+    });
+    addr
+}
+
+fn serve(
+    mut stream: TcpStream,
+    info_hash: [u8; 20],
+    data: &[u8],
+    has: &Bitfield,
+    behavior: Behavior,
+) {
+    let mut buffer = [0; HANDSHAKE_SIZE];
+    stream.read_exact(&mut buffer).unwrap();
+    let reply = Handshake::new(info_hash, PeerId::generate());
+    stream.write_all(&reply.to_bytes()).unwrap();
+    write_message(&mut stream, &PeerMessage::Bitfield(has.as_bytes().to_vec())).unwrap();
+    write_message(&mut stream, &PeerMessage::Unchoke).unwrap();
+    let mut sent = 0;
+    let mut choked = false;
+    while let Ok(message) = read_message(&mut stream) {
+        if let (Behavior::Stall(cancels), PeerMessage::Cancel { index, begin, .. }) =
+            (&behavior, &message)
+        {
+            let _ = cancels.send((*index, *begin));
+        }
+        let PeerMessage::Request {
+            index,
+            begin,
+            length,
+        } = message
+        else {
+            continue;
+        };
+        if choked {
+            continue;
+        }
+        match behavior {
+            Behavior::DieAfter(blocks) | Behavior::CorruptThenDie(blocks) if sent == blocks => {
+                return
+            }
+            Behavior::Stall(_) => continue,
+            Behavior::ChokeAfter(blocks) if sent == blocks => {
+                write_message(&mut stream, &PeerMessage::Choke).unwrap();
+                choked = true;
+                continue;
+            }
+            _ => {}
+        }
+        let start = index as usize * PIECE_LENGTH + begin as usize;
+        let mut block = data[start..start + length as usize].to_vec();
+        if let Behavior::Corrupt | Behavior::CorruptThenDie(_) = behavior {
+            block.iter_mut().for_each(|byte| *byte = !*byte);
+        }
+        let block = PeerMessage::Piece {
+            index,
+            begin,
+            block,
+        };
+        if write_message(&mut stream, &block).is_err() {
+            return;
+        }
+        sent += 1;
+    }
+}
+
+#[tokio::test]
+async fn survives_dead_and_choking_peers() {
+    let (info, data) = content(PIECE_LENGTH * 8 + 1000);
+    let all = Bitfield::full(info.piece_count());
+    let pool = CandidatePool::new();
+    let peers = [
+        seeder(&info, data.clone(), all.clone(), Behavior::DieAfter(3)),
+        seeder(&info, data.clone(), all.clone(), Behavior::ChokeAfter(2)),
+        seeder(&info, data.clone(), all.clone(), Behavior::Serve),
+    ];
+    pool.add(peers, PeerSource::Tracker);
+    let stats = Arc::new(TransferStats::new(info.length));
+    let engine = Engine::new(info, stats.clone());
+    let downloaded = download(engine, pool.clone()).await.unwrap();
+    assert_eq!(downloaded, *data);
+    assert_eq!(stats.left(), 0);
+    assert!(pool.connected_peers().is_empty());
+}
+
+#[tokio::test]
+async fn reconnects_to_a_peer_that_dropped() {
+    let (info, data) = content(PIECE_LENGTH * 4);
+    let all = Bitfield::full(info.piece_count());
+    let pool = CandidatePool::new();
+    let peer = seeder(&info, data.clone(), all, Behavior::DropOnceAfter(2));
+    pool.add([peer], PeerSource::Tracker);
+    let engine = Engine::new(info.clone(), Arc::new(TransferStats::new(info.length)));
+    let run = tokio::spawn(download(engine, pool.clone()));
+    // The next announce names it again, which only queues it once the drop was noticed.
+    let requeued = async {
+        while pool.add([peer], PeerSource::Tracker) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), requeued)
+        .await
+        .expect("the dropped peer was never queued again");
+    assert_eq!(run.await.unwrap().unwrap(), *data);
+}
+
+#[tokio::test]
+async fn combines_pieces_from_partial_peers() {
+    let (info, data) = content(PIECE_LENGTH * 6);
+    let mut even = Bitfield::new(info.piece_count());
+    let mut odd = Bitfield::new(info.piece_count());
+    for index in 0..info.piece_count() {
+        if index % 2 == 0 {
+            even.set(index);
+        } else {
+            odd.set(index);
+        }
+    }
+    let pool = CandidatePool::new();
+    let peers = [
+        seeder(&info, data.clone(), even, Behavior::Serve),
+        seeder(&info, data.clone(), odd, Behavior::Serve),
+    ];
+    pool.add(peers, PeerSource::Tracker);
+    let engine = Engine::new(info.clone(), Arc::new(TransferStats::new(info.length)));
+    assert_eq!(download(engine, pool).await.unwrap(), *data);
+}
+
+#[tokio::test]
+async fn fetches_only_selected_pieces() {
+    let (info, data) = content(PIECE_LENGTH * 4 + 10);
+    let pool = CandidatePool::new();
+    let all = Bitfield::full(info.piece_count());
+    pool.add(
+        [seeder(&info, data.clone(), all, Behavior::Serve)],
+        PeerSource::Tracker,
+    );
+    let engine =
+        Engine::new(info.clone(), Arc::new(TransferStats::new(info.length))).with_pieces([4]);
+    let piece = download(engine, pool).await.unwrap();
+    assert_eq!(piece, data[PIECE_LENGTH * 4..]);
+}
+
+#[tokio::test]
+async fn endgame_finishes_blocks_held_by_a_stalled_peer() {
+    let (info, data) = content(PIECE_LENGTH * 2);
+    let all = Bitfield::full(info.piece_count());
+    let (cancels, cancelled) = mpsc::channel();
+    let pool = CandidatePool::new();
+    // The stalled peer comes first, so it is asked for blocks it will never send.
+    let stalled = seeder(&info, data.clone(), all.clone(), Behavior::Stall(cancels));
+    pool.add([stalled], PeerSource::Tracker);
+    let stats = Arc::new(TransferStats::new(info.length));
+    let engine = Engine::new(info.clone(), stats.clone());
+    let run = tokio::spawn(download(engine, pool.clone()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    pool.add(
+        [seeder(&info, data.clone(), all, Behavior::Serve)],
+        PeerSource::Tracker,
+    );
+    // Without endgame this would wait for the stalled peer to time out.
+    assert_eq!(run.await.unwrap().unwrap(), *data);
+    let (index, begin) = cancelled.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(index < 2 && begin % (16 * 1024) == 0);
+    assert_eq!(stats.redundant(), 0);
+}
+
+#[tokio::test]
+async fn bans_a_peer_sending_corrupt_pieces() {
+    let (info, data) = content(PIECE_LENGTH * 6);
+    let all = Bitfield::full(info.piece_count());
+    let pool = CandidatePool::new();
+    // Bans are per address, so the corrupt peer needs one of its own.
+    let corrupt = seeder_at(
+        "127.0.0.2",
+        &info,
+        data.clone(),
+        all.clone(),
+        Behavior::Corrupt,
+    );
+    pool.add([corrupt], PeerSource::Tracker);
+    let engine = Engine::new(info.clone(), Arc::new(TransferStats::new(info.length))).with_config(
+        EngineConfig {
+            max_hash_failures: 2,
+            ..EngineConfig::default()
+        },
+    );
+    let run = tokio::spawn(download(engine, pool.clone()));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    let honest = seeder(&info, data.clone(), all, Behavior::Serve);
+    pool.add([honest], PeerSource::Tracker);
+    assert_eq!(run.await.unwrap().unwrap(), *data);
+    assert!(pool.is_banned(corrupt.ip()));
+    assert!(!pool.is_banned(honest.ip()));
+    // Nothing else from that address is queued either.
+    let other_port = SocketAddr::new(corrupt.ip(), corrupt.port().wrapping_add(1));
+    assert_eq!(pool.add([other_port], PeerSource::Pex), 0);
+}
+
+/// Waits until blocks worth `bytes` have arrived, whether or not they were any good.
+async fn received(stats: &TransferStats, bytes: u64) {
+    while stats.downloaded() < bytes {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn blames_only_the_contributor_of_the_corrupt_block() {
+    // One piece of two blocks, the first from an honest peer, the second from a corrupt one.
+    let (info, data) = content(PIECE_LENGTH);
+    let all = Bitfield::full(1);
+    let pool = CandidatePool::new();
+    let honest = seeder_at(
+        "127.0.0.2",
+        &info,
+        data.clone(),
+        all.clone(),
+        Behavior::ChokeAfter(1),
+    );
+    pool.add([honest], PeerSource::Tracker);
+    let stats = Arc::new(TransferStats::new(info.length));
+    let engine = Engine::new(info.clone(), stats.clone()).with_config(EngineConfig {
+        max_hash_failures: 1,
+        ..EngineConfig::default()
+    });
+    let run = tokio::spawn(download(engine, pool.clone()));
+    received(&stats, PIECE_LENGTH as u64 / 2).await;
+    let corrupt = seeder_at(
+        "127.0.0.3",
+        &info,
+        data.clone(),
+        all.clone(),
+        Behavior::CorruptThenDie(1),
+    );
+    pool.add([corrupt], PeerSource::Tracker);
+    received(&stats, PIECE_LENGTH as u64).await;
+    // With two contributors the failure alone blames nobody; the retry tells them apart.
+    assert!(!pool.is_banned(corrupt.ip()));
+    pool.add(
+        [seeder(&info, data.clone(), all, Behavior::Serve)],
+        PeerSource::Tracker,
+    );
+    assert_eq!(run.await.unwrap().unwrap(), *data);
+    assert!(pool.is_banned(corrupt.ip()));
+    assert!(!pool.is_banned(honest.ip()));
+}
+
+#[tokio::test]
+async fn stalls_without_peers() {
+    let (info, _) = content(PIECE_LENGTH);
+    let pool = CandidatePool::new();
+    // Nothing listens here once the listener is gone.
+    let closed = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    pool.add([closed], PeerSource::Tracker);
+    let engine = Engine::new(info.clone(), Arc::new(TransferStats::new(info.length))).with_config(
+        EngineConfig {
+            stall_timeout: Duration::from_millis(200),
+            ..EngineConfig::default()
+        },
+    );
+    let result = download(engine, pool).await;
+    assert!(matches!(result, Err(EngineError::Stalled(_))));
+}
@@ -0,0 +1,131 @@
+//! The BEP 10 extended handshake and per-connection routing of extension messages.
+
+use bittorrent_starter_rust::connection::PeerConnection;
+use bittorrent_starter_rust::extension::{
+    ExtendedHandshake, Extension, ExtensionError, ExtensionRegistry, HANDSHAKE_ID,
+};
+use bittorrent_starter_rust::handshake::{Extensions, Handshake};
+use bittorrent_starter_rust::message::PeerMessage;
+use bittorrent_starter_rust::peer_id::PeerId;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv6Addr};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
+
+/// Answers every payload with the same bytes reversed.
+struct Reverse;
+
+impl Extension for Reverse {
+    fn name(&self) -> &'static str {
+        "x_reverse"
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, ExtensionError> {
+        Ok(vec![payload.iter().rev().copied().collect()])
+    }
+}
+
+fn remote_handshake(m: &[(&str, u8)]) -> Vec<u8> {
+    ExtendedHandshake {
+        m: m.iter().map(|(name, id)| (name.to_string(), *id)).collect(),
+        ..ExtendedHandshake::default()
+    }
+    .to_bytes()
+}
+
+#[test]
+fn extended_handshake_round_trips() {
+    let handshake = ExtendedHandshake {
+        m: BTreeMap::from([("ut_metadata".to_owned(), 3), ("ut_pex".to_owned(), 1)]),
+        v: Some("test/1.0".to_owned()),
+        p: Some(6881),
+        reqq: Some(500),
+        yourip: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+        metadata_size: Some(31_235),
+    };
+    assert_eq!(
+        ExtendedHandshake::from_bytes(&handshake.to_bytes()).unwrap(),
+        handshake
+    );
+    let minimal = ExtendedHandshake::default();
+    assert_eq!(minimal.to_bytes(), b"d1:mdee");
+    assert_eq!(ExtendedHandshake::from_bytes(b"de").unwrap(), minimal);
+}
+
+#[test]
+fn drops_disabled_and_out_of_range_entries() {
+    let handshake =
+        ExtendedHandshake::from_bytes(b"d1:md1:ai0e1:bi300e1:ci2ee1:pi0e6:yourip3:abce").unwrap();
+    assert_eq!(handshake.m, BTreeMap::from([("c".to_owned(), 2)]));
+    assert_eq!((handshake.p, handshake.yourip), (None, None));
+    assert!(matches!(
+        ExtendedHandshake::from_bytes(b"i1e"),
+        Err(ExtensionError::Malformed(_))
+    ));
+}
+
+#[test]
+fn routes_messages_under_the_ids_each_side_assigned() {
+    let mut registry = ExtensionRegistry::new()
+        .with(Reverse)
+        .with_listen_port(7000);
+    let local = registry.local_handshake(None);
+    assert_eq!(local.m, BTreeMap::from([("x_reverse".to_owned(), 1)]));
+    assert_eq!(local.p, Some(7000));
+    assert!(registry.message("x_reverse", vec![1]).is_none());
+
+    registry
+        .handle(HANDSHAKE_ID, &remote_handshake(&[("x_reverse", 9)]))
+        .unwrap();
+    assert!(registry.supports("x_reverse"));
+    assert_eq!(
+        registry.handle(1, b"abc").unwrap(),
+        [PeerMessage::Extended {
+            id: 9,
+            payload: b"cba".to_vec()
+        }]
+    );
+}
+
+#[test]
+fn ignores_ids_we_never_assigned() {
+    let mut registry = ExtensionRegistry::new().with(Reverse);
+    registry
+        .handle(HANDSHAKE_ID, &remote_handshake(&[("x_reverse", 2)]))
+        .unwrap();
+    assert!(registry.handle(7, b"junk").unwrap().is_empty());
+    assert!(registry.handle(255, b"").unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn connections_survive_unknown_extended_messages() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let outgoing = TcpStream::connect(listener.local_addr().unwrap());
+    let (outgoing, incoming) = tokio::join!(outgoing, listener.accept());
+    let handshake = || {
+        Handshake::new([0x40; 20], PeerId::generate()).with_extensions(Extensions {
+            extension_protocol: true,
+            ..Extensions::default()
+        })
+    };
+    let cancel = CancellationToken::new();
+    let remote = handshake();
+    let (ours, theirs) = tokio::join!(
+        PeerConnection::establish(outgoing.unwrap(), handshake(), cancel.clone()),
+        PeerConnection::accept(incoming.unwrap().0, |_| Some(remote), cancel.clone()),
+    );
+    let (mut ours, mut theirs) = (ours.unwrap(), theirs.unwrap());
+    ours.start_extensions(ExtensionRegistry::new().with(Reverse))
+        .await
+        .unwrap();
+
+    theirs
+        .send(PeerMessage::Extended {
+            id: 42,
+            payload: b"unsolicited".to_vec(),
+        })
+        .await
+        .unwrap();
+    theirs.send(PeerMessage::Unchoke).await.unwrap();
+    assert_eq!(ours.recv().await.unwrap(), Some(PeerMessage::Unchoke));
+}
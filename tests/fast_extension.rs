@@ -0,0 +1,183 @@
+//! The fast extension (BEP 6) as the engine handles it, against peers scripted by the test.
+
+mod common;
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::engine::{Engine, EngineConfig, EngineError};
+use bittorrent_starter_rust::handshake::Extensions;
+use bittorrent_starter_rust::message::PeerMessage;
+use bittorrent_starter_rust::pool::{CandidatePool, PeerSource};
+use bittorrent_starter_rust::torrent::TorrentFileInfo;
+use bittorrent_starter_rust::tracker::TransferStats;
+use common::{content, download, scripted_peer, ScriptedPeer, PIECE_LENGTH};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const FAST: Extensions = Extensions {
+    extension_protocol: false,
+    fast: true,
+    dht: false,
+};
+/// Long enough for the engine to send everything its pipeline allows.
+const SETTLE: Duration = Duration::from_millis(200);
+
+fn engine(info: &TorrentFileInfo) -> Engine {
+    Engine::new(info.clone(), Arc::new(TransferStats::new(info.length)))
+}
+
+fn pool(peers: impl IntoIterator<Item = SocketAddr>) -> CandidatePool {
+    let pool = CandidatePool::new();
+    pool.add(peers, PeerSource::Tracker);
+    pool
+}
+
+/// Every request the engine sends before it waits for answers.
+fn in_flight(peer: &mut ScriptedPeer) -> Vec<(u32, u32, u32)> {
+    let mut requests = vec![peer.request()];
+    while let Some(request) = peer.try_request(SETTLE) {
+        requests.push(request);
+    }
+    requests
+}
+
+fn reject((index, begin, length): (u32, u32, u32)) -> PeerMessage {
+    PeerMessage::RejectRequest {
+        index,
+        begin,
+        length,
+    }
+}
+
+#[tokio::test]
+async fn have_all_stands_in_for_a_bitfield() {
+    let (info, data) = content(PIECE_LENGTH * 3);
+    let (addr, peer) = scripted_peer(&info, data.clone(), FAST, |mut peer| {
+        // Having nothing yet is said in one message too.
+        assert_eq!(peer.recv(), PeerMessage::HaveNone);
+        peer.send(PeerMessage::HaveAll);
+        assert_eq!(peer.recv(), PeerMessage::Interested);
+        peer.send(PeerMessage::Unchoke);
+        peer.serve_all();
+    });
+    assert_eq!(download(engine(&info), pool([addr])).await.unwrap(), *data);
+    peer.join().unwrap();
+}
+
+#[tokio::test]
+async fn rejected_blocks_are_requested_again() {
+    let (info, data) = content(PIECE_LENGTH * 2);
+    let (addr, peer) = scripted_peer(&info, data.clone(), FAST, |mut peer| {
+        peer.send(PeerMessage::HaveAll);
+        peer.send(PeerMessage::Unchoke);
+        let pending = in_flight(&mut peer);
+        // With the fast extension a choke leaves requests pending until they are rejected.
+        peer.send(PeerMessage::Choke);
+        for request in &pending {
+            peer.send(reject(*request));
+        }
+        peer.send(PeerMessage::Unchoke);
+        let served = peer.serve_all();
+        assert!(pending.iter().all(|request| served.contains(request)));
+    });
+    assert_eq!(download(engine(&info), pool([addr])).await.unwrap(), *data);
+    peer.join().unwrap();
+}
+
+#[tokio::test]
+async fn rejects_while_unchoked_leave_the_piece_to_others() {
+    let (info, data) = content(PIECE_LENGTH * 2);
+    let (rejecting, mut rejected) = tokio::sync::mpsc::unbounded_channel();
+    let (refusing, refuser) = scripted_peer(&info, data.clone(), FAST, move |mut peer| {
+        peer.send(PeerMessage::HaveAll);
+        peer.send(PeerMessage::Unchoke);
+        let mut asked = Vec::new();
+        while let Some(request) = peer.try_request(Duration::from_secs(5)) {
+            peer.send(reject(request));
+            asked.push(request);
+            let _ = rejecting.send(());
+        }
+        asked
+    });
+    let pool = pool([refusing]);
+    let run = tokio::spawn(download(engine(&info), pool.clone()));
+    rejected.recv().await.unwrap();
+    let (serving, server) = scripted_peer(&info, data.clone(), FAST, |mut peer| {
+        peer.send(PeerMessage::HaveAll);
+        peer.send(PeerMessage::Unchoke);
+        peer.serve_all();
+    });
+    pool.add([serving], PeerSource::Tracker);
+    assert_eq!(run.await.unwrap().unwrap(), *data);
+    server.join().unwrap();
+    // Pieces the peer refused while unchoked are never asked of it again.
+    let asked = refuser.join().unwrap();
+    let unique: HashSet<_> = asked.iter().collect();
+    assert_eq!(unique.len(), asked.len());
+}
+
+#[tokio::test]
+async fn allowed_fast_pieces_download_while_choked() {
+    let (info, data) = content(PIECE_LENGTH * 3);
+    let (addr, peer) = scripted_peer(&info, data.clone(), FAST, |mut peer| {
+        peer.send(PeerMessage::AllowedFast { index: 1 });
+        peer.send(PeerMessage::HaveAll);
+        peer.serve_all()
+    });
+    let engine = engine(&info).with_pieces([1]);
+    let piece = download(engine, pool([addr])).await.unwrap();
+    assert_eq!(piece, data[PIECE_LENGTH..PIECE_LENGTH * 2]);
+    let served = peer.join().unwrap();
+    assert!(served.iter().all(|(index, _, _)| *index == 1));
+}
+
+#[tokio::test]
+async fn suggested_pieces_come_first() {
+    let (info, data) = content(PIECE_LENGTH * 8);
+    let (addr, peer) = scripted_peer(&info, data.clone(), FAST, |mut peer| {
+        peer.send(PeerMessage::HaveAll);
+        peer.send(PeerMessage::SuggestPiece { index: 5 });
+        peer.send(PeerMessage::Unchoke);
+        let first = peer.request();
+        peer.serve(first);
+        peer.serve_all();
+        first
+    });
+    assert_eq!(download(engine(&info), pool([addr])).await.unwrap(), *data);
+    // Without the suggestion the first piece is a random one.
+    assert_eq!(peer.join().unwrap().0, 5);
+}
+
+#[tokio::test]
+async fn fast_messages_need_negotiating() {
+    let (info, data) = content(PIECE_LENGTH);
+    let (addr, peer) = scripted_peer(&info, data, Extensions::default(), |mut peer| {
+        peer.send(PeerMessage::HaveAll);
+        peer.closed()
+    });
+    let engine = engine(&info).with_config(EngineConfig {
+        stall_timeout: Duration::from_millis(300),
+        ..EngineConfig::default()
+    });
+    let result = download(engine, pool([addr])).await;
+    assert!(matches!(result, Err(EngineError::Stalled(_))));
+    assert!(peer.join().unwrap(), "the connection stayed open");
+}
+
+#[tokio::test]
+async fn choke_without_fast_extension_drops_requests() {
+    let (info, data) = content(PIECE_LENGTH * 2);
+    let bitfield = Bitfield::full(info.piece_count()).as_bytes().to_vec();
+    let (addr, peer) = scripted_peer(&info, data.clone(), Extensions::default(), |mut peer| {
+        peer.send(PeerMessage::Bitfield(bitfield));
+        peer.send(PeerMessage::Unchoke);
+        let dropped = in_flight(&mut peer);
+        peer.send(PeerMessage::Choke);
+        peer.send(PeerMessage::Unchoke);
+        let served = peer.serve_all();
+        assert!(dropped.iter().all(|request| served.contains(request)));
+    });
+    assert_eq!(download(engine(&info), pool([addr])).await.unwrap(), *data);
+    peer.join().unwrap();
+}
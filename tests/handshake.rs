@@ -0,0 +1,135 @@
+//! Handshake layout, reserved-bit negotiation and validation of the peer's reply.
+
+use bittorrent_starter_rust::handshake::{
+    perform_handshake, Extensions, Handshake, HandshakeError, HANDSHAKE_SIZE,
+};
+use bittorrent_starter_rust::peer_id::PeerId;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INFO_HASH: [u8; 20] = [0x39; 20];
+
+const ALL: Extensions = Extensions {
+    extension_protocol: true,
+    fast: true,
+    dht: true,
+};
+
+fn local() -> Handshake {
+    Handshake::new(INFO_HASH, PeerId::generate()).with_extensions(ALL)
+}
+
+/// A peer that reads our handshake and answers with `reply`, a byte at a time.
+fn peer(reply: Vec<u8>) -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut ours = [0; HANDSHAKE_SIZE];
+        stream.read_exact(&mut ours).unwrap();
+        for byte in reply {
+            stream.write_all(&[byte]).unwrap();
+        }
+        // Hold the connection open so a short reply times out instead of ending.
+        thread::sleep(Duration::from_secs(2));
+    });
+    TcpStream::connect(addr).unwrap()
+}
+
+#[test]
+fn lays_out_the_reserved_bits() {
+    let bytes = local().to_bytes();
+    assert_eq!(bytes[0], 19);
+    assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+    assert_eq!(&bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x05]);
+    assert_eq!(&bytes[28..48], INFO_HASH);
+    assert_eq!(Handshake::from_bytes(&bytes).extensions(), ALL);
+    assert_eq!(
+        Extensions::from_reserved([0xff, 0, 0, 0, 0, 0x10, 0, 0]),
+        Extensions {
+            extension_protocol: true,
+            ..Extensions::default()
+        },
+        "unknown bits are ignored"
+    );
+}
+
+#[test]
+fn verify_keeps_only_shared_extensions() {
+    let local = local();
+    let peer_id = PeerId::generate();
+    let remote = Handshake::new(INFO_HASH, peer_id).with_extensions(Extensions {
+        fast: true,
+        ..Extensions::default()
+    });
+    let result = remote.verify(&local).unwrap();
+    assert_eq!(result.peer_id, peer_id);
+    assert_eq!(
+        result.extensions,
+        Extensions {
+            fast: true,
+            ..Extensions::default()
+        }
+    );
+    let plain = Handshake::new(INFO_HASH, PeerId::generate());
+    assert_eq!(
+        remote.verify(&plain).unwrap().extensions,
+        Extensions::default()
+    );
+}
+
+#[test]
+fn verify_rejects_bad_handshakes() {
+    let local = local();
+    let mut other_protocol = Handshake::new(INFO_HASH, PeerId::generate());
+    other_protocol.protocol = *b"BitTorrent protocoX";
+    let mut other_length = Handshake::new(INFO_HASH, PeerId::generate());
+    other_length.protocol_length = 18;
+    for remote in [other_protocol, other_length] {
+        assert!(matches!(
+            remote.verify(&local),
+            Err(HandshakeError::Protocol)
+        ));
+    }
+    assert!(matches!(
+        Handshake::new([0; 20], PeerId::generate()).verify(&local),
+        Err(HandshakeError::InfoHashMismatch)
+    ));
+    assert!(matches!(
+        local.verify(&local),
+        Err(HandshakeError::SelfConnection)
+    ));
+}
+
+#[test]
+fn perform_handshake_reads_a_reply_split_across_segments() {
+    let local = local();
+    let peer_id = PeerId::generate();
+    let reply = Handshake::new(INFO_HASH, peer_id).with_extensions(ALL);
+    let mut stream = peer(reply.to_bytes().to_vec());
+    let result = perform_handshake(&mut stream, &local, Duration::from_secs(5)).unwrap();
+    assert_eq!(result.peer_id, peer_id);
+    assert_eq!(result.extensions, ALL);
+}
+
+#[test]
+fn perform_handshake_times_out_on_a_short_reply() {
+    let reply = Handshake::new(INFO_HASH, PeerId::generate()).to_bytes();
+    let mut stream = peer(reply[..40].to_vec());
+    let started = Instant::now();
+    let result = perform_handshake(&mut stream, &local(), Duration::from_millis(200));
+    assert!(matches!(result, Err(HandshakeError::Timeout)), "{result:?}");
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn perform_handshake_drops_connections_to_ourselves() {
+    let local = local();
+    let mut stream = peer(local.to_bytes().to_vec());
+    assert!(matches!(
+        perform_handshake(&mut stream, &local, Duration::from_secs(5)),
+        Err(HandshakeError::SelfConnection)
+    ));
+}
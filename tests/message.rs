@@ -0,0 +1,167 @@
+//! Encoding, decoding and framing of peer wire messages.
+
+use bittorrent_starter_rust::message::{
+    read_message, MessageError, MessageId, PeerMessage, MAX_MESSAGE_LEN,
+};
+use bytes::BytesMut;
+
+fn every_message() -> Vec<PeerMessage> {
+    vec![
+        PeerMessage::KeepAlive,
+        PeerMessage::Choke,
+        PeerMessage::Unchoke,
+        PeerMessage::Interested,
+        PeerMessage::NotInterested,
+        PeerMessage::Have { index: 7 },
+        PeerMessage::Bitfield(vec![0b1010_0000, 0xff]),
+        PeerMessage::Request {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        },
+        PeerMessage::Piece {
+            index: 2,
+            begin: 0,
+            block: b"block".to_vec(),
+        },
+        PeerMessage::Cancel {
+            index: 1,
+            begin: 16384,
+            length: 16384,
+        },
+        PeerMessage::Port(6881),
+        PeerMessage::SuggestPiece { index: 3 },
+        PeerMessage::HaveAll,
+        PeerMessage::HaveNone,
+        PeerMessage::RejectRequest {
+            index: 4,
+            begin: 0,
+            length: 100,
+        },
+        PeerMessage::AllowedFast { index: 5 },
+        PeerMessage::Extended {
+            id: 0,
+            payload: b"de".to_vec(),
+        },
+        PeerMessage::Unknown {
+            id: 42,
+            payload: vec![1, 2, 3],
+        },
+    ]
+}
+
+#[test]
+fn every_message_round_trips() {
+    for message in every_message() {
+        let bytes = message.to_bytes();
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, bytes.len() - 4, "{message:?}");
+        assert_eq!(PeerMessage::decode(&bytes[4..]).unwrap(), message);
+        assert_eq!(read_message(&mut &bytes[..]).unwrap(), message);
+    }
+}
+
+#[test]
+fn encodes_the_wire_layout() {
+    assert_eq!(PeerMessage::KeepAlive.to_bytes(), [0, 0, 0, 0]);
+    assert_eq!(PeerMessage::Interested.to_bytes(), [0, 0, 0, 1, 2]);
+    assert_eq!(
+        PeerMessage::Have { index: 0x0102 }.to_bytes(),
+        [0, 0, 0, 5, 4, 0, 0, 1, 2]
+    );
+    assert_eq!(
+        PeerMessage::Request {
+            index: 1,
+            begin: 2,
+            length: 3
+        }
+        .to_bytes(),
+        [0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]
+    );
+    assert_eq!(
+        PeerMessage::Port(0x1ae1).to_bytes(),
+        [0, 0, 0, 3, 9, 0x1a, 0xe1]
+    );
+    assert_eq!(
+        PeerMessage::Extended {
+            id: 3,
+            payload: vec![9]
+        }
+        .to_bytes(),
+        [0, 0, 0, 3, 20, 3, 9]
+    );
+}
+
+#[test]
+fn keeps_unknown_messages_verbatim() {
+    let message = PeerMessage::decode(&[99, 0xde, 0xad]).unwrap();
+    assert_eq!(
+        message,
+        PeerMessage::Unknown {
+            id: 99,
+            payload: vec![0xde, 0xad]
+        }
+    );
+    assert_eq!(message.id(), None);
+    assert_eq!(message.to_bytes(), [0, 0, 0, 3, 99, 0xde, 0xad]);
+}
+
+#[test]
+fn rejects_malformed_payloads() {
+    let malformed = |frame: &[u8]| match PeerMessage::decode(frame) {
+        Err(MessageError::Malformed { id, .. }) => id,
+        other => panic!("unexpected {other:?}"),
+    };
+    assert_eq!(malformed(&[0, 1]), MessageId::Choke);
+    assert_eq!(malformed(&[4, 0, 0, 1]), MessageId::Have);
+    assert_eq!(malformed(&[6, 0, 0, 0, 1, 0, 0, 0, 2]), MessageId::Request);
+    assert_eq!(malformed(&[7, 0, 0, 0, 1]), MessageId::Piece);
+    assert_eq!(malformed(&[9, 1]), MessageId::Port);
+    assert_eq!(malformed(&[20]), MessageId::Extended);
+}
+
+#[test]
+fn decode_frame_waits_for_whole_frames() {
+    let mut wire = BytesMut::new();
+    PeerMessage::Have { index: 9 }.encode(&mut wire);
+    PeerMessage::KeepAlive.encode(&mut wire);
+    PeerMessage::Unchoke.encode(&mut wire);
+    let wire = wire.freeze();
+
+    let mut buf = BytesMut::new();
+    let mut decoded = Vec::new();
+    for byte in wire.iter() {
+        buf.extend_from_slice(&[*byte]);
+        while let Some(message) = PeerMessage::decode_frame(&mut buf, MAX_MESSAGE_LEN).unwrap() {
+            decoded.push(message);
+        }
+    }
+    assert_eq!(
+        decoded,
+        [
+            PeerMessage::Have { index: 9 },
+            PeerMessage::KeepAlive,
+            PeerMessage::Unchoke
+        ]
+    );
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn refuses_frames_over_the_limit() {
+    let mut buf = BytesMut::from(&[0, 0, 0x10, 0x01, 5][..]);
+    match PeerMessage::decode_frame(&mut buf, 0x1000) {
+        Err(MessageError::TooLong { len, max }) => assert_eq!((len, max), (0x1001, 0x1000)),
+        other => panic!("unexpected {other:?}"),
+    }
+    // A frame exactly at the limit is fine once it has arrived.
+    let mut buf = BytesMut::new();
+    PeerMessage::Bitfield(vec![0xff; 3]).encode(&mut buf);
+    assert!(PeerMessage::decode_frame(&mut buf, 4).unwrap().is_some());
+
+    let oversized = ((MAX_MESSAGE_LEN + 1) as u32).to_be_bytes();
+    assert!(matches!(
+        read_message(&mut &oversized[..]),
+        Err(MessageError::TooLong { .. })
+    ));
+}
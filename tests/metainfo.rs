@@ -0,0 +1,122 @@
+//! Metainfo parsing and the field-level errors it reports.
+
+use bittorrent_starter_rust::bencode::BencodeValue;
+use bittorrent_starter_rust::torrent::{MetainfoError, TorrentFile};
+
+fn info(entries: Vec<(&str, BencodeValue)>) -> BencodeValue {
+    let mut fields = vec![
+        ("name", BencodeValue::ByteString(b"file.bin".to_vec())),
+        ("piece length", BencodeValue::Integer(32768)),
+        ("pieces", BencodeValue::ByteString(vec![0xaa; 40])),
+        ("length", BencodeValue::Integer(40000)),
+    ];
+    for (key, value) in entries {
+        fields.retain(|(name, _)| *name != key);
+        fields.push((key, value));
+    }
+    BencodeValue::dictionary(fields)
+}
+
+fn torrent(info: BencodeValue) -> Vec<u8> {
+    BencodeValue::dictionary([
+        (
+            "announce",
+            BencodeValue::ByteString(b"http://tracker.example/announce".to_vec()),
+        ),
+        ("info", info),
+    ])
+    .encode()
+}
+
+fn error(bytes: &[u8]) -> String {
+    match TorrentFile::from_bytes(bytes) {
+        Ok(_) => panic!("parsed invalid metainfo"),
+        Err(err) => err.to_string(),
+    }
+}
+
+#[test]
+fn parses_a_single_file_torrent() {
+    let torrent = TorrentFile::from_bytes(&torrent(info(vec![]))).unwrap();
+    assert_eq!(torrent.announce, "http://tracker.example/announce");
+    assert_eq!(torrent.info.name, "file.bin");
+    assert_eq!(torrent.info.piece_length, 32768);
+    assert_eq!(torrent.info.length, 40000);
+    assert_eq!(torrent.info.piece_count(), 2);
+    assert_eq!(torrent.info.piece_size(1), 40000 - 32768);
+    assert!(!torrent.info.is_private());
+    assert!(torrent.nodes.is_empty());
+}
+
+#[test]
+fn trackerless_torrents_need_no_announce() {
+    let bytes = BencodeValue::dictionary([
+        (
+            "nodes",
+            BencodeValue::List(vec![BencodeValue::List(vec![
+                BencodeValue::ByteString(b"router.example".to_vec()),
+                BencodeValue::Integer(6881),
+            ])]),
+        ),
+        ("info", info(vec![("private", BencodeValue::Integer(1))])),
+    ])
+    .encode();
+    let torrent = TorrentFile::from_bytes(&bytes).unwrap();
+    assert_eq!(torrent.announce, "");
+    assert_eq!(torrent.nodes, [("router.example".to_owned(), 6881)]);
+    assert!(torrent.info.is_private());
+}
+
+#[test]
+fn names_missing_fields() {
+    let bytes = BencodeValue::dictionary([(
+        "announce",
+        BencodeValue::ByteString(b"http://tracker.example/announce".to_vec()),
+    )])
+    .encode();
+    assert!(matches!(
+        TorrentFile::from_bytes(&bytes),
+        Err(MetainfoError::Missing { ref field }) if field == "info"
+    ));
+    let mut without_length = info(vec![]);
+    if let BencodeValue::Dictionary(fields) = &mut without_length {
+        fields.remove(b"length".as_slice());
+    }
+    assert_eq!(error(&torrent(without_length)), "info.length: missing");
+}
+
+#[test]
+fn names_wrongly_typed_fields() {
+    let bytes = torrent(info(vec![(
+        "piece length",
+        BencodeValue::ByteString(b"big".to_vec()),
+    )]));
+    let message = error(&bytes);
+    assert!(
+        message.starts_with("info.piece length: "),
+        "unexpected error: {message}"
+    );
+}
+
+#[test]
+fn rejects_inconsistent_values() {
+    let bytes = torrent(info(vec![(
+        "pieces",
+        BencodeValue::ByteString(vec![0; 30]),
+    )]));
+    assert_eq!(error(&bytes), "info.pieces: length is not a multiple of 20");
+    let bytes = torrent(info(vec![("piece length", BencodeValue::Integer(0))]));
+    assert_eq!(error(&bytes), "info.piece length: must be positive");
+}
+
+#[test]
+fn reports_malformed_bencode() {
+    assert!(matches!(
+        TorrentFile::from_bytes(b"d8:announce"),
+        Err(MetainfoError::Malformed(_))
+    ));
+    assert!(matches!(
+        TorrentFile::from_path("/nonexistent/file.torrent"),
+        Err(MetainfoError::Io(_))
+    ));
+}
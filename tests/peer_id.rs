@@ -0,0 +1,57 @@
+//! Peer ID generation and recognizing clients from their IDs.
+
+use bittorrent_starter_rust::peer_id::{PeerId, CLIENT_PREFIX};
+
+/// An ID starting with `prefix` and padded with digits, which must not leak into versions.
+fn id(prefix: &[u8]) -> PeerId {
+    let mut id = *b"99999999999999999999";
+    id[..prefix.len()].copy_from_slice(prefix);
+    PeerId(id)
+}
+
+fn client(prefix: &[u8]) -> Option<String> {
+    id(prefix).client()
+}
+
+#[test]
+fn generated_ids_carry_our_prefix() {
+    let (a, b) = (PeerId::generate(), PeerId::generate());
+    assert_eq!(&a.as_bytes()[..8], CLIENT_PREFIX);
+    assert_ne!(a, b);
+    assert_eq!(a.client().as_deref(), Some("bittorrent-rust 0.1"));
+    let long = PeerId::with_prefix(&[b'x'; 30]);
+    assert_eq!(long.as_bytes(), &[b'x'; 20]);
+}
+
+#[test]
+fn recognizes_azureus_style_ids() {
+    assert_eq!(client(b"-qB4520-").as_deref(), Some("qBittorrent 4.5.2"));
+    assert_eq!(client(b"-TR4040-").as_deref(), Some("Transmission 4.0.4"));
+    // Letters stand for 10 and up.
+    assert_eq!(client(b"-UT355W-").as_deref(), Some("µTorrent 3.5.5.32"));
+    assert_eq!(client(b"-ZZ1000-"), None);
+    assert_eq!(client(b"-qB4.5-"), None);
+}
+
+#[test]
+fn recognizes_shadow_style_ids() {
+    assert_eq!(client(b"S587----").as_deref(), Some("Shadow 5.8.7"));
+    assert_eq!(client(b"T03I----").as_deref(), Some("BitTornado 0.3.18"));
+    assert_eq!(client(b"A2------").as_deref(), Some("ABC 2"));
+    assert_eq!(client(b"X587----"), None);
+    assert_eq!(client(b"S-------"), None);
+}
+
+#[test]
+fn recognizes_mainline_style_ids() {
+    assert_eq!(client(b"M4-3-6--").as_deref(), Some("BitTorrent 4.3.6"));
+    assert_eq!(client(b"M4-10-2-").as_deref(), Some("BitTorrent 4.10.2"));
+    assert_eq!(client(b"M7-0----").as_deref(), Some("BitTorrent 7.0"));
+    assert_eq!(client(b"M4-x-6--"), None);
+}
+
+#[test]
+fn unknown_ids_have_no_client() {
+    assert_eq!(PeerId([0; 20]).client(), None);
+    assert_eq!(client(b"random-bytes").as_deref(), None);
+}
@@ -0,0 +1,139 @@
+//! ut_pex messages and the per-connection extension that sends and receives them.
+
+use bittorrent_starter_rust::bencode::BencodeValue;
+use bittorrent_starter_rust::extension::Extension;
+use bittorrent_starter_rust::pex::{PexFlags, PexMessage, UtPex};
+use bittorrent_starter_rust::pool::{CandidatePool, PeerSource};
+use bittorrent_starter_rust::torrent::TorrentFileInfo;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+fn info(private: Option<i64>) -> TorrentFileInfo {
+    TorrentFileInfo {
+        name: "pex".to_owned(),
+        piece_length: 16384,
+        pieces: vec![0; 20],
+        length: 16384,
+        private,
+    }
+}
+
+fn addr(s: &str) -> SocketAddr {
+    s.parse().unwrap()
+}
+
+#[test]
+fn messages_round_trip_both_address_families() {
+    let message = PexMessage {
+        added: vec![
+            (
+                addr("10.0.0.1:6881"),
+                PexFlags::SEED.union(PexFlags::REACHABLE),
+            ),
+            (addr("[2001:db8::1]:51413"), PexFlags::UTP),
+            (addr("10.0.0.2:7000"), PexFlags::default()),
+        ],
+        dropped: vec![addr("192.168.1.9:1234"), addr("[::1]:80")],
+    };
+    let decoded = PexMessage::from_bytes(&message.to_bytes()).unwrap();
+    // IPv4 peers are listed before IPv6 ones on the wire.
+    assert_eq!(
+        decoded.added,
+        [message.added[0], message.added[2], message.added[1]]
+    );
+    assert_eq!(decoded.dropped, message.dropped);
+    assert!(decoded.added[0].1.contains(PexFlags::SEED));
+    assert!(!decoded.added[0].1.contains(PexFlags::ENCRYPTION));
+}
+
+#[test]
+fn missing_and_short_flag_lists_leave_peers_unflagged() {
+    let compact = [10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2];
+    let payload = BencodeValue::dictionary([
+        ("added", BencodeValue::ByteString(compact.to_vec())),
+        ("added.f", BencodeValue::ByteString(vec![0x02])),
+    ])
+    .encode();
+    let message = PexMessage::from_bytes(&payload).unwrap();
+    assert_eq!(
+        message.added,
+        [
+            (addr("10.0.0.1:6881"), PexFlags::SEED),
+            (addr("10.0.0.2:6882"), PexFlags::default())
+        ]
+    );
+    assert!(message.dropped.is_empty());
+
+    let bare = BencodeValue::dictionary([("added", BencodeValue::ByteString(compact.to_vec()))]);
+    let message = PexMessage::from_bytes(&bare.encode()).unwrap();
+    assert!(message.added.iter().all(|(_, flags)| flags.0 == 0));
+    assert_eq!(
+        PexMessage::from_bytes(b"de").unwrap(),
+        PexMessage::default()
+    );
+    assert!(PexMessage::from_bytes(b"li1ee").is_err());
+}
+
+#[test]
+fn private_torrents_get_no_pex() {
+    let remote = addr("127.0.0.1:1");
+    assert!(UtPex::for_torrent(&info(Some(1)), CandidatePool::new(), remote).is_none());
+    assert!(UtPex::for_torrent(&info(Some(0)), CandidatePool::new(), remote).is_some());
+    assert!(UtPex::for_torrent(&info(None), CandidatePool::new(), remote).is_some());
+}
+
+#[test]
+fn reported_peers_join_the_pool() {
+    let pool = CandidatePool::new();
+    let mut pex = UtPex::for_torrent(&info(None), pool.clone(), addr("127.0.0.1:1")).unwrap();
+    let message = PexMessage {
+        added: vec![
+            (addr("10.0.0.1:6881"), PexFlags::default()),
+            (addr("10.0.0.2:0"), PexFlags::default()),
+        ],
+        dropped: Vec::new(),
+    };
+    assert!(pex.on_message(&message.to_bytes()).unwrap().is_empty());
+    assert_eq!(pool.pop(), Some((addr("10.0.0.1:6881"), PeerSource::Pex)));
+    assert_eq!(pool.pop(), None, "port 0 is not connectable");
+}
+
+#[test]
+fn tick_respects_the_interval() {
+    let pool = CandidatePool::new();
+    let remote = addr("127.0.0.1:1");
+    let mut pex = UtPex::for_torrent(&info(None), pool.clone(), remote)
+        .unwrap()
+        .with_interval(Duration::from_millis(200));
+    assert!(pex.tick().is_empty(), "nothing to report yet");
+
+    pool.connected(remote, PexFlags::default());
+    pool.connected(addr("10.0.0.1:6881"), PexFlags::REACHABLE);
+    let sent = pex.tick();
+    assert_eq!(sent.len(), 1);
+    let first = PexMessage::from_bytes(&sent[0]).unwrap();
+    assert_eq!(
+        first.added,
+        [(addr("10.0.0.1:6881"), PexFlags::REACHABLE)],
+        "the peer is not told about itself"
+    );
+
+    pool.disconnected(addr("10.0.0.1:6881"));
+    pool.connected(addr("10.0.0.2:6881"), PexFlags::default());
+    assert!(pex.tick().is_empty(), "too soon after the last message");
+    thread::sleep(Duration::from_millis(250));
+    let second = PexMessage::from_bytes(&pex.tick()[0]).unwrap();
+    assert_eq!(second.added, [(addr("10.0.0.2:6881"), PexFlags::default())]);
+    assert_eq!(second.dropped, [addr("10.0.0.1:6881")]);
+}
+
+#[test]
+fn default_interval_holds_back_a_second_message() {
+    let pool = CandidatePool::new();
+    let mut pex = UtPex::for_torrent(&info(None), pool.clone(), addr("127.0.0.1:1")).unwrap();
+    pool.connected(addr("10.0.0.1:6881"), PexFlags::default());
+    assert_eq!(pex.tick().len(), 1);
+    pool.connected(addr("10.0.0.2:6881"), PexFlags::default());
+    assert!(pex.tick().is_empty());
+}
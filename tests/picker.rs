@@ -0,0 +1,130 @@
+//! Piece selection against synthetic availability.
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::picker::PiecePicker;
+use std::collections::HashSet;
+
+fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+    let mut bitfield = Bitfield::new(len);
+    for index in pieces {
+        bitfield.set(*index);
+    }
+    bitfield
+}
+
+/// A picker past its random first piece, with `counts[i]` peers having piece `i`.
+fn with_availability(counts: &[u32]) -> PiecePicker {
+    let mut picker = PiecePicker::new(counts.len() + 1);
+    let most = counts.iter().copied().max().unwrap_or(0);
+    for round in 0..most {
+        let pieces: Vec<usize> = (0..counts.len()).filter(|i| counts[*i] > round).collect();
+        picker.add_peer(&bitfield(counts.len() + 1, &pieces));
+    }
+    // The extra last piece is already downloaded.
+    picker.complete(counts.len() as u32);
+    picker
+}
+
+#[test]
+fn picks_rarest_first() {
+    let mut picker = with_availability(&[3, 1, 2, 5]);
+    assert_eq!(picker.pick(|_| true), Some(1));
+    assert_eq!(picker.pick(|_| true), Some(2));
+    assert_eq!(picker.pick(|_| true), Some(0));
+    assert_eq!(picker.pick(|_| true), Some(3));
+    assert_eq!(picker.pick(|_| true), None);
+}
+
+#[test]
+fn only_picks_what_the_peer_has() {
+    let mut picker = with_availability(&[1, 2, 3]);
+    assert_eq!(picker.pick(|index| index != 0), Some(1));
+    assert_eq!(picker.pick(|index| index == 0), Some(0));
+    assert_eq!(picker.pick(|index| index == 0), None);
+}
+
+#[test]
+fn breaks_ties_at_random() {
+    let mut seen = HashSet::new();
+    for _ in 0..200 {
+        let mut picker = with_availability(&[2, 1, 1, 1, 3]);
+        let index = picker.pick(|_| true).unwrap();
+        assert!((1..=3).contains(&index));
+        seen.insert(index);
+    }
+    assert_eq!(seen.len(), 3);
+}
+
+#[test]
+fn first_piece_is_random() {
+    let mut seen = HashSet::new();
+    for _ in 0..200 {
+        let mut picker = PiecePicker::new(4);
+        picker.add_peer(&bitfield(4, &[0, 1, 2, 3]));
+        picker.add_peer(&bitfield(4, &[1, 2, 3]));
+        seen.insert(picker.pick(|_| true).unwrap());
+    }
+    // Rarest-first alone would always start with piece 0.
+    assert!(seen.len() > 1);
+}
+
+#[test]
+fn finishes_partial_pieces_first() {
+    let mut picker = with_availability(&[1, 4, 4]);
+    let rare = picker.pick(|_| true).unwrap();
+    assert_eq!(rare, 0);
+    let common = picker.pick(|_| true).unwrap();
+    picker.release(common, true);
+    picker.release(rare, false);
+    assert_eq!(picker.pick(|_| true), Some(common));
+    assert_eq!(picker.pick(|_| true), Some(0));
+}
+
+#[test]
+fn tracks_have_and_departures() {
+    let mut picker = PiecePicker::new(3);
+    let peer = bitfield(3, &[0, 1]);
+    picker.add_peer(&peer);
+    picker.peer_has(2);
+    picker.peer_has(2);
+    assert_eq!(
+        (0..3).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+        [1, 1, 2]
+    );
+    picker.remove_peer(&peer);
+    assert_eq!(
+        (0..3).map(|i| picker.availability(i)).collect::<Vec<_>>(),
+        [0, 0, 2]
+    );
+}
+
+#[test]
+fn skips_busy_done_and_unwanted_pieces() {
+    let mut picker = PiecePicker::new(4).with_wanted(bitfield(4, &[0, 1, 2]));
+    picker.add_peer(&Bitfield::full(4));
+    picker.complete(0);
+    let first = picker.pick(|_| true).unwrap();
+    let second = picker.pick(|_| true).unwrap();
+    assert_eq!(
+        HashSet::from([first, second]),
+        HashSet::from([1, 2]),
+        "piece 3 is not wanted"
+    );
+    assert_eq!(picker.pick(|_| true), None);
+    picker.complete(first);
+    picker.complete(second);
+    assert!(picker.is_complete());
+}
+
+#[test]
+fn prefers_suggested_pieces() {
+    let mut picker = with_availability(&[1, 4, 4, 2]);
+    // Piece 4 is already downloaded and piece 1 unavailable, so 2 is the first that qualifies.
+    assert_eq!(
+        picker.pick_suggested([4, 1, 2], |index| index != 1),
+        Some(2)
+    );
+    assert!(picker.is_busy(2));
+    // Nothing suggested qualifies any more, so it falls back to rarest first.
+    assert_eq!(picker.pick_suggested([2], |_| true), Some(0));
+}
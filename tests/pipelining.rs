@@ -0,0 +1,165 @@
+//! Request pipelining, counted as requests in flight at a peer scripted by the test.
+
+mod common;
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::engine::{Engine, EngineConfig};
+use bittorrent_starter_rust::extension::ExtendedHandshake;
+use bittorrent_starter_rust::handshake::Extensions;
+use bittorrent_starter_rust::message::PeerMessage;
+use bittorrent_starter_rust::pool::{CandidatePool, PeerSource};
+use bittorrent_starter_rust::session::{Pipeline, PipelineConfig, DEFAULT_QUEUE_DEPTH};
+use bittorrent_starter_rust::torrent::TorrentFileInfo;
+use bittorrent_starter_rust::tracker::TransferStats;
+use common::{content, download, scripted_peer, ScriptedPeer, PIECE_LENGTH};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the peer waits for more requests before it takes the client to be waiting.
+const SETTLE: Duration = Duration::from_millis(100);
+const LATENCY: Duration = Duration::from_millis(20);
+
+fn engine(info: &TorrentFileInfo, pipeline: PipelineConfig) -> Engine {
+    Engine::new(info.clone(), Arc::new(TransferStats::new(info.length))).with_config(EngineConfig {
+        pipeline,
+        ..EngineConfig::default()
+    })
+}
+
+fn announce(peer: &mut ScriptedPeer, info: &TorrentFileInfo) {
+    let bitfield = Bitfield::full(info.piece_count()).as_bytes().to_vec();
+    peer.send(PeerMessage::Bitfield(bitfield));
+    peer.send(PeerMessage::Unchoke);
+}
+
+/// Lets the requests pile up until the client stops sending, answers them all at once and
+/// starts over, until the download is done. Returns how many requests each round held.
+fn serve_in_rounds(peer: &mut ScriptedPeer) -> Vec<usize> {
+    let mut rounds = Vec::new();
+    while let Some(first) = peer.try_request(Duration::from_secs(5)) {
+        let mut round = vec![first];
+        while let Some(request) = peer.try_request(SETTLE) {
+            round.push(request);
+        }
+        rounds.push(round.len());
+        for request in round {
+            peer.serve(request);
+        }
+    }
+    rounds
+}
+
+/// Answers each request `LATENCY` after it arrives, like a seeder a round trip away, and
+/// returns the most requests that were ever waiting for an answer at once.
+fn serve_with_latency(peer: &mut ScriptedPeer) -> usize {
+    let outstanding = Arc::new(AtomicUsize::new(0));
+    let (queue, due) = mpsc::channel::<(Instant, (u32, u32, u32))>();
+    let mut writer = peer.try_clone();
+    let answered = outstanding.clone();
+    let delay_line = thread::spawn(move || {
+        for (at, request) in due {
+            thread::sleep(at.saturating_duration_since(Instant::now()));
+            // Counted before the block leaves, so a request it frees is never counted early.
+            answered.fetch_sub(1, Ordering::SeqCst);
+            writer.serve(request);
+        }
+    });
+    let mut most = 0;
+    while let Some(request) = peer.try_request(Duration::from_secs(5)) {
+        most = most.max(outstanding.fetch_add(1, Ordering::SeqCst) + 1);
+        queue.send((Instant::now() + LATENCY, request)).unwrap();
+    }
+    drop(queue);
+    delay_line.join().unwrap();
+    most
+}
+
+async fn fetch(info: &TorrentFileInfo, pipeline: PipelineConfig, peer: SocketAddr) {
+    let pool = CandidatePool::new();
+    pool.add([peer], PeerSource::Tracker);
+    download(engine(info, pipeline), pool).await.unwrap();
+}
+
+#[tokio::test]
+async fn starts_at_the_default_depth() {
+    let (info, data) = content(PIECE_LENGTH * 8);
+    let announced = info.clone();
+    let (addr, peer) = scripted_peer(&info, data, Extensions::default(), move |mut peer| {
+        announce(&mut peer, &announced);
+        serve_in_rounds(&mut peer)
+    });
+    fetch(&info, PipelineConfig::default(), addr).await;
+    assert_eq!(peer.join().unwrap()[0], DEFAULT_QUEUE_DEPTH);
+}
+
+#[tokio::test]
+async fn depth_one_sends_one_request_at_a_time() {
+    let (info, data) = content(PIECE_LENGTH * 2);
+    let announced = info.clone();
+    let (addr, peer) = scripted_peer(&info, data, Extensions::default(), move |mut peer| {
+        announce(&mut peer, &announced);
+        serve_in_rounds(&mut peer)
+    });
+    let serial = PipelineConfig {
+        initial_depth: 1,
+        max_depth: 1,
+    };
+    fetch(&info, serial, addr).await;
+    assert_eq!(peer.join().unwrap(), vec![1; 4]);
+}
+
+#[tokio::test]
+async fn depth_grows_with_a_slow_round_trip() {
+    let (info, data) = content(PIECE_LENGTH * 64);
+    let announced = info.clone();
+    let (addr, peer) = scripted_peer(&info, data, Extensions::default(), move |mut peer| {
+        announce(&mut peer, &announced);
+        serve_with_latency(&mut peer)
+    });
+    fetch(&info, PipelineConfig::default(), addr).await;
+    let most = peer.join().unwrap();
+    assert!(
+        most > DEFAULT_QUEUE_DEPTH,
+        "never more than {most} in flight"
+    );
+}
+
+#[tokio::test]
+async fn depth_stays_within_the_peers_reqq() {
+    let (info, data) = content(PIECE_LENGTH * 8);
+    let announced = info.clone();
+    let extensions = Extensions {
+        extension_protocol: true,
+        ..Extensions::default()
+    };
+    let (addr, peer) = scripted_peer(&info, data, extensions, move |mut peer| {
+        let handshake = ExtendedHandshake {
+            reqq: Some(2),
+            ..ExtendedHandshake::default()
+        };
+        peer.send(handshake.to_message());
+        announce(&mut peer, &announced);
+        serve_in_rounds(&mut peer)
+    });
+    fetch(&info, PipelineConfig::default(), addr).await;
+    let rounds = peer.join().unwrap();
+    assert_eq!(rounds[0], 2);
+    assert!(rounds.iter().all(|requests| *requests <= 2), "{rounds:?}");
+}
+
+#[test]
+fn depth_respects_peer_limit() {
+    let mut pipeline = Pipeline::default();
+    pipeline.set_peer_limit(3);
+    for _ in 0..3 {
+        assert!(pipeline.has_room());
+        pipeline.sent();
+    }
+    assert!(!pipeline.has_room());
+    pipeline.received(LATENCY, 1 << 14);
+    assert!(pipeline.has_room());
+    assert_eq!(pipeline.depth(), 3);
+}
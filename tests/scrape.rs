@@ -0,0 +1,137 @@
+//! Scrape URL derivation, scrape response parsing and batched scrapes against a local tracker.
+
+use bittorrent_starter_rust::bencode::BencodeValue;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{
+    parse_scrape, scrape, scrape_url, AnnounceEvent, ScrapeStats, TrackerError,
+};
+use bittorrent_starter_rust::tracker_server::{ClientAnnounce, HttpTrackerServer, PeerStore};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[test]
+fn derives_scrape_urls_by_convention() {
+    for (announce, expected) in [
+        ("http://t.example/announce", "http://t.example/scrape"),
+        (
+            "http://t.example/x/announce.php",
+            "http://t.example/x/scrape.php",
+        ),
+        (
+            "http://t.example/announce?passkey=abc",
+            "http://t.example/scrape?passkey=abc",
+        ),
+        (
+            "https://t.example/announce-v2",
+            "https://t.example/scrape-v2",
+        ),
+        (
+            "udp://t.example:1337/announce",
+            "udp://t.example:1337/announce",
+        ),
+        ("udp://t.example:1337", "udp://t.example:1337"),
+    ] {
+        assert_eq!(
+            scrape_url(announce).as_deref(),
+            Some(expected),
+            "{announce}"
+        );
+    }
+}
+
+#[test]
+fn trackers_off_the_convention_cannot_be_scraped() {
+    for announce in [
+        "http://t.example/a",
+        "http://t.example/x/announce/",
+        "http://t.example/ann",
+        "http://t.example/tracker?path=/announce",
+    ] {
+        assert_eq!(scrape_url(announce), None, "{announce}");
+    }
+    assert!(matches!(
+        scrape("http://t.example/a", &[[0; 20]]),
+        Err(TrackerError::ScrapeUnsupported)
+    ));
+}
+
+#[test]
+fn parses_files_keyed_by_raw_info_hash() {
+    let mut files = BTreeMap::new();
+    files.insert(
+        vec![0xff; 20],
+        BencodeValue::dictionary([
+            ("complete", BencodeValue::Integer(3)),
+            ("downloaded", BencodeValue::Integer(11)),
+            ("incomplete", BencodeValue::Integer(2)),
+        ]),
+    );
+    // Missing counters default to zero; keys that are not info hashes are dropped.
+    files.insert(
+        vec![0x01; 20],
+        BencodeValue::dictionary([("complete", BencodeValue::Integer(1))]),
+    );
+    files.insert(b"short".to_vec(), BencodeValue::dictionary([]));
+    let body = BencodeValue::dictionary([("files", BencodeValue::Dictionary(files))]).encode();
+
+    let stats = parse_scrape(&body).unwrap();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(
+        stats[&[0xff; 20]],
+        ScrapeStats {
+            complete: 3,
+            downloaded: 11,
+            incomplete: 2,
+        }
+    );
+    assert_eq!(stats[&[0x01; 20]].complete, 1);
+    assert_eq!(stats[&[0x01; 20]].incomplete, 0);
+}
+
+#[test]
+fn surfaces_scrape_failures() {
+    let body = BencodeValue::dictionary([(
+        "failure reason",
+        BencodeValue::ByteString(b"scrape disabled".to_vec()),
+    )])
+    .encode();
+    assert!(matches!(
+        parse_scrape(&body),
+        Err(TrackerError::Failure(reason)) if reason == "scrape disabled"
+    ));
+    assert!(matches!(
+        parse_scrape(b"d5:files"),
+        Err(TrackerError::Malformed(_))
+    ));
+}
+
+#[test]
+fn scrapes_several_torrents_in_one_request() {
+    let store = PeerStore::new(Duration::from_secs(60));
+    for (info_hash, left) in [([1; 20], 0), ([1; 20], 10), ([2; 20], 10)] {
+        store
+            .announce(&ClientAnnounce {
+                info_hash,
+                peer_id: PeerId::generate(),
+                addr: "127.0.0.1:6881".parse().unwrap(),
+                left,
+                event: AnnounceEvent::Started,
+                numwant: 0,
+            })
+            .unwrap();
+    }
+    let server = HttpTrackerServer::bind("127.0.0.1:0", store).unwrap();
+    let url = server.announce_url().unwrap();
+    server.spawn();
+
+    let stats = scrape(&url, &[[1; 20], [2; 20], [3; 20]]).unwrap();
+    assert_eq!(stats.len(), 2, "unknown torrents are left out");
+    assert_eq!(
+        (stats[&[1; 20]].complete, stats[&[1; 20]].incomplete),
+        (1, 1)
+    );
+    assert_eq!(
+        (stats[&[2; 20]].complete, stats[&[2; 20]].incomplete),
+        (0, 1)
+    );
+}
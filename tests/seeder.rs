@@ -0,0 +1,332 @@
+//! Serving pieces from a file to inbound peers.
+
+mod common;
+
+use bittorrent_starter_rust::bitfield::Bitfield;
+use bittorrent_starter_rust::dht::{Dht, DhtConfig};
+use bittorrent_starter_rust::engine::Engine;
+use bittorrent_starter_rust::handshake::{Extensions, Handshake, HANDSHAKE_SIZE};
+use bittorrent_starter_rust::message::{read_message, write_message, PeerMessage};
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::pool::{CandidatePool, PeerSource};
+use bittorrent_starter_rust::proxy::ProxyConfig;
+use bittorrent_starter_rust::seeder::{FileStore, PieceStore, Seeder};
+use bittorrent_starter_rust::torrent::{TorrentFile, TorrentFileInfo};
+use bittorrent_starter_rust::tracker::{Tracker, TransferStats};
+use bittorrent_starter_rust::tracker_server::{HttpTrackerServer, PeerStore};
+use bittorrent_starter_rust::utils::random_u64;
+use common::{content, BLOCK, PIECE_LENGTH};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn write_temp(data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("seed-{:016x}", random_u64()));
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+/// Starts seeding `data` on a background runtime thread and returns its address.
+fn start_seeder(info: &TorrentFileInfo, data: &[u8]) -> (SocketAddr, Arc<TransferStats>) {
+    let path = write_temp(data);
+    let store = FileStore::open(info, &path).unwrap();
+    std::fs::remove_file(&path).ok();
+    serve(info, Arc::new(store))
+}
+
+/// Seeds the pieces `store` has on a background runtime thread and returns its address.
+fn serve(info: &TorrentFileInfo, store: Arc<dyn PieceStore>) -> (SocketAddr, Arc<TransferStats>) {
+    let stats = Arc::new(TransferStats::new(0));
+    let (info, seed_stats) = (info.clone(), stats.clone());
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // Distinct from the session ID the engine under test uses.
+            let seeder = Seeder::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .with_peer_id(PeerId::generate());
+            seeder.add(info, store, seed_stats);
+            tx.send(seeder.local_addr().unwrap()).unwrap();
+            seeder.run().await.unwrap();
+        });
+    });
+    (rx.recv().unwrap(), stats)
+}
+
+/// Offers only the first piece until `complete` is set, then all of them.
+struct Growing {
+    store: FileStore,
+    complete: AtomicBool,
+}
+
+impl PieceStore for Growing {
+    fn have(&self) -> Bitfield {
+        let mut have = self.store.have();
+        if !self.complete.load(Ordering::Relaxed) {
+            for index in 1..have.len() {
+                have.clear(index);
+            }
+        }
+        have
+    }
+
+    fn read(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        self.store.read(index, begin, length)
+    }
+}
+
+/// Connects with the fast extension and reads the seeder's opening messages up to its unchoke.
+fn connect(addr: SocketAddr, info_hash: [u8; 20]) -> (TcpStream, Vec<PeerMessage>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let local = Handshake::new(info_hash, PeerId::generate()).with_extensions(Extensions {
+        fast: true,
+        ..Extensions::default()
+    });
+    stream.write_all(&local.to_bytes()).unwrap();
+    let mut reply = [0; HANDSHAKE_SIZE];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(Handshake::from_bytes(&reply).info_hash, info_hash);
+    let mut opening = Vec::new();
+    loop {
+        let message = read_message(&mut stream).unwrap();
+        let unchoke = message == PeerMessage::Unchoke;
+        opening.push(message);
+        if unchoke {
+            return (stream, opening);
+        }
+    }
+}
+
+fn request(index: u32, begin: u32, length: u32) -> PeerMessage {
+    PeerMessage::Request {
+        index,
+        begin,
+        length,
+    }
+}
+
+fn reject(index: u32, begin: u32, length: u32) -> PeerMessage {
+    PeerMessage::RejectRequest {
+        index,
+        begin,
+        length,
+    }
+}
+
+#[tokio::test]
+async fn engine_downloads_from_the_seeder() {
+    let (info, data) = content(PIECE_LENGTH * 5 + 123);
+    let (addr, uploaded) = start_seeder(&info, &data);
+    let pool = CandidatePool::new();
+    pool.add([addr], PeerSource::Tracker);
+    let engine = Engine::new(info.clone(), Arc::new(TransferStats::new(info.length)));
+    let downloaded = tokio::time::timeout(Duration::from_secs(20), engine.run(pool))
+        .await
+        .expect("download did not finish")
+        .unwrap();
+    assert_eq!(downloaded, *data);
+    assert_eq!(uploaded.uploaded(), info.length);
+}
+
+#[test]
+fn only_verified_pieces_are_announced_and_served() {
+    let (info, data) = content(PIECE_LENGTH * 3);
+    let mut damaged = data.to_vec();
+    damaged[PIECE_LENGTH + 5] ^= 0xff;
+    let path = write_temp(&damaged);
+    let store = FileStore::open(&info, &path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert_eq!(store.have().iter().collect::<Vec<_>>(), [0, 2]);
+    assert!(store.read(1, 0, BLOCK).is_err());
+
+    let (addr, _) = start_seeder(&info, &damaged);
+    let (mut stream, opening) = connect(addr, info.info_hash());
+    let mut have = Bitfield::new(3);
+    have.set(0);
+    have.set(2);
+    assert_eq!(opening[0], PeerMessage::Bitfield(have.as_bytes().to_vec()));
+    for message in [
+        request(1, 0, BLOCK),
+        request(2, 0, BLOCK * 2),
+        request(2, BLOCK, BLOCK + 1),
+        request(2, BLOCK, BLOCK),
+    ] {
+        write_message(&mut stream, &message).unwrap();
+    }
+    assert_eq!(read_message(&mut stream).unwrap(), reject(1, 0, BLOCK));
+    assert_eq!(read_message(&mut stream).unwrap(), reject(2, 0, BLOCK * 2));
+    assert_eq!(
+        read_message(&mut stream).unwrap(),
+        reject(2, BLOCK, BLOCK + 1)
+    );
+    let start = PIECE_LENGTH * 2 + BLOCK as usize;
+    assert_eq!(
+        read_message(&mut stream).unwrap(),
+        PeerMessage::Piece {
+            index: 2,
+            begin: BLOCK,
+            block: data[start..start + BLOCK as usize].to_vec(),
+        }
+    );
+}
+
+#[test]
+fn cancelled_requests_are_not_served() {
+    let (info, data) = content(PIECE_LENGTH * 4);
+    let (addr, _) = start_seeder(&info, &data);
+    let (mut stream, opening) = connect(addr, info.info_hash());
+    assert_eq!(opening[0], PeerMessage::HaveAll);
+    // Sent in one go, so the cancel is read before the queue drains.
+    let mut batch = Vec::new();
+    for index in 0..4 {
+        write_message(&mut batch, &request(index, 0, BLOCK)).unwrap();
+    }
+    write_message(
+        &mut batch,
+        &PeerMessage::Cancel {
+            index: 3,
+            begin: 0,
+            length: BLOCK,
+        },
+    )
+    .unwrap();
+    stream.write_all(&batch).unwrap();
+    let mut served = Vec::new();
+    let mut rejected = Vec::new();
+    while served.len() + rejected.len() < 4 {
+        match read_message(&mut stream).unwrap() {
+            PeerMessage::Piece { index, .. } => served.push(index),
+            PeerMessage::RejectRequest { index, .. } => rejected.push(index),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+    assert_eq!(served, [0, 1, 2]);
+    assert_eq!(rejected, [3]);
+}
+
+#[test]
+fn refuses_unknown_torrents() {
+    let (info, data) = content(PIECE_LENGTH);
+    let (addr, _) = start_seeder(&info, &data);
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let local = Handshake::new([7; 20], PeerId::generate());
+    stream.write_all(&local.to_bytes()).unwrap();
+    let mut reply = Vec::new();
+    // The seeder closes without answering.
+    stream.read_to_end(&mut reply).unwrap();
+    assert!(reply.is_empty());
+}
+
+#[test]
+fn trackerless_downloads_find_the_seeder_through_the_dht() {
+    let (info, data) = content(3 * PIECE_LENGTH);
+    let (seeder, _) = start_seeder(&info, &data);
+    let node = || {
+        Dht::start(DhtConfig {
+            bind: SocketAddr::from(([127, 0, 0, 1], 0)),
+            routers: Vec::new(),
+            query_timeout: Duration::from_millis(500),
+            state_path: None,
+            proxy: ProxyConfig::direct(),
+        })
+        .unwrap()
+    };
+    let (router, seeding_node) = (node(), node());
+    let router_addr = router.local_addr().unwrap();
+    let nodes = vec![(router_addr.ip().to_string(), router_addr.port())];
+    seeding_node.bootstrap(&nodes);
+    seeding_node.announce(info.info_hash(), seeder.port());
+
+    let torrent = TorrentFile {
+        announce: String::new(),
+        nodes,
+        info,
+    };
+    let output = std::env::temp_dir().join(format!("dht-{:016x}", random_u64()));
+    let output_path = output.to_str().unwrap().to_owned();
+    torrent.download(&output_path).unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), *data);
+    std::fs::remove_file(&output).ok();
+}
+
+#[test]
+fn running_downloads_serve_their_verified_pieces() {
+    let (info, data) = content(2 * PIECE_LENGTH);
+    let path = write_temp(&data);
+    let growing = Arc::new(Growing {
+        store: FileStore::open(&info, &path).unwrap(),
+        complete: AtomicBool::new(false),
+    });
+    std::fs::remove_file(&path).ok();
+    let (seeder, _) = serve(&info, growing.clone());
+    let server =
+        HttpTrackerServer::bind("127.0.0.1:0", PeerStore::new(Duration::from_secs(60))).unwrap();
+    let url = server.announce_url().unwrap();
+    server.spawn();
+    let tracker = |port| {
+        let stats = Arc::new(TransferStats::new(0));
+        Tracker::new(
+            url.clone(),
+            info.info_hash(),
+            PeerId::generate(),
+            port,
+            stats,
+        )
+    };
+    tracker(seeder.port()).announce().unwrap();
+
+    let torrent = TorrentFile {
+        announce: url.clone(),
+        nodes: Vec::new(),
+        info: info.clone(),
+    };
+    let output = std::env::temp_dir().join(format!("serving-{:016x}", random_u64()));
+    let output_path = output.to_str().unwrap().to_owned();
+    let download = std::thread::spawn(move || torrent.download(&output_path));
+
+    // The download announces the port it serves on, with only piece 0 to offer so far.
+    let mut probe = tracker(1);
+    let started = Instant::now();
+    let (mut stream, opening) = loop {
+        assert!(started.elapsed() < Duration::from_secs(20), "not served");
+        let peers = probe.announce().unwrap().peers;
+        if let Some(peer) = peers.iter().find(|peer| peer.port != seeder.port()) {
+            let (stream, opening) = connect(peer.socket_addr(), info.info_hash());
+            if opening[0] != PeerMessage::HaveNone {
+                break (stream, opening);
+            }
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    let mut have = Bitfield::new(2);
+    have.set(0);
+    assert_eq!(opening[0], PeerMessage::Bitfield(have.as_bytes().to_vec()));
+    write_message(&mut stream, &request(1, 0, BLOCK)).unwrap();
+    write_message(&mut stream, &request(0, BLOCK, BLOCK)).unwrap();
+    assert_eq!(read_message(&mut stream).unwrap(), reject(1, 0, BLOCK));
+    assert_eq!(
+        read_message(&mut stream).unwrap(),
+        PeerMessage::Piece {
+            index: 0,
+            begin: BLOCK,
+            block: data[BLOCK as usize..PIECE_LENGTH].to_vec(),
+        }
+    );
+
+    // Piece 1 shows up with a `have` once the seeder offers it, and the download finishes.
+    growing.complete.store(true, Ordering::Relaxed);
+    download.join().unwrap().unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), *data);
+    std::fs::remove_file(&output).ok();
+}
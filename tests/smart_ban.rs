@@ -0,0 +1,64 @@
+//! Blaming and banning peers for pieces that fail their hash check.
+
+use bittorrent_starter_rust::ban::SmartBan;
+use bittorrent_starter_rust::session::BLOCK_SIZE;
+use std::net::{IpAddr, Ipv4Addr};
+
+const A: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const B: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+/// Three blocks, the last one short.
+fn piece() -> Vec<u8> {
+    (0..BLOCK_SIZE as usize * 2 + 100)
+        .map(|i| (i % 251) as u8)
+        .collect()
+}
+
+fn corrupt(piece: &[u8], block: usize) -> Vec<u8> {
+    let mut data = piece.to_vec();
+    data[block * BLOCK_SIZE as usize] ^= 0xff;
+    data
+}
+
+#[test]
+fn bans_a_sole_source_after_enough_failures() {
+    let good = piece();
+    let mut ban = SmartBan::new(2);
+    assert!(ban
+        .piece_failed(0, &corrupt(&good, 0), &[A, A, A])
+        .is_empty());
+    assert_eq!(ban.failures(A), 1);
+    assert_eq!(ban.piece_failed(1, &corrupt(&good, 2), &[A, A, A]), [A]);
+    assert!(ban.is_banned(A));
+    // Banned once only.
+    assert!(ban
+        .piece_failed(2, &corrupt(&good, 1), &[A, A, A])
+        .is_empty());
+}
+
+#[test]
+fn pinpoints_the_corrupt_block_once_the_piece_passes() {
+    let good = piece();
+    let mut ban = SmartBan::new(1);
+    assert!(ban
+        .piece_failed(0, &corrupt(&good, 1), &[A, B, A])
+        .is_empty());
+    assert_eq!((ban.failures(A), ban.failures(B)), (0, 0));
+    assert_eq!(ban.contributor_count(0), 2);
+    assert_eq!(ban.contributed_to(B), [0]);
+    assert_eq!(ban.piece_passed(0, &good), [B]);
+    assert!(!ban.is_banned(A));
+    assert_eq!(ban.contributor_count(0), 0);
+}
+
+#[test]
+fn blames_each_culprit_once_per_piece() {
+    let good = piece();
+    let mut ban = SmartBan::new(3);
+    ban.piece_failed(0, &corrupt(&good, 0), &[B, A, B]);
+    let mut twice = corrupt(&good, 0);
+    twice[BLOCK_SIZE as usize * 2] ^= 0xff;
+    ban.piece_failed(0, &twice, &[B, A, B]);
+    assert!(ban.piece_passed(0, &good).is_empty());
+    assert_eq!((ban.failures(A), ban.failures(B)), (0, 1));
+}
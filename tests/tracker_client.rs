@@ -0,0 +1,110 @@
+//! The async tracker client's HTTP handling against local stand-in servers.
+
+use bittorrent_starter_rust::bencode::BencodeValue;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{AnnounceEvent, AnnounceRequest, TrackerError};
+use bittorrent_starter_rust::tracker_client::{TrackerClient, TrackerClientConfig};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::thread;
+
+fn request() -> AnnounceRequest {
+    AnnounceRequest {
+        info_hash: [3; 20],
+        peer_id: PeerId::generate(),
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 10,
+        event: AnnounceEvent::Started,
+        compact: true,
+        numwant: None,
+        key: 0,
+        ip: None,
+        tracker_id: None,
+    }
+}
+
+fn client() -> TrackerClient {
+    TrackerClient::new(TrackerClientConfig::default()).unwrap()
+}
+
+/// Answers every request with `headers` and `body`.
+fn http_stand_in(headers: &'static str, body: Vec<u8>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        }
+    });
+    addr
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// A gzip member holding `data` in a single stored DEFLATE block.
+fn gzip_stored(data: &[u8]) -> Vec<u8> {
+    let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff, 1];
+    let len = data.len() as u16;
+    gzip.extend(len.to_le_bytes());
+    gzip.extend((!len).to_le_bytes());
+    gzip.extend(data);
+    gzip.extend(crc32(data).to_le_bytes());
+    gzip.extend((data.len() as u32).to_le_bytes());
+    gzip
+}
+
+#[tokio::test]
+async fn decodes_gzipped_responses() {
+    let body = BencodeValue::dictionary([
+        ("interval", BencodeValue::Integer(600)),
+        ("peers", BencodeValue::ByteString(vec![127, 0, 0, 1, 0, 80])),
+    ])
+    .encode();
+    let addr = http_stand_in("Content-Encoding: gzip\r\n", gzip_stored(&body));
+    let response = client()
+        .announce(&format!("http://{addr}/announce"), &request())
+        .await
+        .unwrap();
+    assert_eq!(response.interval, 600);
+    assert_eq!(response.peers[0].port, 80);
+}
+
+#[tokio::test]
+async fn reports_corrupt_gzip() {
+    let mut gzip = gzip_stored(b"d8:intervali600ee");
+    let crc = gzip.len() - 8;
+    gzip[crc] ^= 0xff;
+    let addr = http_stand_in("Content-Encoding: gzip\r\n", gzip);
+    let result = client()
+        .announce(&format!("http://{addr}/announce"), &request())
+        .await;
+    assert!(matches!(result, Err(TrackerError::Http(_))), "{result:?}");
+}
@@ -0,0 +1,132 @@
+//! Parsing of tracker announce responses in all the shapes trackers send them.
+
+use bittorrent_starter_rust::bencode::BencodeValue;
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{Peer, TrackerError, TrackerResponse};
+use std::net::SocketAddr;
+
+fn bytes(value: &[u8]) -> BencodeValue {
+    BencodeValue::ByteString(value.to_vec())
+}
+
+fn parse(entries: Vec<(&str, BencodeValue)>) -> Result<TrackerResponse, TrackerError> {
+    TrackerResponse::from_bytes(&BencodeValue::dictionary(entries).encode())
+}
+
+fn addrs(response: &TrackerResponse) -> Vec<SocketAddr> {
+    response.peers.iter().map(Peer::socket_addr).collect()
+}
+
+#[test]
+fn parses_compact_peers_and_optional_keys() {
+    let response = parse(vec![
+        ("interval", BencodeValue::Integer(1800)),
+        ("min interval", BencodeValue::Integer(60)),
+        ("complete", BencodeValue::Integer(4)),
+        ("incomplete", BencodeValue::Integer(9)),
+        ("tracker id", bytes(b"xyz")),
+        ("warning message", bytes(b"slow down")),
+        (
+            "peers",
+            bytes(&[10, 0, 0, 1, 0x1a, 0xe1, 192, 168, 1, 2, 0, 80, 1]),
+        ),
+    ])
+    .unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!(response.min_interval, Some(60));
+    assert_eq!((response.complete, response.incomplete), (Some(4), Some(9)));
+    assert_eq!(response.tracker_id.as_deref(), Some("xyz"));
+    assert_eq!(response.warning_message.as_deref(), Some("slow down"));
+    // The trailing partial entry is dropped.
+    assert_eq!(
+        addrs(&response),
+        [
+            "10.0.0.1:6881".parse().unwrap(),
+            "192.168.1.2:80".parse().unwrap()
+        ]
+    );
+}
+
+#[test]
+fn tolerates_missing_optional_keys() {
+    let response = parse(vec![("interval", BencodeValue::Integer(900))]).unwrap();
+    assert_eq!(response.interval, 900);
+    assert!(response.peers.is_empty());
+    assert_eq!(response.min_interval, None);
+    assert_eq!((response.complete, response.incomplete), (None, None));
+    assert_eq!(response.tracker_id, None);
+}
+
+#[test]
+fn parses_dictionary_peers() {
+    let peer_id = PeerId::generate();
+    let response = parse(vec![
+        ("interval", BencodeValue::Integer(1800)),
+        (
+            "peers",
+            BencodeValue::List(vec![
+                BencodeValue::dictionary([
+                    ("ip", bytes(b"10.1.1.1")),
+                    ("port", BencodeValue::Integer(6881)),
+                    ("peer id", bytes(peer_id.as_bytes())),
+                ]),
+                BencodeValue::dictionary([
+                    ("ip", bytes(b"::1")),
+                    ("port", BencodeValue::Integer(51413)),
+                ]),
+                // Hostnames are skipped.
+                BencodeValue::dictionary([
+                    ("ip", bytes(b"peer.example")),
+                    ("port", BencodeValue::Integer(1)),
+                ]),
+            ]),
+        ),
+    ])
+    .unwrap();
+    assert_eq!(
+        addrs(&response),
+        [
+            "10.1.1.1:6881".parse().unwrap(),
+            "[::1]:51413".parse().unwrap()
+        ]
+    );
+    assert_eq!(response.peers[0].peer_id, Some(peer_id));
+    assert_eq!(response.peers[1].peer_id, None);
+}
+
+#[test]
+fn appends_ipv6_peers6() {
+    let v6: SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+    let response = parse(vec![
+        ("interval", BencodeValue::Integer(1800)),
+        ("peers", bytes(&[127, 0, 0, 1, 0x1a, 0xe1])),
+        ("peers6", bytes(&Peer::new(v6).to_compact())),
+    ])
+    .unwrap();
+    assert_eq!(addrs(&response), ["127.0.0.1:6881".parse().unwrap(), v6]);
+}
+
+#[test]
+fn surfaces_failure_reasons() {
+    // A failure wins even when the rest of the response is incomplete.
+    let result = parse(vec![("failure reason", bytes(b"unregistered torrent"))]);
+    assert!(
+        matches!(result, Err(TrackerError::Failure(reason)) if reason == "unregistered torrent")
+    );
+}
+
+#[test]
+fn rejects_malformed_responses() {
+    assert!(matches!(
+        parse(vec![("peers", bytes(b""))]),
+        Err(TrackerError::Malformed(_))
+    ));
+    assert!(matches!(
+        parse(vec![("interval", bytes(b"soon"))]),
+        Err(TrackerError::Malformed(_))
+    ));
+    assert!(matches!(
+        TrackerResponse::from_bytes(b"d8:intervali1800e"),
+        Err(TrackerError::Malformed(_))
+    ));
+}
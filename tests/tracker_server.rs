@@ -0,0 +1,226 @@
+//! The HTTP tracker frontend: announces, scrapes and the `ip` override policy.
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{parse_scrape, Peer, TrackerError, TrackerResponse};
+use bittorrent_starter_rust::tracker_server::{is_local, HttpTrackerServer, PeerStore};
+use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+const INFO_HASH: [u8; 20] = [0x42; 20];
+
+fn serve(store: PeerStore) -> SocketAddr {
+    let server = HttpTrackerServer::bind("127.0.0.1:0", store).unwrap();
+    let addr = server.local_addr().unwrap();
+    server.spawn();
+    addr
+}
+
+fn store() -> PeerStore {
+    PeerStore::new(Duration::from_secs(60))
+}
+
+/// Sends a GET for `target` and returns the response body.
+fn get(addr: SocketAddr, target: &str) -> Vec<u8> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {target} HTTP/1.1\r\nHost: {addr}\r\n\r\n").unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    response.split_off(end + 4)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    percent_encode(bytes, NON_ALPHANUMERIC).to_string()
+}
+
+fn announce(
+    addr: SocketAddr,
+    peer_id: PeerId,
+    port: u16,
+    left: u64,
+    extra: &str,
+) -> Result<TrackerResponse, TrackerError> {
+    let target = format!(
+        "/announce?info_hash={}&peer_id={}&port={port}&left={left}{extra}",
+        encode(&INFO_HASH),
+        encode(peer_id.as_bytes()),
+    );
+    TrackerResponse::from_bytes(&get(addr, &target))
+}
+
+fn ports(response: &TrackerResponse) -> HashSet<u16> {
+    response.peers.iter().map(|peer| peer.port).collect()
+}
+
+#[test]
+fn announces_hand_out_the_rest_of_the_swarm() {
+    let addr = serve(store());
+    let first = announce(addr, PeerId::generate(), 7001, 100, "&event=started").unwrap();
+    assert!(first.peers.is_empty());
+    announce(addr, PeerId::generate(), 7002, 0, "").unwrap();
+    let third = announce(addr, PeerId::generate(), 7003, 100, "").unwrap();
+    assert_eq!(ports(&third), HashSet::from([7001, 7002]));
+    assert_eq!((third.complete, third.incomplete), (Some(1), Some(2)));
+    let limited = announce(addr, PeerId::generate(), 7004, 100, "&numwant=1").unwrap();
+    assert_eq!(limited.peers.len(), 1);
+}
+
+#[test]
+fn stopped_peers_leave_the_swarm() {
+    let addr = serve(store());
+    let leaving = PeerId::generate();
+    announce(addr, leaving, 7001, 100, "").unwrap();
+    announce(addr, leaving, 7001, 100, "&event=stopped").unwrap();
+    let response = announce(addr, PeerId::generate(), 7002, 100, "").unwrap();
+    assert!(response.peers.is_empty());
+    assert_eq!(response.incomplete, Some(1));
+}
+
+#[test]
+fn serves_dictionary_peers_when_not_compact() {
+    let addr = serve(store());
+    let peer_id = PeerId::generate();
+    announce(addr, peer_id, 7001, 100, "").unwrap();
+    let response = announce(addr, PeerId::generate(), 7002, 100, "&compact=0").unwrap();
+    assert_eq!(response.peers[0].peer_id, Some(peer_id));
+    let response = announce(
+        addr,
+        PeerId::generate(),
+        7003,
+        100,
+        "&compact=0&no_peer_id=1",
+    )
+    .unwrap();
+    assert!(response.peers.iter().all(|peer| peer.peer_id.is_none()));
+}
+
+#[test]
+fn refuses_bad_announces() {
+    let allowed = HashSet::from([[1; 20]]);
+    let addr = serve(PeerStore::with_allowlist(
+        Duration::from_secs(60),
+        Some(allowed),
+    ));
+    let failure = |target: &str| match TrackerResponse::from_bytes(&get(addr, target)) {
+        Err(TrackerError::Failure(reason)) => reason,
+        other => panic!("unexpected {other:?}"),
+    };
+    assert_eq!(
+        failure("/announce?peer_id=x&port=1"),
+        "missing parameter `info_hash`"
+    );
+    let peer_id = encode(PeerId::generate().as_bytes());
+    assert_eq!(
+        failure(&format!(
+            "/announce?info_hash=short&peer_id={peer_id}&port=1"
+        )),
+        "invalid parameter `info_hash`"
+    );
+    assert_eq!(
+        failure(&format!(
+            "/announce?info_hash={}&peer_id={peer_id}&port=http",
+            encode(&[1; 20])
+        )),
+        "invalid parameter `port`"
+    );
+    assert_eq!(
+        failure(&format!(
+            "/announce?info_hash={}&peer_id={peer_id}&port=1",
+            encode(&INFO_HASH)
+        )),
+        "torrent is not tracked here"
+    );
+}
+
+#[test]
+fn honors_the_ip_override_from_local_clients() {
+    let addr = serve(store());
+    announce(addr, PeerId::generate(), 7001, 100, "&ip=10.9.8.7").unwrap();
+    let response = announce(addr, PeerId::generate(), 7002, 100, "").unwrap();
+    assert_eq!(
+        response
+            .peers
+            .iter()
+            .map(Peer::socket_addr)
+            .collect::<Vec<_>>(),
+        ["10.9.8.7:7001".parse().unwrap()]
+    );
+}
+
+#[test]
+fn only_local_addresses_may_override_their_ip() {
+    for ip in [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.9",
+        "192.168.1.1",
+        "169.254.0.5",
+        "::1",
+        "fd12:3456::1",
+        "fe80::1",
+        "::ffff:192.168.0.2",
+    ] {
+        assert!(is_local(ip.parse::<IpAddr>().unwrap()), "{ip}");
+    }
+    for ip in ["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:1.2.3.4"] {
+        assert!(!is_local(ip.parse::<IpAddr>().unwrap()), "{ip}");
+    }
+}
+
+#[test]
+fn scrapes_known_torrents() {
+    let addr = serve(store());
+    announce(addr, PeerId::generate(), 7001, 0, "").unwrap();
+    let finishing = PeerId::generate();
+    announce(addr, finishing, 7002, 100, "").unwrap();
+    announce(addr, finishing, 7002, 0, "&event=completed").unwrap();
+    let stats = parse_scrape(&get(
+        addr,
+        &format!(
+            "/scrape?info_hash={}&info_hash={}",
+            encode(&INFO_HASH),
+            encode(&[9; 20])
+        ),
+    ))
+    .unwrap();
+    assert_eq!(stats.len(), 1);
+    let swarm = stats[&INFO_HASH];
+    assert_eq!(
+        (swarm.complete, swarm.incomplete, swarm.downloaded),
+        (2, 0, 1)
+    );
+    assert!(matches!(
+        parse_scrape(&get(addr, "/scrape?info_hash=short")),
+        Err(TrackerError::Failure(_))
+    ));
+}
+
+#[test]
+fn closes_connections_past_the_limit() {
+    let server = HttpTrackerServer::bind("127.0.0.1:0", store())
+        .unwrap()
+        .with_max_connections(1);
+    let addr = server.local_addr().unwrap();
+    server.spawn();
+    // Whether a scrape gets an answer; refused connections are closed or reset unanswered.
+    let answered = || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut response = Vec::new();
+        let _ = write!(stream, "GET /scrape HTTP/1.1\r\n\r\n")
+            .and_then(|_| stream.read_to_end(&mut response));
+        response.starts_with(b"HTTP/1.1 200")
+    };
+    // Holds the only slot until it is dropped.
+    let idle = TcpStream::connect(addr).unwrap();
+    assert!(!answered());
+
+    drop(idle);
+    let started = std::time::Instant::now();
+    while !answered() {
+        assert!(started.elapsed() < Duration::from_secs(5), "slot not freed");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
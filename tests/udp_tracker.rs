@@ -0,0 +1,222 @@
+//! The UDP tracker client against a scripted stand-in tracker on loopback.
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::Peer;
+use bittorrent_starter_rust::udp_tracker::{UdpAnnounce, UdpTracker, UdpTrackerError};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BASE_TIMEOUT: Duration = Duration::from_millis(50);
+const CONNECTION_ID: u64 = 0x1122_3344_5566_7788;
+
+/// A request as the stand-in saw it.
+struct Received {
+    at: Instant,
+    action: u32,
+    transaction_id: u32,
+    packet: Vec<u8>,
+}
+
+/// Answers every datagram with whatever `reply` returns for it, which may be nothing, and
+/// reports each one through the receiver.
+fn stand_in(
+    mut reply: impl FnMut(&Received) -> Vec<Vec<u8>> + Send + 'static,
+) -> (SocketAddr, Receiver<Received>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 2048];
+        while let Ok((n, from)) = socket.recv_from(&mut buf) {
+            let packet = buf[..n].to_vec();
+            let received = Received {
+                at: Instant::now(),
+                action: u32::from_be_bytes(packet[8..12].try_into().unwrap()),
+                transaction_id: u32::from_be_bytes(packet[12..16].try_into().unwrap()),
+                packet,
+            };
+            for datagram in reply(&received) {
+                socket.send_to(&datagram, from).unwrap();
+            }
+            // Tests that ignore the log drop the receiver; keep answering regardless.
+            let _ = tx.send(received);
+        }
+    });
+    (addr, rx)
+}
+
+fn header(action: u32, transaction_id: u32) -> Vec<u8> {
+    let mut datagram = action.to_be_bytes().to_vec();
+    datagram.extend(transaction_id.to_be_bytes());
+    datagram
+}
+
+fn connected(request: &Received) -> Vec<u8> {
+    let mut datagram = header(0, request.transaction_id);
+    datagram.extend(CONNECTION_ID.to_be_bytes());
+    datagram
+}
+
+fn announced(request: &Received, peers: &[SocketAddr]) -> Vec<u8> {
+    let mut datagram = header(1, request.transaction_id);
+    for value in [1800u32, 3, 7] {
+        datagram.extend(value.to_be_bytes());
+    }
+    for peer in peers {
+        datagram.extend(Peer::new(*peer).to_compact());
+    }
+    datagram
+}
+
+/// A tracker that answers connects and announces.
+fn answer(request: &Received, peers: &[SocketAddr]) -> Vec<Vec<u8>> {
+    match request.action {
+        0 => vec![connected(request)],
+        1 => vec![announced(request, peers)],
+        _ => Vec::new(),
+    }
+}
+
+fn announce_params() -> UdpAnnounce {
+    UdpAnnounce {
+        info_hash: [9; 20],
+        peer_id: PeerId::generate(),
+        downloaded: 100,
+        left: 200,
+        uploaded: 300,
+        event: 2,
+        ip: 0,
+        key: 0xdead_beef,
+        num_want: 50,
+        port: 6881,
+    }
+}
+
+fn client(addr: SocketAddr, max_retransmits: u32) -> UdpTracker {
+    UdpTracker::with_addr(addr)
+        .unwrap()
+        .with_timeouts(BASE_TIMEOUT, max_retransmits)
+}
+
+#[test]
+fn announces_and_reuses_the_connection_id() {
+    let peer: SocketAddr = "10.1.2.3:51413".parse().unwrap();
+    let (addr, received) = stand_in(move |request| answer(request, &[peer]));
+    let mut tracker = client(addr, 2);
+    let params = announce_params();
+    let response = tracker.announce(&params).unwrap();
+    assert_eq!(response.interval, 1800);
+    assert_eq!((response.incomplete, response.complete), (Some(3), Some(7)));
+    assert_eq!(
+        response
+            .peers
+            .iter()
+            .map(Peer::socket_addr)
+            .collect::<Vec<_>>(),
+        [peer]
+    );
+    tracker.announce(&params).unwrap();
+
+    let requests: Vec<Received> = received.try_iter().collect();
+    let actions: Vec<u32> = requests.iter().map(|request| request.action).collect();
+    assert_eq!(actions, [0, 1, 1], "connected once for both announces");
+    let announce = &requests[1].packet;
+    assert_eq!(
+        u64::from_be_bytes(announce[..8].try_into().unwrap()),
+        CONNECTION_ID
+    );
+    assert_eq!(&announce[16..36], &params.info_hash);
+    assert_eq!(&announce[36..56], params.peer_id.as_bytes());
+    assert_eq!(&announce[88..92], &0xdead_beefu32.to_be_bytes());
+    assert_eq!(&announce[92..96], &50i32.to_be_bytes());
+    assert_eq!(&announce[96..98], &6881u16.to_be_bytes());
+}
+
+#[test]
+fn retransmits_with_doubling_timeouts() {
+    let mut connects = 0;
+    let (addr, received) = stand_in(move |request| {
+        if request.action == 0 {
+            connects += 1;
+            if connects <= 2 {
+                return Vec::new();
+            }
+        }
+        answer(request, &[])
+    });
+    client(addr, 3).announce(&announce_params()).unwrap();
+    let times: Vec<Instant> = received
+        .try_iter()
+        .filter(|request| request.action == 0)
+        .map(|request| request.at)
+        .collect();
+    assert_eq!(times.len(), 3);
+    assert!(times[1] - times[0] >= BASE_TIMEOUT);
+    assert!(times[2] - times[1] >= BASE_TIMEOUT * 2);
+}
+
+#[test]
+fn gives_up_after_the_last_retransmit() {
+    let (addr, received) = stand_in(|_| Vec::new());
+    let result = client(addr, 2).announce(&announce_params());
+    assert!(matches!(result, Err(UdpTrackerError::Timeout(3))));
+    assert_eq!(received.try_iter().count(), 3);
+}
+
+#[test]
+fn reports_error_actions() {
+    let (addr, _) = stand_in(|request| match request.action {
+        0 => vec![connected(request)],
+        _ => {
+            let mut datagram = header(3, request.transaction_id);
+            datagram.extend(b"torrent not registered");
+            vec![datagram]
+        }
+    });
+    match client(addr, 1).announce(&announce_params()) {
+        Err(UdpTrackerError::Tracker(message)) => assert_eq!(message, "torrent not registered"),
+        other => panic!("unexpected {:?}", other.map(|response| response.interval)),
+    }
+}
+
+#[test]
+fn ignores_replies_to_other_transactions() {
+    let (addr, _) = stand_in(|request| {
+        let mut stale = answer(request, &[]);
+        stale[0][4..8].copy_from_slice(&request.transaction_id.wrapping_add(1).to_be_bytes());
+        // The stale reply comes first and must not be taken for ours.
+        let mut datagrams = stale;
+        datagrams.extend(answer(request, &[]));
+        datagrams
+    });
+    let response = client(addr, 0).announce(&announce_params()).unwrap();
+    assert_eq!(response.interval, 1800);
+}
+
+#[test]
+fn scrapes_several_hashes_at_once() {
+    let (addr, received) = stand_in(|request| match request.action {
+        0 => vec![connected(request)],
+        _ => {
+            let mut datagram = header(2, request.transaction_id);
+            for (seeders, completed, leechers) in [(5u32, 10u32, 2u32), (0, 1, 4)] {
+                datagram.extend(seeders.to_be_bytes());
+                datagram.extend(completed.to_be_bytes());
+                datagram.extend(leechers.to_be_bytes());
+            }
+            vec![datagram]
+        }
+    });
+    let stats = client(addr, 1).scrape(&[[1; 20], [2; 20]]).unwrap();
+    assert_eq!(
+        stats
+            .iter()
+            .map(|stats| (stats.complete, stats.downloaded, stats.incomplete))
+            .collect::<Vec<_>>(),
+        [(5, 10, 2), (0, 1, 4)]
+    );
+    let scrape = received.try_iter().last().unwrap().packet;
+    assert_eq!(&scrape[16..], [[1; 20], [2; 20]].concat());
+}
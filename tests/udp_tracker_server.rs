@@ -0,0 +1,113 @@
+//! The UDP tracker frontend against our own UDP client, and alongside the HTTP frontend.
+
+use bittorrent_starter_rust::peer_id::PeerId;
+use bittorrent_starter_rust::tracker::{scrape, Peer, Tracker, TransferStats};
+use bittorrent_starter_rust::tracker_server::{HttpTrackerServer, PeerStore};
+use bittorrent_starter_rust::udp_tracker::{UdpAnnounce, UdpTracker};
+use bittorrent_starter_rust::udp_tracker_server::UdpTrackerServer;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+const INFO_HASH: [u8; 20] = [0x77; 20];
+
+fn store() -> PeerStore {
+    PeerStore::new(Duration::from_secs(60))
+}
+
+fn serve_udp(store: PeerStore) -> (SocketAddr, String) {
+    let server = UdpTrackerServer::bind("127.0.0.1:0", store).unwrap();
+    let (addr, url) = (server.local_addr().unwrap(), server.announce_url().unwrap());
+    server.spawn();
+    (addr, url)
+}
+
+fn client(addr: SocketAddr) -> UdpTracker {
+    UdpTracker::with_addr(addr)
+        .unwrap()
+        .with_timeouts(Duration::from_millis(200), 2)
+}
+
+fn announce(port: u16, left: u64) -> UdpAnnounce {
+    UdpAnnounce {
+        info_hash: INFO_HASH,
+        peer_id: PeerId::generate(),
+        downloaded: 0,
+        left,
+        uploaded: 0,
+        event: 2,
+        ip: 0,
+        key: 1,
+        num_want: -1,
+        port,
+    }
+}
+
+fn addrs(peers: &[Peer]) -> Vec<SocketAddr> {
+    peers.iter().map(Peer::socket_addr).collect()
+}
+
+#[test]
+fn udp_client_announces_and_scrapes() {
+    let (addr, _) = serve_udp(store());
+    let mut seed = client(addr);
+    let first = seed.announce(&announce(7001, 0)).unwrap();
+    assert!(first.peers.is_empty());
+    assert_eq!((first.complete, first.incomplete), (Some(1), Some(0)));
+
+    let mut leecher = client(addr);
+    let second = leecher.announce(&announce(7002, 500)).unwrap();
+    assert_eq!(addrs(&second.peers), ["127.0.0.1:7001".parse().unwrap()]);
+    assert_eq!((second.complete, second.incomplete), (Some(1), Some(1)));
+
+    let stats = leecher.scrape(&[INFO_HASH, [0; 20]]).unwrap();
+    assert_eq!(
+        stats
+            .iter()
+            .map(|stats| (stats.complete, stats.incomplete))
+            .collect::<Vec<_>>(),
+        [(1, 1), (0, 0)],
+        "unknown torrents are reported as empty"
+    );
+}
+
+#[test]
+fn rejects_connection_ids_it_did_not_issue() {
+    let (addr, _) = serve_udp(store());
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut packet = 0xfeed_u64.to_be_bytes().to_vec();
+    packet.extend(1u32.to_be_bytes());
+    packet.extend(99u32.to_be_bytes());
+    packet.extend([0; 82]);
+    socket.send_to(&packet, addr).unwrap();
+    let mut buf = [0; 512];
+    let n = socket.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..8], [0, 0, 0, 3, 0, 0, 0, 99]);
+    assert_eq!(&buf[8..n], b"invalid connection id");
+}
+
+#[test]
+fn http_and_udp_frontends_share_one_store() {
+    let store = store();
+    let http = HttpTrackerServer::bind("127.0.0.1:0", store.clone()).unwrap();
+    let http_url = http.announce_url().unwrap();
+    http.spawn();
+    let (udp_addr, udp_url) = serve_udp(store);
+
+    let stats = Arc::new(TransferStats::new(0));
+    let mut over_http = Tracker::new(http_url.clone(), INFO_HASH, PeerId::generate(), 7001, stats);
+    assert!(over_http.announce().unwrap().peers.is_empty());
+
+    let over_udp = client(udp_addr).announce(&announce(7002, 500)).unwrap();
+    assert_eq!(addrs(&over_udp.peers), ["127.0.0.1:7001".parse().unwrap()]);
+    let again = over_http.announce().unwrap();
+    assert_eq!(addrs(&again.peers), ["127.0.0.1:7002".parse().unwrap()]);
+
+    for url in [&http_url, &udp_url] {
+        let swarm = scrape(url, &[INFO_HASH]).unwrap()[&INFO_HASH];
+        assert_eq!((swarm.complete, swarm.incomplete), (1, 1), "{url}");
+    }
+}